@@ -2,8 +2,8 @@ const { invokeBinary } = require('../utils/invoke');
 const { error } = require('../utils/logger');
 
 module.exports = function buildCommand(platform, file, optimize) {
-  const validPlatforms = ['linux', 'windows', 'macos'];
-  if (!validPlatforms.includes(platform)) {
+  const validPlatforms = ['linux', 'windows', 'windows-gnu', 'macos'];
+  if (platform && !validPlatforms.includes(platform)) {
     error(`Invalid platform. Supported: ${validPlatforms.join(', ')}`);
     throw new Error('Invalid platform');
   }
@@ -11,9 +11,9 @@ module.exports = function buildCommand(platform, file, optimize) {
     error('File must end with .nula');
     throw new Error('Invalid file');
   }
-  const args = ['--platform', platform, file];
+  const args = platform ? ['build', '--platform', platform, file] : ['build', file];
   if (optimize) {
-    args.push('--optimize');
+    args.push('-O2');
   }
   invokeBinary('nula-compiler', args);
 };
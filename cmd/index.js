@@ -83,11 +83,11 @@ program
 program
   .command('build')
   .description(chalk.yellow('Build production binary'))
-  .requiredOption('--platform <platform>', 'Target platform (linux, windows, macos)')
+  .option('--platform <platform>', 'Target platform (linux, windows, windows-gnu, macos), defaults to this machine')
   .argument('<file>', 'Path to .nula file')
   .option('--optimize', 'Enable optimizations')
   .action((file, options) => {
-    info(`Building for ${options.platform}...`);
+    info(`Building for ${options.platform || 'this machine'}...`);
     const spinner = ora('Building...').start();
     try {
       buildCommand(options.platform, file, options.optimize);
@@ -0,0 +1,21 @@
+// build.rs - Compiles the runtime into the compiler for `nula jit`
+//
+// Executables link an object compiled from the runtime's C source. Code
+// compiled in memory has no linker, so the runtime is linked into the compiler
+// itself with its symbols exported, where the JIT finds them like any other
+// library's.
+
+fn main() {
+    println!("cargo:rerun-if-changed=runtime/nula_rt.c");
+    cc::Build::new()
+        .file("runtime/nula_rt.c")
+        // Nothing in the compiler calls the runtime, so keep it from being dropped
+        .link_lib_modifier("+whole-archive")
+        .compile("nula_rt");
+    let target_os = std::env::var("CARGO_CFG_TARGET_OS").unwrap();
+    match target_os.as_str() {
+        "linux" => println!("cargo:rustc-link-arg-bins=-rdynamic"),
+        "macos" => println!("cargo:rustc-link-arg-bins=-Wl,-export_dynamic"),
+        _ => {}
+    }
+}
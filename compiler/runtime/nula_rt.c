@@ -0,0 +1,1494 @@
+// runtime/nula_rt.c - Runtime support linked into every Nula binary
+
+#if defined(__APPLE__)
+// ucontext is only exposed under XSI on macOS
+#define _XOPEN_SOURCE 700
+#define _DARWIN_C_SOURCE
+#elif !defined(_WIN32)
+// strptime and friends are XSI extensions
+#define _GNU_SOURCE
+#endif
+
+#include <math.h>
+#include <setjmp.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <time.h>
+
+#ifdef _WIN32
+#include <windows.h>
+#define NULA_THREAD_LOCAL __declspec(thread)
+#else
+#include <errno.h>
+#include <poll.h>
+#include <pthread.h>
+#include <sys/wait.h>
+#include <ucontext.h>
+#include <unistd.h>
+#define NULA_THREAD_LOCAL _Thread_local
+#endif
+
+// Every Nula function takes and returns f64 values
+typedef double (*nula_fn)(double);
+
+// Growable byte buffers for collecting output
+
+typedef struct {
+    char *data;
+    size_t len;
+    size_t cap;
+} nula_buf;
+
+static void nula_buf_append(nula_buf *b, const char *data, size_t n) {
+    if (b->len + n + 1 > b->cap) {
+        size_t cap = b->cap ? b->cap * 2 : 256;
+        while (cap < b->len + n + 1) cap *= 2;
+        char *grown = realloc(b->data, cap);
+        if (grown == NULL) return;
+        b->data = grown;
+        b->cap = cap;
+    }
+    memcpy(b->data + b->len, data, n);
+    b->len += n;
+    b->data[b->len] = '\0';
+}
+
+// Stack traces: every Nula function links a frame into nula_frames on entry
+// and unlinks it on return, storing the line it's at before each call, so an
+// abort can say how the program got there
+
+typedef struct nula_frame {
+    struct nula_frame *prev;
+    const char *function;
+    const char *file; // NULL until the function first calls something
+    int64_t line;
+} nula_frame;
+
+static NULA_THREAD_LOCAL nula_frame *nula_frames;
+
+// Deep recursion would print a frame per call; the innermost ones say enough
+#define NULA_TRACE_FRAMES 32
+
+static void nula_print_trace(void) {
+    if (nula_frames == NULL) return;
+    fprintf(stderr, "stack trace:\n");
+    int64_t shown = 0;
+    for (nula_frame *f = nula_frames; f != NULL; f = f->prev) {
+        if (shown++ == NULA_TRACE_FRAMES) {
+            int64_t more = 0;
+            for (; f != NULL; f = f->prev) more++;
+            fprintf(stderr, "  ... %lld more\n", (long long)more);
+            break;
+        }
+        if (f->file != NULL) {
+            fprintf(stderr, "  at %s (%s:%lld)\n", f->function, f->file, (long long)f->line);
+        } else {
+            fprintf(stderr, "  at %s\n", f->function);
+        }
+    }
+}
+
+// Panics: every runtime error ends up in nula_panic, which prints where it
+// happened and the stack trace, then exits with NULA_EXIT_PANIC. Under `nula
+// test` it jumps back to nula_run_test instead, to go on with the next test.
+
+#define NULA_EXIT_PANIC 70
+
+static NULA_THREAD_LOCAL jmp_buf *nula_test_jump; // set while a test runs
+
+// `file` is NULL for errors found inside the runtime, which happen where the
+// innermost frame last called it, or in code the compiler made up
+void nula_panic(const char *msg, const char *file, int64_t line) {
+    if (file == NULL && nula_frames != NULL) {
+        file = nula_frames->file;
+        line = nula_frames->line;
+    }
+    fflush(stdout);
+    if (file != NULL) {
+        fprintf(stderr, "panic at %s:%lld: %s\n", file, (long long)line, msg);
+    } else {
+        fprintf(stderr, "panic: %s\n", msg);
+    }
+    nula_print_trace();
+    if (nula_test_jump != NULL) longjmp(*nula_test_jump, 1);
+    exit(NULA_EXIT_PANIC);
+}
+
+// Stack overflow: frames are stack slots, so pushing one also checks how deep
+// the thread's stack is. Past the limit, a margin above the end of the stack,
+// the program panics while there's still room to print why.
+
+#define NULA_STACK_MARGIN (64 * 1024)
+
+static NULA_THREAD_LOCAL char *nula_stack_limit; // NULL until found
+
+static char *nula_find_stack_limit(void) {
+#if defined(_WIN32)
+    // A task's fiber has limits of its own, which this gives while it runs
+    ULONG_PTR low, high;
+    GetCurrentThreadStackLimits(&low, &high);
+    return (char *)low + NULA_STACK_MARGIN;
+#elif defined(__APPLE__)
+    char *top = pthread_get_stackaddr_np(pthread_self());
+    return top - pthread_get_stacksize_np(pthread_self()) + NULA_STACK_MARGIN;
+#else
+    pthread_attr_t attr;
+    void *low;
+    size_t size;
+    if (pthread_getattr_np(pthread_self(), &attr) != 0) return NULL;
+    pthread_attr_getstack(&attr, &low, &size);
+    pthread_attr_destroy(&attr);
+    return (char *)low + NULA_STACK_MARGIN;
+#endif
+}
+
+void nula_frame_push(nula_frame *frame) {
+    if (nula_stack_limit == NULL) nula_stack_limit = nula_find_stack_limit();
+    // Reported from the caller, at the call that went too deep
+    if ((char *)frame < nula_stack_limit) nula_panic("stack overflow", NULL, 0);
+    frame->prev = nula_frames;
+    nula_frames = frame;
+}
+
+void nula_frame_pop(void) {
+    nula_frames = nula_frames->prev;
+}
+
+// Runs the program's main on a thread with a `size`-byte stack, for --stack-size
+
+typedef struct {
+    int (*main)(void);
+    int code;
+} nula_main_thread;
+
+#ifdef _WIN32
+static DWORD WINAPI nula_main_start(LPVOID data) {
+#else
+static void *nula_main_start(void *data) {
+#endif
+    nula_main_thread *m = data;
+    m->code = m->main();
+    return 0;
+}
+
+int nula_run_main(int (*main)(void), int64_t size) {
+    nula_main_thread m = {main, 0};
+#ifdef _WIN32
+    HANDLE thread = CreateThread(NULL, (SIZE_T)size, nula_main_start, &m, STACK_SIZE_PARAM_IS_A_RESERVATION, NULL);
+    if (thread == NULL) nula_panic("couldn't start the main thread with the requested stack size", NULL, 0);
+    WaitForSingleObject(thread, INFINITE);
+    CloseHandle(thread);
+#else
+    pthread_attr_t attr;
+    pthread_t thread;
+    pthread_attr_init(&attr);
+    // Rounded up to whole pages
+    size_t page = (size_t)sysconf(_SC_PAGESIZE);
+    size_t stack_size = ((size_t)size + page - 1) / page * page;
+    if (pthread_attr_setstacksize(&attr, stack_size) != 0 || pthread_create(&thread, &attr, nula_main_start, &m) != 0) {
+        nula_panic("couldn't start the main thread with the requested stack size", NULL, 0);
+    }
+    pthread_attr_destroy(&attr);
+    pthread_join(thread, NULL);
+#endif
+    return m.code;
+}
+
+// Memory: strings and arrays are reference counted, with a header just
+// before the pointer Nula code gets. Codegen retains and releases them, and
+// writes the same header in front of string literals and stack arrays.
+
+typedef struct {
+    int64_t refs;     // negative for values that are never freed: literals and stack arrays
+    int64_t pointers; // leading 8-byte slots holding counted values, released along with it
+    int64_t len;      // bytes of a string, not counting the nul after them, or elements of an array
+    int64_t cap;      // bytes of storage after the header
+} nula_header;
+
+// Counts change atomically, since values sent over a channel or to a spawned
+// thread are retained and released by more than one thread
+#ifdef _WIN32
+#define NULA_REFS_LOAD(refs) InterlockedCompareExchange64(refs, 0, 0)
+#define NULA_REFS_INC(refs) InterlockedIncrement64(refs)
+#define NULA_REFS_DEC(refs) InterlockedDecrement64(refs)
+#else
+#define NULA_REFS_LOAD(refs) __atomic_load_n(refs, __ATOMIC_ACQUIRE)
+#define NULA_REFS_INC(refs) __atomic_add_fetch(refs, 1, __ATOMIC_RELAXED)
+#define NULA_REFS_DEC(refs) __atomic_sub_fetch(refs, 1, __ATOMIC_ACQ_REL)
+#endif
+
+// Cycle collection for --gc. Counting can't free values that point at each
+// other, so with --gc every heap value is also kept in a list, and once enough
+// have been allocated a mark-sweep pass frees the ones nothing reaches. Its
+// roots are the references codegen already counts for locals and temporaries:
+// a value with more references than other heap values hold is in use from a
+// stack (or was handed on as bits, and is kept).
+
+typedef struct nula_gc_link {
+    struct nula_gc_link *prev, *next;
+    int64_t gc_refs; // during a collection: references from outside the heap
+    int64_t state;   // NULA_GC_*
+} nula_gc_link;
+
+enum { NULA_GC_IDLE, NULA_GC_UNREACHED, NULA_GC_REACHED };
+
+static int nula_gc;
+static nula_gc_link *nula_gc_objects;
+static int64_t nula_gc_count, nula_gc_threshold = 10000;
+#ifdef _WIN32
+static SRWLOCK nula_gc_lock = SRWLOCK_INIT;
+#define NULA_GC_LOCK() AcquireSRWLockExclusive(&nula_gc_lock)
+#define NULA_GC_UNLOCK() ReleaseSRWLockExclusive(&nula_gc_lock)
+#else
+static pthread_mutex_t nula_gc_lock = PTHREAD_MUTEX_INITIALIZER;
+#define NULA_GC_LOCK() pthread_mutex_lock(&nula_gc_lock)
+#define NULA_GC_UNLOCK() pthread_mutex_unlock(&nula_gc_lock)
+#endif
+
+// Called first thing by programs built with --gc
+void nula_gc_enable(void) {
+    nula_gc = 1;
+}
+
+static nula_gc_link *nula_gc_link_of(nula_header *h) {
+    return (nula_gc_link *)h - 1;
+}
+
+// Link of a value held in a heap value's slot, or NULL for literals and stack arrays
+static nula_gc_link *nula_gc_child(nula_header *h, int64_t i) {
+    void *p = ((void **)(h + 1))[i];
+    if (p == NULL || ((nula_header *)p - 1)->refs < 0) return NULL;
+    return nula_gc_link_of((nula_header *)p - 1);
+}
+
+static void nula_gc_unlink(nula_gc_link *link) {
+    if (link->prev != NULL) link->prev->next = link->next;
+    else nula_gc_objects = link->next;
+    if (link->next != NULL) link->next->prev = link->prev;
+    nula_gc_count--;
+}
+
+// Called with the lock held
+static void nula_gc_collect(void) {
+    // References from outside the heap are the ones not held by heap values
+    for (nula_gc_link *o = nula_gc_objects; o != NULL; o = o->next) {
+        o->gc_refs = NULA_REFS_LOAD(&((nula_header *)(o + 1))->refs);
+        // Ones down to no references are being freed by another thread
+        o->state = o->gc_refs > 0 ? NULA_GC_UNREACHED : NULA_GC_IDLE;
+    }
+    for (nula_gc_link *o = nula_gc_objects; o != NULL; o = o->next) {
+        if (o->state == NULA_GC_IDLE) continue;
+        nula_header *h = (nula_header *)(o + 1);
+        for (int64_t i = 0; i < h->pointers; i++) {
+            nula_gc_link *child = nula_gc_child(h, i);
+            if (child != NULL) child->gc_refs--;
+        }
+    }
+
+    // Mark everything reachable from those
+    nula_gc_link **stack = malloc(((size_t)nula_gc_count + 1) * sizeof(nula_gc_link *));
+    if (stack == NULL) return;
+    int64_t top = 0;
+    for (nula_gc_link *o = nula_gc_objects; o != NULL; o = o->next) {
+        if (o->gc_refs <= 0 || o->state != NULA_GC_UNREACHED) continue;
+        o->state = NULA_GC_REACHED;
+        stack[top++] = o;
+        while (top > 0) {
+            nula_header *h = (nula_header *)(stack[--top] + 1);
+            for (int64_t i = 0; i < h->pointers; i++) {
+                nula_gc_link *child = nula_gc_child(h, i);
+                if (child != NULL && child->state == NULA_GC_UNREACHED) {
+                    child->state = NULA_GC_REACHED;
+                    stack[top++] = child;
+                }
+            }
+        }
+    }
+
+    // Sweep: take the garbage out of the list, let go of what it holds that
+    // survives, then free it
+    nula_gc_link *garbage = NULL;
+    for (nula_gc_link *o = nula_gc_objects, *next; o != NULL; o = next) {
+        next = o->next;
+        if (o->state != NULA_GC_UNREACHED) {
+            o->state = NULA_GC_IDLE;
+            continue;
+        }
+        nula_gc_unlink(o);
+        o->next = garbage;
+        garbage = o;
+    }
+    for (nula_gc_link *o = garbage; o != NULL; o = o->next) {
+        nula_header *h = (nula_header *)(o + 1);
+        for (int64_t i = 0; i < h->pointers; i++) {
+            nula_gc_link *child = nula_gc_child(h, i);
+            if (child != NULL && child->state == NULA_GC_IDLE) NULA_REFS_DEC(&((nula_header *)(child + 1))->refs);
+        }
+    }
+    for (nula_gc_link *o = garbage, *next; o != NULL; o = next) {
+        next = o->next;
+        free(o);
+    }
+    free(stack);
+    nula_gc_threshold = nula_gc_count * 2 > 10000 ? nula_gc_count * 2 : 10000;
+}
+
+// Heap storage for a value, starting out with one reference. Its pointer
+// slots start out NULL.
+static void *nula_alloc(int64_t size, int64_t pointers) {
+    size_t extra = nula_gc ? sizeof(nula_gc_link) : 0;
+    char *block = malloc(extra + sizeof(nula_header) + (size > 0 ? (size_t)size : 0));
+    if (block == NULL) nula_panic("out of memory", NULL, 0);
+    nula_header *h = (nula_header *)(block + extra);
+    h->refs = 1;
+    h->pointers = pointers;
+    h->len = 0;
+    h->cap = size;
+    memset(h + 1, 0, (size_t)pointers * sizeof(void *));
+    if (nula_gc) {
+        NULA_GC_LOCK();
+        if (nula_gc_count >= nula_gc_threshold) nula_gc_collect();
+        nula_gc_link *link = (nula_gc_link *)block;
+        link->prev = NULL;
+        link->next = nula_gc_objects;
+        link->gc_refs = 0;
+        link->state = NULA_GC_IDLE;
+        if (nula_gc_objects != NULL) nula_gc_objects->prev = link;
+        nula_gc_objects = link;
+        nula_gc_count++;
+        NULA_GC_UNLOCK();
+    }
+    return h + 1;
+}
+
+void nula_retain(void *p) {
+    if (p == NULL) return;
+    nula_header *h = (nula_header *)p - 1;
+    if (h->refs >= 0) NULA_REFS_INC(&h->refs);
+}
+
+void nula_release(void *p) {
+    if (p == NULL) return;
+    nula_header *h = (nula_header *)p - 1;
+    if (h->refs < 0 || NULA_REFS_DEC(&h->refs) > 0) return;
+    for (int64_t i = 0; i < h->pointers; i++) {
+        nula_release(((void **)p)[i]);
+    }
+    if (nula_gc) {
+        NULA_GC_LOCK();
+        nula_gc_unlink(nula_gc_link_of(h));
+        NULA_GC_UNLOCK();
+        free(nula_gc_link_of(h));
+    } else {
+        free(h);
+    }
+}
+
+// Array of `len` 8-byte elements, the first `pointers` of them counted values
+void *nula_array_new(int64_t len, int64_t pointers) {
+    void *arr = nula_alloc(len * 8, pointers);
+    ((nula_header *)arr - 1)->len = len;
+    return arr;
+}
+
+// Strings: `len` bytes, which may include nuls, followed by a nul for C
+
+static int64_t nula_len(const void *p) {
+    return ((const nula_header *)p - 1)->len;
+}
+
+static char *nula_string_new(const void *data, size_t len) {
+    char *out = nula_alloc((int64_t)len + 1, 0);
+    if (len > 0) memcpy(out, data, len);
+    out[len] = '\0';
+    ((nula_header *)out - 1)->len = (int64_t)len;
+    return out;
+}
+
+// Counted copy of a C string built with malloc, which is freed
+static char *nula_string(char *s) {
+    if (s == NULL) return NULL;
+    char *out = nula_string_new(s, strlen(s));
+    free(s);
+    return out;
+}
+
+// The text of a string as a C string, for C APIs
+static char *nula_cstr_arg(const char *s) {
+    size_t n = (size_t)nula_len(s);
+    char *out = malloc(n + 1);
+    if (out == NULL) return NULL;
+    memcpy(out, s, n);
+    out[n] = '\0';
+    return out;
+}
+
+// a + b
+char *nula_str_concat(const char *a, const char *b) {
+    size_t a_len = (size_t)nula_len(a), b_len = (size_t)nula_len(b);
+    char *out = nula_alloc((int64_t)(a_len + b_len + 1), 0);
+    memcpy(out, a, a_len);
+    memcpy(out + a_len, b, b_len + 1);
+    ((nula_header *)out - 1)->len = (int64_t)(a_len + b_len);
+    return out;
+}
+
+// a == b, by their bytes
+double nula_str_eq(const char *a, const char *b) {
+    int64_t len = nula_len(a);
+    return len == nula_len(b) && memcmp(a, b, (size_t)len) == 0;
+}
+
+// Output
+
+// The shortest text that reads back as x, so 1234567 and fnv's hashes come
+// out whole while 0.1 stays 0.1
+static void nula_format_number(char *buf, size_t size, double x) {
+    for (int precision = 15; precision < 17; precision++) {
+        snprintf(buf, size, "%.*g", precision, x);
+        if (strtod(buf, NULL) == x) return;
+    }
+    snprintf(buf, size, "%.17g", x);
+}
+
+// `write` of anything but a string
+void nula_write_number(double x) {
+    char buf[32];
+    nula_format_number(buf, sizeof buf, x);
+    puts(buf);
+}
+
+// `write` of a string, on a line of its own like a number
+void nula_write_string(const char *s) {
+    fwrite(s, 1, (size_t)nula_len(s), stdout);
+    putchar('\n');
+}
+
+// `exit(code)`, from anywhere in the program
+void nula_exit(double code) {
+    exit((int)code);
+}
+
+// Numbers
+
+double nula_pow(double base, double exponent) {
+    return pow(base, exponent);
+}
+
+// Arrays
+
+double nula_array_contains(const double *arr, int64_t len, double x) {
+    for (int64_t i = 0; i < len; i++) {
+        if (arr[i] == x) return 1.0;
+    }
+    return 0.0;
+}
+
+// Threads
+
+typedef struct {
+#ifdef _WIN32
+    HANDLE handle;
+#else
+    pthread_t handle;
+#endif
+    nula_fn fn;
+    double arg;
+    double result;
+} nula_thread;
+
+#ifdef _WIN32
+static DWORD WINAPI nula_thread_start(LPVOID data) {
+#else
+static void *nula_thread_start(void *data) {
+#endif
+    nula_thread *t = (nula_thread *)data;
+    t->result = t->fn(t->arg);
+    return 0;
+}
+
+int64_t nula_spawn(nula_fn fn, double arg) {
+    nula_thread *t = malloc(sizeof(nula_thread));
+    if (t == NULL) return 0;
+    t->fn = fn;
+    t->arg = arg;
+    t->result = 0.0;
+#ifdef _WIN32
+    t->handle = CreateThread(NULL, 0, nula_thread_start, t, 0, NULL);
+    if (t->handle == NULL) {
+        free(t);
+        return 0;
+    }
+#else
+    if (pthread_create(&t->handle, NULL, nula_thread_start, t) != 0) {
+        free(t);
+        return 0;
+    }
+#endif
+    return (int64_t)(intptr_t)t;
+}
+
+double nula_join(int64_t handle) {
+    nula_thread *t = (nula_thread *)(intptr_t)handle;
+    if (t == NULL) return 0.0;
+#ifdef _WIN32
+    WaitForSingleObject(t->handle, INFINITE);
+    CloseHandle(t->handle);
+#else
+    pthread_join(t->handle, NULL);
+#endif
+    double result = t->result;
+    free(t);
+    return result;
+}
+
+// Channels: unbounded FIFO queues guarded by a mutex and condition variable
+
+typedef struct nula_chan_node {
+    double value;
+    struct nula_chan_node *next;
+} nula_chan_node;
+
+typedef struct {
+#ifdef _WIN32
+    CRITICAL_SECTION lock;
+    CONDITION_VARIABLE ready;
+#else
+    pthread_mutex_t lock;
+    pthread_cond_t ready;
+#endif
+    nula_chan_node *head;
+    nula_chan_node *tail;
+} nula_channel;
+
+int64_t nula_channel_new(void) {
+    nula_channel *ch = malloc(sizeof(nula_channel));
+    if (ch == NULL) return 0;
+#ifdef _WIN32
+    InitializeCriticalSection(&ch->lock);
+    InitializeConditionVariable(&ch->ready);
+#else
+    pthread_mutex_init(&ch->lock, NULL);
+    pthread_cond_init(&ch->ready, NULL);
+#endif
+    ch->head = NULL;
+    ch->tail = NULL;
+    return (int64_t)(intptr_t)ch;
+}
+
+void nula_channel_send(int64_t handle, double value) {
+    nula_channel *ch = (nula_channel *)(intptr_t)handle;
+    if (ch == NULL) return;
+    nula_chan_node *node = malloc(sizeof(nula_chan_node));
+    if (node == NULL) return;
+    node->value = value;
+    node->next = NULL;
+#ifdef _WIN32
+    EnterCriticalSection(&ch->lock);
+#else
+    pthread_mutex_lock(&ch->lock);
+#endif
+    if (ch->tail != NULL) {
+        ch->tail->next = node;
+    } else {
+        ch->head = node;
+    }
+    ch->tail = node;
+#ifdef _WIN32
+    WakeConditionVariable(&ch->ready);
+    LeaveCriticalSection(&ch->lock);
+#else
+    pthread_cond_signal(&ch->ready);
+    pthread_mutex_unlock(&ch->lock);
+#endif
+}
+
+// Blocks until a value is available
+double nula_channel_recv(int64_t handle) {
+    nula_channel *ch = (nula_channel *)(intptr_t)handle;
+    if (ch == NULL) return 0.0;
+#ifdef _WIN32
+    EnterCriticalSection(&ch->lock);
+    while (ch->head == NULL) {
+        SleepConditionVariableCS(&ch->ready, &ch->lock, INFINITE);
+    }
+#else
+    pthread_mutex_lock(&ch->lock);
+    while (ch->head == NULL) {
+        pthread_cond_wait(&ch->ready, &ch->lock);
+    }
+#endif
+    nula_chan_node *node = ch->head;
+    ch->head = node->next;
+    if (ch->head == NULL) ch->tail = NULL;
+#ifdef _WIN32
+    LeaveCriticalSection(&ch->lock);
+#else
+    pthread_mutex_unlock(&ch->lock);
+#endif
+    double value = node->value;
+    free(node);
+    return value;
+}
+
+// Mutexes
+
+int64_t nula_mutex_new(void) {
+#ifdef _WIN32
+    CRITICAL_SECTION *m = malloc(sizeof(CRITICAL_SECTION));
+    if (m == NULL) return 0;
+    InitializeCriticalSection(m);
+#else
+    pthread_mutex_t *m = malloc(sizeof(pthread_mutex_t));
+    if (m == NULL) return 0;
+    pthread_mutex_init(m, NULL);
+#endif
+    return (int64_t)(intptr_t)m;
+}
+
+void nula_mutex_lock(int64_t handle) {
+    if (handle == 0) return;
+#ifdef _WIN32
+    EnterCriticalSection((CRITICAL_SECTION *)(intptr_t)handle);
+#else
+    pthread_mutex_lock((pthread_mutex_t *)(intptr_t)handle);
+#endif
+}
+
+void nula_mutex_unlock(int64_t handle) {
+    if (handle == 0) return;
+#ifdef _WIN32
+    LeaveCriticalSection((CRITICAL_SECTION *)(intptr_t)handle);
+#else
+    pthread_mutex_unlock((pthread_mutex_t *)(intptr_t)handle);
+#endif
+}
+
+// Atomic counters: a heap cell that codegen accesses with atomic instructions
+
+int64_t nula_atomic_new(double init) {
+    int64_t *cell = malloc(sizeof(int64_t));
+    if (cell == NULL) return 0;
+    *cell = (int64_t)init;
+    return (int64_t)(intptr_t)cell;
+}
+
+// Async tasks: cooperative green threads, one scheduler per OS thread.
+// A task runs on its own small stack and only gives up control in nula_await.
+
+#define NULA_TASK_STACK_SIZE (256 * 1024)
+
+// Compiler-generated trampoline unpacking the argument array for an async fn
+typedef double (*nula_task_fn)(double *args);
+
+// A finished task frees its stack and arguments but keeps its result, which
+// every await of the handle gives
+typedef struct nula_task {
+    nula_task_fn fn;
+    double *args;
+    double result;
+    int done;
+#ifdef _WIN32
+    LPVOID fiber;
+#else
+    ucontext_t *ctx;
+    char *stack;
+#endif
+    nula_frame *frames; // its stack trace chain while switched out
+    char *stack_limit;  // and its nula_stack_limit
+    struct nula_task *next;
+} nula_task;
+
+static NULA_THREAD_LOCAL nula_task *nula_run_head;
+static NULA_THREAD_LOCAL nula_task *nula_run_tail;
+// Task currently executing, NULL while on the thread's own stack
+static NULA_THREAD_LOCAL nula_task *nula_current;
+#ifdef _WIN32
+static NULA_THREAD_LOCAL LPVOID nula_sched_fiber;
+#else
+static NULA_THREAD_LOCAL ucontext_t nula_sched_ctx;
+#endif
+
+static void nula_task_enqueue(nula_task *t) {
+    t->next = NULL;
+    if (nula_run_tail != NULL) {
+        nula_run_tail->next = t;
+    } else {
+        nula_run_head = t;
+    }
+    nula_run_tail = t;
+}
+
+#ifdef _WIN32
+static void WINAPI nula_task_entry(LPVOID data) {
+    nula_task *t = (nula_task *)data;
+    t->result = t->fn(t->args);
+    t->done = 1;
+    SwitchToFiber(nula_sched_fiber);
+}
+#else
+static void nula_task_entry(void) {
+    nula_task *t = nula_current;
+    t->result = t->fn(t->args);
+    t->done = 1;
+    // Returning resumes nula_sched_ctx through uc_link
+}
+#endif
+
+int64_t nula_async(nula_task_fn fn, double *args, int64_t argc) {
+    nula_task *t = calloc(1, sizeof(nula_task));
+    if (t == NULL) return 0;
+    t->fn = fn;
+    t->args = malloc((argc > 0 ? argc : 1) * sizeof(double));
+    if (argc > 0) memcpy(t->args, args, argc * sizeof(double));
+#ifdef _WIN32
+    if (nula_sched_fiber == NULL) {
+        nula_sched_fiber = ConvertThreadToFiber(NULL);
+    }
+    t->fiber = CreateFiber(NULA_TASK_STACK_SIZE, nula_task_entry, t);
+#else
+    t->stack = malloc(NULA_TASK_STACK_SIZE);
+    t->ctx = malloc(sizeof(ucontext_t));
+    getcontext(t->ctx);
+    t->ctx->uc_stack.ss_sp = t->stack;
+    t->ctx->uc_stack.ss_size = NULA_TASK_STACK_SIZE;
+    t->stack_limit = t->stack + NULA_STACK_MARGIN;
+    t->ctx->uc_link = &nula_sched_ctx;
+    makecontext(t->ctx, nula_task_entry, 0);
+#endif
+    nula_task_enqueue(t);
+    return (int64_t)(intptr_t)t;
+}
+
+// Runs the next ready task until it finishes or awaits something unfinished
+static int nula_run_one(void) {
+    nula_task *t = nula_run_head;
+    if (t == NULL) return 0;
+    nula_run_head = t->next;
+    if (nula_run_head == NULL) nula_run_tail = NULL;
+    nula_current = t;
+    nula_frame *frames = nula_frames;
+    char *stack_limit = nula_stack_limit;
+    nula_frames = t->frames;
+    nula_stack_limit = t->stack_limit;
+#ifdef _WIN32
+    SwitchToFiber(t->fiber);
+#else
+    swapcontext(&nula_sched_ctx, t->ctx);
+#endif
+    t->frames = nula_frames;
+    t->stack_limit = nula_stack_limit;
+    nula_frames = frames;
+    nula_stack_limit = stack_limit;
+    nula_current = NULL;
+    if (t->done) {
+#ifdef _WIN32
+        DeleteFiber(t->fiber);
+        t->fiber = NULL;
+#else
+        free(t->stack);
+        free(t->ctx);
+        t->stack = NULL;
+        t->ctx = NULL;
+#endif
+        free(t->args);
+        t->args = NULL;
+    }
+    return 1;
+}
+
+// Suspends the current task, putting it back at the end of the run queue
+static void nula_yield(void) {
+    nula_task *t = nula_current;
+    nula_task_enqueue(t);
+#ifdef _WIN32
+    SwitchToFiber(nula_sched_fiber);
+#else
+    swapcontext(t->ctx, &nula_sched_ctx);
+#endif
+}
+
+double nula_await(int64_t handle) {
+    nula_task *t = (nula_task *)(intptr_t)handle;
+    if (t == NULL) return 0.0;
+    while (!t->done) {
+        if (nula_current != NULL) {
+            nula_yield();
+        } else if (!nula_run_one()) {
+            nula_panic("awaited task can never finish", NULL, 0);
+        }
+    }
+    return t->result;
+}
+
+// Processes
+
+#ifdef _WIN32
+typedef struct {
+    HANDLE pipe;
+    nula_buf *buf;
+} nula_pipe_reader;
+
+static DWORD WINAPI nula_read_pipe(LPVOID data) {
+    nula_pipe_reader *r = (nula_pipe_reader *)data;
+    char chunk[4096];
+    DWORD n;
+    while (ReadFile(r->pipe, chunk, sizeof(chunk), &n, NULL) && n > 0) {
+        nula_buf_append(r->buf, chunk, n);
+    }
+    return 0;
+}
+
+static int nula_run_process(char **argv, nula_buf *out, nula_buf *err) {
+    nula_buf cmdline = {0};
+    for (char **arg = argv; *arg != NULL; arg++) {
+        nula_buf_append(&cmdline, "\"", 1);
+        for (const char *c = *arg; *c != '\0'; c++) {
+            if (*c == '"') nula_buf_append(&cmdline, "\\", 1);
+            nula_buf_append(&cmdline, c, 1);
+        }
+        nula_buf_append(&cmdline, "\" ", 2);
+    }
+
+    SECURITY_ATTRIBUTES sa = {sizeof(sa), NULL, TRUE};
+    HANDLE out_r, out_w, err_r, err_w;
+    if (!CreatePipe(&out_r, &out_w, &sa, 0) || !CreatePipe(&err_r, &err_w, &sa, 0)) {
+        free(cmdline.data);
+        return -1;
+    }
+    SetHandleInformation(out_r, HANDLE_FLAG_INHERIT, 0);
+    SetHandleInformation(err_r, HANDLE_FLAG_INHERIT, 0);
+
+    STARTUPINFOA si = {0};
+    si.cb = sizeof(si);
+    si.dwFlags = STARTF_USESTDHANDLES;
+    si.hStdInput = GetStdHandle(STD_INPUT_HANDLE);
+    si.hStdOutput = out_w;
+    si.hStdError = err_w;
+    PROCESS_INFORMATION pi;
+    BOOL started = CreateProcessA(NULL, cmdline.data, NULL, NULL, TRUE, 0, NULL, NULL, &si, &pi);
+    free(cmdline.data);
+    CloseHandle(out_w);
+    CloseHandle(err_w);
+    if (!started) {
+        CloseHandle(out_r);
+        CloseHandle(err_r);
+        return -1;
+    }
+
+    // Drain both pipes concurrently so a full stderr can't block the child
+    nula_pipe_reader readers[2] = {{out_r, out}, {err_r, err}};
+    HANDLE err_thread = CreateThread(NULL, 0, nula_read_pipe, &readers[1], 0, NULL);
+    nula_read_pipe(&readers[0]);
+    WaitForSingleObject(err_thread, INFINITE);
+    CloseHandle(err_thread);
+
+    WaitForSingleObject(pi.hProcess, INFINITE);
+    DWORD code = 0;
+    GetExitCodeProcess(pi.hProcess, &code);
+    CloseHandle(pi.hProcess);
+    CloseHandle(pi.hThread);
+    CloseHandle(out_r);
+    CloseHandle(err_r);
+    return (int)code;
+}
+#else
+static int nula_run_process(char **argv, nula_buf *out, nula_buf *err) {
+    int out_pipe[2], err_pipe[2];
+    if (pipe(out_pipe) != 0) return -1;
+    if (pipe(err_pipe) != 0) {
+        close(out_pipe[0]);
+        close(out_pipe[1]);
+        return -1;
+    }
+    pid_t pid = fork();
+    if (pid < 0) {
+        close(out_pipe[0]);
+        close(out_pipe[1]);
+        close(err_pipe[0]);
+        close(err_pipe[1]);
+        return -1;
+    }
+    if (pid == 0) {
+        dup2(out_pipe[1], 1);
+        dup2(err_pipe[1], 2);
+        close(out_pipe[0]);
+        close(out_pipe[1]);
+        close(err_pipe[0]);
+        close(err_pipe[1]);
+        execvp(argv[0], argv);
+        _exit(127);
+    }
+    close(out_pipe[1]);
+    close(err_pipe[1]);
+
+    // Drain both pipes together so a full stderr can't block the child
+    struct pollfd fds[2] = {{out_pipe[0], POLLIN, 0}, {err_pipe[0], POLLIN, 0}};
+    nula_buf *bufs[2] = {out, err};
+    int open_fds = 2;
+    char chunk[4096];
+    while (open_fds > 0) {
+        if (poll(fds, 2, -1) < 0) {
+            if (errno == EINTR) continue;
+            break;
+        }
+        for (int i = 0; i < 2; i++) {
+            if (fds[i].fd < 0 || fds[i].revents == 0) continue;
+            ssize_t n = read(fds[i].fd, chunk, sizeof(chunk));
+            if (n > 0) {
+                nula_buf_append(bufs[i], chunk, (size_t)n);
+            } else {
+                close(fds[i].fd);
+                fds[i].fd = -1;
+                open_fds--;
+            }
+        }
+    }
+    for (int i = 0; i < 2; i++) {
+        if (fds[i].fd >= 0) close(fds[i].fd);
+    }
+
+    int status;
+    if (waitpid(pid, &status, 0) < 0) return -1;
+    return WIFEXITED(status) ? WEXITSTATUS(status) : 128 + WTERMSIG(status);
+}
+#endif
+
+static double nula_ptr_bits(const void *p) {
+    int64_t bits = (int64_t)(intptr_t)p;
+    double d;
+    memcpy(&d, &bits, sizeof(d));
+    return d;
+}
+
+// Returns a 3-element array: [stdout, stderr, exit status], with the strings
+// first so the array owns them
+int64_t nula_run(const char *cmd, const int64_t *args, int64_t argc) {
+    char **argv = calloc(argc + 2, sizeof(char *));
+    if (argv == NULL) return 0;
+    argv[0] = nula_cstr_arg(cmd);
+    for (int64_t i = 0; i < argc; i++) {
+        argv[i + 1] = nula_cstr_arg((const char *)(intptr_t)args[i]);
+    }
+
+    nula_buf out = {0}, err = {0};
+    int status = nula_run_process(argv, &out, &err);
+    nula_buf_append(&out, "", 0);
+    nula_buf_append(&err, "", 0);
+    for (int64_t i = 0; i <= argc; i++) {
+        free(argv[i]);
+    }
+    free(argv);
+
+    double *result = nula_array_new(3, 2);
+    result[0] = nula_ptr_bits(nula_string_new(out.data, out.len));
+    result[1] = nula_ptr_bits(nula_string_new(err.data, err.len));
+    result[2] = status;
+    free(out.data);
+    free(err.data);
+    return (int64_t)(intptr_t)result;
+}
+
+// Date and time: timestamps are seconds since the Unix epoch
+
+double nula_now(void) {
+#ifdef _WIN32
+    FILETIME ft;
+    GetSystemTimeAsFileTime(&ft);
+    uint64_t ticks = ((uint64_t)ft.dwHighDateTime << 32) | ft.dwLowDateTime;
+    // FILETIME counts 100ns intervals since 1601-01-01
+    return (double)(ticks - 116444736000000000ULL) / 1e7;
+#else
+    struct timespec ts;
+    clock_gettime(CLOCK_REALTIME, &ts);
+    return (double)ts.tv_sec + (double)ts.tv_nsec / 1e9;
+#endif
+}
+
+// Formats a timestamp in local time with strftime
+char *nula_format_time(double t, const char *fmt) {
+    time_t secs = (time_t)t;
+    struct tm tm;
+#ifdef _WIN32
+    localtime_s(&tm, &secs);
+#else
+    localtime_r(&secs, &tm);
+#endif
+    char *format = nula_cstr_arg(fmt);
+    size_t cap = 64;
+    char *out = NULL;
+    for (;;) {
+        char *grown = realloc(out, cap);
+        if (grown == NULL) break;
+        out = grown;
+        // strftime returns 0 when the buffer is too small
+        if (strftime(out, cap, format, &tm) > 0 || cap > 4096) break;
+        cap *= 2;
+    }
+    free(format);
+    return nula_string(out);
+}
+
+#ifdef _WIN32
+// Windows has no strptime; accept the numeric fields Nula scripts use
+static int nula_strptime(const char *s, const char *fmt, struct tm *tm) {
+    while (*fmt != '\0') {
+        if (*fmt != '%') {
+            if (*s++ != *fmt++) return 0;
+            continue;
+        }
+        fmt++;
+        int width, *field, offset = 0;
+        switch (*fmt++) {
+            case 'Y': width = 4; field = &tm->tm_year; offset = -1900; break;
+            case 'm': width = 2; field = &tm->tm_mon; offset = -1; break;
+            case 'd': width = 2; field = &tm->tm_mday; break;
+            case 'H': width = 2; field = &tm->tm_hour; break;
+            case 'M': width = 2; field = &tm->tm_min; break;
+            case 'S': width = 2; field = &tm->tm_sec; break;
+            case '%':
+                if (*s++ != '%') return 0;
+                continue;
+            default: return 0;
+        }
+        int value = 0, digits = 0;
+        while (digits < width && *s >= '0' && *s <= '9') {
+            value = value * 10 + (*s++ - '0');
+            digits++;
+        }
+        if (digits == 0) return 0;
+        *field = value + offset;
+    }
+    return 1;
+}
+#endif
+
+// Parses a local time with strptime, returning -1 when the text doesn't match
+double nula_parse_time(const char *s, const char *fmt) {
+    char *text = nula_cstr_arg(s);
+    char *format = nula_cstr_arg(fmt);
+    struct tm tm = {0};
+    tm.tm_mday = 1;
+    tm.tm_isdst = -1;
+#ifdef _WIN32
+    int ok = nula_strptime(text, format, &tm);
+#else
+    int ok = strptime(text, format, &tm) != NULL;
+#endif
+    free(text);
+    free(format);
+    if (!ok) return -1.0;
+    return (double)mktime(&tm);
+}
+
+// Hashing: digests are returned as lowercase hex strings
+
+static char *nula_hex(const unsigned char *digest, size_t n) {
+    static const char digits[] = "0123456789abcdef";
+    char *out = malloc(n * 2 + 1);
+    if (out == NULL) return NULL;
+    for (size_t i = 0; i < n; i++) {
+        out[i * 2] = digits[digest[i] >> 4];
+        out[i * 2 + 1] = digits[digest[i] & 0xf];
+    }
+    out[n * 2] = '\0';
+    return out;
+}
+
+#define NULA_ROTR32(x, n) (((x) >> (n)) | ((x) << (32 - (n))))
+#define NULA_ROTL32(x, n) (((x) << (n)) | ((x) >> (32 - (n))))
+
+static const uint32_t nula_sha256_k[64] = {
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+};
+
+static void nula_sha256_block(uint32_t h[8], const unsigned char *block) {
+    uint32_t w[64];
+    for (int i = 0; i < 16; i++) {
+        w[i] = (uint32_t)block[i * 4] << 24 | (uint32_t)block[i * 4 + 1] << 16 |
+               (uint32_t)block[i * 4 + 2] << 8 | (uint32_t)block[i * 4 + 3];
+    }
+    for (int i = 16; i < 64; i++) {
+        uint32_t s0 = NULA_ROTR32(w[i - 15], 7) ^ NULA_ROTR32(w[i - 15], 18) ^ (w[i - 15] >> 3);
+        uint32_t s1 = NULA_ROTR32(w[i - 2], 17) ^ NULA_ROTR32(w[i - 2], 19) ^ (w[i - 2] >> 10);
+        w[i] = w[i - 16] + s0 + w[i - 7] + s1;
+    }
+    uint32_t a = h[0], b = h[1], c = h[2], d = h[3], e = h[4], f = h[5], g = h[6], hh = h[7];
+    for (int i = 0; i < 64; i++) {
+        uint32_t s1 = NULA_ROTR32(e, 6) ^ NULA_ROTR32(e, 11) ^ NULA_ROTR32(e, 25);
+        uint32_t ch = (e & f) ^ (~e & g);
+        uint32_t t1 = hh + s1 + ch + nula_sha256_k[i] + w[i];
+        uint32_t s0 = NULA_ROTR32(a, 2) ^ NULA_ROTR32(a, 13) ^ NULA_ROTR32(a, 22);
+        uint32_t maj = (a & b) ^ (a & c) ^ (b & c);
+        uint32_t t2 = s0 + maj;
+        hh = g;
+        g = f;
+        f = e;
+        e = d + t1;
+        d = c;
+        c = b;
+        b = a;
+        a = t1 + t2;
+    }
+    h[0] += a; h[1] += b; h[2] += c; h[3] += d;
+    h[4] += e; h[5] += f; h[6] += g; h[7] += hh;
+}
+
+static const uint32_t nula_md5_k[64] = {
+    0xd76aa478, 0xe8c7b756, 0x242070db, 0xc1bdceee, 0xf57c0faf, 0x4787c62a, 0xa8304613, 0xfd469501,
+    0x698098d8, 0x8b44f7af, 0xffff5bb1, 0x895cd7be, 0x6b901122, 0xfd987193, 0xa679438e, 0x49b40821,
+    0xf61e2562, 0xc040b340, 0x265e5a51, 0xe9b6c7aa, 0xd62f105d, 0x02441453, 0xd8a1e681, 0xe7d3fbc8,
+    0x21e1cde6, 0xc33707d6, 0xf4d50d87, 0x455a14ed, 0xa9e3e905, 0xfcefa3f8, 0x676f02d9, 0x8d2a4c8a,
+    0xfffa3942, 0x8771f681, 0x6d9d6122, 0xfde5380c, 0xa4beea44, 0x4bdecfa9, 0xf6bb4b60, 0xbebfbc70,
+    0x289b7ec6, 0xeaa127fa, 0xd4ef3085, 0x04881d05, 0xd9d4d039, 0xe6db99e5, 0x1fa27cf8, 0xc4ac5665,
+    0xf4292244, 0x432aff97, 0xab9423a7, 0xfc93a039, 0x655b59c3, 0x8f0ccc92, 0xffeff47d, 0x85845dd1,
+    0x6fa87e4f, 0xfe2ce6e0, 0xa3014314, 0x4e0811a1, 0xf7537e82, 0xbd3af235, 0x2ad7d2bb, 0xeb86d391,
+};
+
+static const int nula_md5_r[64] = {
+    7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22,
+    5, 9, 14, 20, 5, 9, 14, 20, 5, 9, 14, 20, 5, 9, 14, 20,
+    4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23,
+    6, 10, 15, 21, 6, 10, 15, 21, 6, 10, 15, 21, 6, 10, 15, 21,
+};
+
+static void nula_md5_block(uint32_t h[4], const unsigned char *block) {
+    uint32_t w[16];
+    for (int i = 0; i < 16; i++) {
+        w[i] = (uint32_t)block[i * 4] | (uint32_t)block[i * 4 + 1] << 8 |
+               (uint32_t)block[i * 4 + 2] << 16 | (uint32_t)block[i * 4 + 3] << 24;
+    }
+    uint32_t a = h[0], b = h[1], c = h[2], d = h[3];
+    for (int i = 0; i < 64; i++) {
+        uint32_t f;
+        int g;
+        if (i < 16) {
+            f = (b & c) | (~b & d);
+            g = i;
+        } else if (i < 32) {
+            f = (d & b) | (~d & c);
+            g = (5 * i + 1) % 16;
+        } else if (i < 48) {
+            f = b ^ c ^ d;
+            g = (3 * i + 5) % 16;
+        } else {
+            f = c ^ (b | ~d);
+            g = (7 * i) % 16;
+        }
+        uint32_t tmp = d;
+        d = c;
+        c = b;
+        b = b + NULA_ROTL32(a + f + nula_md5_k[i] + w[g], nula_md5_r[i]);
+        a = tmp;
+    }
+    h[0] += a; h[1] += b; h[2] += c; h[3] += d;
+}
+
+// Merkle-Damgard padding shared by SHA-256 (big-endian length) and MD5 (little-endian)
+static void nula_md_hash(const unsigned char *data, size_t len, int big_endian,
+                         void (*block_fn)(uint32_t *, const unsigned char *), uint32_t *h) {
+    size_t i = 0;
+    for (; i + 64 <= len; i += 64) {
+        block_fn(h, data + i);
+    }
+    unsigned char tail[128] = {0};
+    size_t rest = len - i;
+    memcpy(tail, data + i, rest);
+    tail[rest] = 0x80;
+    size_t tail_len = rest + 9 <= 64 ? 64 : 128;
+    uint64_t bits = (uint64_t)len * 8;
+    for (int j = 0; j < 8; j++) {
+        size_t pos = big_endian ? tail_len - 1 - j : tail_len - 8 + j;
+        tail[pos] = (unsigned char)(bits >> (8 * j));
+    }
+    block_fn(h, tail);
+    if (tail_len == 128) block_fn(h, tail + 64);
+}
+
+static char *nula_sha256_bytes(const unsigned char *data, size_t len) {
+    uint32_t h[8] = {0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19};
+    nula_md_hash(data, len, 1, nula_sha256_block, h);
+    unsigned char digest[32];
+    for (int i = 0; i < 8; i++) {
+        digest[i * 4] = (unsigned char)(h[i] >> 24);
+        digest[i * 4 + 1] = (unsigned char)(h[i] >> 16);
+        digest[i * 4 + 2] = (unsigned char)(h[i] >> 8);
+        digest[i * 4 + 3] = (unsigned char)h[i];
+    }
+    return nula_hex(digest, sizeof(digest));
+}
+
+static char *nula_md5_bytes(const unsigned char *data, size_t len) {
+    uint32_t h[4] = {0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476};
+    nula_md_hash(data, len, 0, nula_md5_block, h);
+    unsigned char digest[16];
+    for (int i = 0; i < 4; i++) {
+        digest[i * 4] = (unsigned char)h[i];
+        digest[i * 4 + 1] = (unsigned char)(h[i] >> 8);
+        digest[i * 4 + 2] = (unsigned char)(h[i] >> 16);
+        digest[i * 4 + 3] = (unsigned char)(h[i] >> 24);
+    }
+    return nula_hex(digest, sizeof(digest));
+}
+
+// 32-bit FNV-1a, small enough to be exact as an f64
+static double nula_fnv_bytes(const unsigned char *data, size_t len) {
+    uint32_t h = 2166136261u;
+    for (size_t i = 0; i < len; i++) {
+        h ^= data[i];
+        h *= 16777619u;
+    }
+    return (double)h;
+}
+
+char *nula_sha256(const char *s) {
+    return nula_string(nula_sha256_bytes((const unsigned char *)s, (size_t)nula_len(s)));
+}
+
+char *nula_md5(const char *s) {
+    return nula_string(nula_md5_bytes((const unsigned char *)s, (size_t)nula_len(s)));
+}
+
+double nula_fnv(const char *s) {
+    return nula_fnv_bytes((const unsigned char *)s, (size_t)nula_len(s));
+}
+
+// Base64 and hex codecs. Decoders return an empty string on malformed input.
+
+static const char nula_base64_chars[] = "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+static char *nula_base64_encode_bytes(const unsigned char *data, size_t len) {
+    char *out = malloc((len + 2) / 3 * 4 + 1);
+    if (out == NULL) return NULL;
+    size_t o = 0;
+    for (size_t i = 0; i < len; i += 3) {
+        uint32_t chunk = (uint32_t)data[i] << 16;
+        if (i + 1 < len) chunk |= (uint32_t)data[i + 1] << 8;
+        if (i + 2 < len) chunk |= data[i + 2];
+        out[o++] = nula_base64_chars[(chunk >> 18) & 0x3f];
+        out[o++] = nula_base64_chars[(chunk >> 12) & 0x3f];
+        out[o++] = i + 1 < len ? nula_base64_chars[(chunk >> 6) & 0x3f] : '=';
+        out[o++] = i + 2 < len ? nula_base64_chars[chunk & 0x3f] : '=';
+    }
+    out[o] = '\0';
+    return out;
+}
+
+static int nula_base64_value(char c) {
+    const char *p = c != '\0' ? strchr(nula_base64_chars, c) : NULL;
+    return p != NULL ? (int)(p - nula_base64_chars) : -1;
+}
+
+// Decodes into a nul-terminated buffer, storing the byte count in out_len
+static unsigned char *nula_base64_decode_bytes(const char *s, size_t len, size_t *out_len) {
+    while (len > 0 && s[len - 1] == '=') len--;
+    unsigned char *out = malloc(len / 4 * 3 + 3);
+    if (out == NULL) return NULL;
+    size_t o = 0;
+    uint32_t chunk = 0;
+    int bits = 0;
+    for (size_t i = 0; i < len; i++) {
+        int v = nula_base64_value(s[i]);
+        if (v < 0) {
+            free(out);
+            return NULL;
+        }
+        chunk = (chunk << 6) | (uint32_t)v;
+        bits += 6;
+        if (bits >= 8) {
+            bits -= 8;
+            out[o++] = (unsigned char)(chunk >> bits);
+        }
+    }
+    out[o] = '\0';
+    *out_len = o;
+    return out;
+}
+
+static int nula_hex_value(char c) {
+    if (c >= '0' && c <= '9') return c - '0';
+    if (c >= 'a' && c <= 'f') return c - 'a' + 10;
+    if (c >= 'A' && c <= 'F') return c - 'A' + 10;
+    return -1;
+}
+
+static unsigned char *nula_hex_decode_bytes(const char *s, size_t len, size_t *out_len) {
+    if (len % 2 != 0) return NULL;
+    unsigned char *out = malloc(len / 2 + 1);
+    if (out == NULL) return NULL;
+    for (size_t i = 0; i < len; i += 2) {
+        int hi = nula_hex_value(s[i]), lo = nula_hex_value(s[i + 1]);
+        if (hi < 0 || lo < 0) {
+            free(out);
+            return NULL;
+        }
+        out[i / 2] = (unsigned char)(hi << 4 | lo);
+    }
+    out[len / 2] = '\0';
+    *out_len = len / 2;
+    return out;
+}
+
+char *nula_base64_encode(const char *s) {
+    return nula_string(nula_base64_encode_bytes((const unsigned char *)s, (size_t)nula_len(s)));
+}
+
+char *nula_base64_decode(const char *s) {
+    size_t len = 0;
+    unsigned char *out = nula_base64_decode_bytes(s, (size_t)nula_len(s), &len);
+    char *decoded = nula_string_new(out, len);
+    free(out);
+    return decoded;
+}
+
+char *nula_hex_encode(const char *s) {
+    return nula_string(nula_hex((const unsigned char *)s, (size_t)nula_len(s)));
+}
+
+char *nula_hex_decode(const char *s) {
+    size_t len = 0;
+    unsigned char *out = nula_hex_decode_bytes(s, (size_t)nula_len(s), &len);
+    char *decoded = nula_string_new(out, len);
+    free(out);
+    return decoded;
+}
+
+// Byte buffers: binary data that may contain nul bytes, counted and laid out
+// like a string so the hash and encoding functions take either
+
+char *nula_bytes_from_string(const char *s) {
+    return nula_string_new(s, (size_t)nula_len(s));
+}
+
+double nula_bytes_len(const char *b) {
+    return (double)nula_len(b);
+}
+
+double nula_bytes_at(const unsigned char *b, double index) {
+    int64_t i = (int64_t)index;
+    if (i < 0 || i >= nula_len(b)) {
+        char msg[96];
+        snprintf(msg, sizeof msg, "byte index %lld out of range for length %lld", (long long)i, (long long)nula_len(b));
+        nula_panic(msg, NULL, 0);
+    }
+    return (double)b[i];
+}
+
+// Copies bytes [start, end), clamping both ends to the buffer
+char *nula_bytes_slice(const char *b, double start, double end) {
+    int64_t len = nula_len(b);
+    int64_t from = (int64_t)start, to = (int64_t)end;
+    if (from < 0) from = 0;
+    if (to > len) to = len;
+    if (to < from) to = from;
+    return nula_string_new(b + from, (size_t)(to - from));
+}
+
+// The buffer's bytes as a string, nuls and all
+char *nula_bytes_to_string(const char *b) {
+    return nula_string_new(b, (size_t)nula_len(b));
+}
+
+// read_file(path): the file's contents as bytes
+char *nula_read_file(const char *path) {
+    char *name = nula_cstr_arg(path);
+    FILE *f = name != NULL ? fopen(name, "rb") : NULL;
+    if (f == NULL) {
+        char msg[256];
+        snprintf(msg, sizeof msg, "can't read %s", name != NULL ? name : "file");
+        free(name);
+        nula_panic(msg, NULL, 0);
+    }
+    nula_buf buf = {0};
+    char chunk[65536];
+    size_t n;
+    while ((n = fread(chunk, 1, sizeof chunk, f)) > 0) {
+        nula_buf_append(&buf, chunk, n);
+    }
+    fclose(f);
+    free(name);
+    char *out = nula_string_new(buf.data, buf.len);
+    free(buf.data);
+    return out;
+}
+
+// write_file(path, data): replaces the file's contents with a string or bytes
+void nula_write_file(const char *path, const char *data) {
+    char *name = nula_cstr_arg(path);
+    FILE *f = name != NULL ? fopen(name, "wb") : NULL;
+    size_t len = (size_t)nula_len(data);
+    int ok = f != NULL && fwrite(data, 1, len, f) == len;
+    if (f != NULL && fclose(f) != 0) ok = 0;
+    if (!ok) {
+        char msg[256];
+        snprintf(msg, sizeof msg, "can't write %s", name != NULL ? name : "file");
+        free(name);
+        nula_panic(msg, NULL, 0);
+    }
+    free(name);
+}
+
+// Checks in generated code that failed
+
+void nula_divide_by_zero(const char *file, int64_t line) {
+    nula_panic("division by zero", file, line);
+}
+
+// `array` is NULL or has `index` outside its elements
+void nula_index_error(const double *array, double index, const char *file, int64_t line) {
+    char msg[96];
+    if (array == NULL) {
+        snprintf(msg, sizeof msg, "index into a null array");
+    } else {
+        char number[32];
+        nula_format_number(number, sizeof number, index);
+        snprintf(msg, sizeof msg, "index %s out of range for length %lld", number, (long long)nula_len(array));
+    }
+    nula_panic(msg, file, line);
+}
+
+// Testing: `nula test` runs each test_ function through nula_run_test, and a
+// panic, like a failed assert, jumps back there to go on with the next test
+
+static int64_t nula_tests_passed, nula_tests_failed;
+
+void nula_assert(double cond, const char *file, int64_t line) {
+    if (cond == 0.0) nula_panic("assertion failed", file, line);
+}
+
+void nula_run_test(double (*test)(void), const char *name) {
+    jmp_buf jump;
+    char *test_name = nula_cstr_arg(name);
+    printf("test %s ... ", test_name);
+    free(test_name);
+    fflush(stdout);
+    nula_test_jump = &jump;
+    // A panic leaves the test's frames linked
+    nula_frame *frames = nula_frames;
+    if (setjmp(jump) == 0) {
+        test();
+        printf("ok\n");
+        nula_tests_passed++;
+    } else {
+        printf("FAILED\n");
+        nula_tests_failed++;
+        nula_frames = frames;
+    }
+    nula_test_jump = NULL;
+}
+
+// Exits with status 1 if any test failed
+void nula_test_summary(void) {
+    printf("\n%lld passed, %lld failed\n", (long long)nula_tests_passed, (long long)nula_tests_failed);
+    fflush(stdout);
+    if (nula_tests_failed > 0) exit(1);
+}
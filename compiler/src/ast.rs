@@ -1,18 +1,135 @@
 // src/ast.rs - AST definitions
 
+// Location of a token or node in the source: byte offsets [start, end) and
+// the 1-based line and column where it starts
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub col: usize,
+    pub file: usize, // index of the file among the ones compiled together
+}
+
+impl Span {
+    // Span covering both self and a later span
+    pub fn to(self, other: Span) -> Span {
+        Span { end: other.end, ..self }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Node {
+    pub ast: Ast,
+    pub span: Span,
+}
+
 #[derive(Debug, Clone)]
 pub enum Ast {
-    VarDecl(String, Box<Ast>), // name, value
-    Assign(String, Box<Ast>),
-    If(Box<Ast>, Vec<Ast>, Option<Vec<Ast>>),
-    While(Box<Ast>, Vec<Ast>),
-    For(String, Box<Ast>, Box<Ast>, Vec<Ast>), // var, from, to, body
-    FuncDef(String, Vec<String>, Vec<Ast>),
-    FuncCall(String, Vec<Ast>),
-    BinOp(String, Box<Ast>, Box<Ast>),
+    VarDecl(String, Box<Node>), // name, value
+    Assign(String, Box<Node>),
+    If(Box<Node>, Vec<Node>, Option<Vec<Node>>),
+    While(Box<Node>, Vec<Node>),
+    For(String, Box<Node>, Box<Node>, Vec<Node>), // var, from, to, body
+    ForEach(Option<String>, String, Box<Node>, Vec<Node>), // index var, element var, array, body
+    FuncDef(String, Vec<String>, Vec<Node>),
+    AsyncFuncDef(String, Vec<String>, Vec<Node>), // calls create tasks instead of running the body
+    Await(Box<Node>),
+    FuncCall(String, Vec<Node>),
+    Call(Box<Node>, Vec<Node>), // call through a function value: f()(x)
+    Return(Box<Node>),
+    BinOp(String, Box<Node>, Box<Node>),
     Literal(f64),
     StrLit(String),
     Var(String),
-    Array(Vec<Ast>),
-    Index(String, Box<Ast>), // array name, index
+    Array(Vec<Node>),
+    Index(Box<Node>, Box<Node>), // array, index
+    Link(String), // @link "lib": extra library for the linker
+    Export(String), // @export name: callable from C when built as a library
+}
+
+// Indented tree of the nodes for --emit ast, one node per line with where it starts
+pub fn dump(nodes: &[Node]) -> String {
+    let mut out = String::new();
+    dump_block(nodes, 0, &mut out);
+    out
+}
+
+fn dump_block(nodes: &[Node], depth: usize, out: &mut String) {
+    for node in nodes {
+        dump_node(node, depth, out);
+    }
+}
+
+fn dump_line(text: &str, depth: usize, out: &mut String) {
+    out.push_str(&"  ".repeat(depth));
+    out.push_str(text);
+    out.push('\n');
+}
+
+fn dump_node(node: &Node, depth: usize, out: &mut String) {
+    let label = match &node.ast {
+        Ast::VarDecl(name, _) => format!("VarDecl {}", name),
+        Ast::Assign(name, _) => format!("Assign {}", name),
+        Ast::If(..) => "If".to_string(),
+        Ast::While(..) => "While".to_string(),
+        Ast::For(var, ..) => format!("For {}", var),
+        Ast::ForEach(Some(index), elem, ..) => format!("ForEach {}, {}", index, elem),
+        Ast::ForEach(None, elem, ..) => format!("ForEach {}", elem),
+        Ast::FuncDef(name, params, _) => format!("FuncDef {}({})", name, params.join(", ")),
+        Ast::AsyncFuncDef(name, params, _) => format!("AsyncFuncDef {}({})", name, params.join(", ")),
+        Ast::Await(_) => "Await".to_string(),
+        Ast::FuncCall(name, _) => format!("FuncCall {}", name),
+        Ast::Call(..) => "Call".to_string(),
+        Ast::Return(_) => "Return".to_string(),
+        Ast::BinOp(op, ..) => format!("BinOp {}", op),
+        Ast::Literal(v) => format!("Literal {}", v),
+        Ast::StrLit(s) => format!("StrLit {:?}", s),
+        Ast::Var(name) => format!("Var {}", name),
+        Ast::Array(_) => "Array".to_string(),
+        Ast::Index(..) => "Index".to_string(),
+        Ast::Link(lib) => format!("Link {:?}", lib),
+        Ast::Export(name) => format!("Export {}", name),
+    };
+    dump_line(&format!("{}  {}:{}", label, node.span.line, node.span.col), depth, out);
+    let inner = depth + 1;
+    match &node.ast {
+        Ast::VarDecl(_, value) | Ast::Assign(_, value) | Ast::Await(value) | Ast::Return(value) => dump_node(value, inner, out),
+        Ast::If(cond, then_body, else_body) => {
+            dump_node(cond, inner, out);
+            dump_line("then", inner, out);
+            dump_block(then_body, inner + 1, out);
+            if let Some(else_body) = else_body {
+                dump_line("else", inner, out);
+                dump_block(else_body, inner + 1, out);
+            }
+        }
+        Ast::While(cond, body) => {
+            dump_node(cond, inner, out);
+            dump_line("do", inner, out);
+            dump_block(body, inner + 1, out);
+        }
+        Ast::For(_, from, to, body) => {
+            dump_node(from, inner, out);
+            dump_node(to, inner, out);
+            dump_line("do", inner, out);
+            dump_block(body, inner + 1, out);
+        }
+        Ast::ForEach(_, _, array, body) => {
+            dump_node(array, inner, out);
+            dump_line("do", inner, out);
+            dump_block(body, inner + 1, out);
+        }
+        Ast::FuncDef(_, _, body) | Ast::AsyncFuncDef(_, _, body) => dump_block(body, inner, out),
+        Ast::FuncCall(_, args) | Ast::Array(args) => dump_block(args, inner, out),
+        Ast::Call(callee, args) => {
+            dump_node(callee, inner, out);
+            dump_block(args, inner, out);
+        }
+        Ast::BinOp(_, left, right) | Ast::Index(left, right) => {
+            dump_node(left, inner, out);
+            dump_node(right, inner, out);
+        }
+        Ast::Literal(_) | Ast::StrLit(_) | Ast::Var(_) | Ast::Link(_) | Ast::Export(_) => {}
+    }
 }
@@ -0,0 +1,131 @@
+// src/backend.rs - Where generated code goes
+//
+// CodeGen turns HIR into Cranelift IR and hands every finished function and
+// piece of constant data to a Backend, which decides what becomes of them:
+// an object file for the linker (ObjectBackend) or machine code run straight
+// from memory (JitBackend).
+
+use cranelift_codegen::isa::OwnedTargetIsa;
+use cranelift_codegen::Context as CodegenContext;
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, DataDescription, DataId, FuncId, Linkage, Module};
+use cranelift_object::{ObjectBuilder, ObjectModule};
+
+use crate::debuginfo::DebugInfo;
+use crate::diagnostic::{Diagnostic, NulaResult};
+use crate::hir::Local;
+
+pub trait Backend {
+    // For declaring functions and data and making signatures
+    fn module(&mut self) -> &mut dyn Module;
+
+    // Compiles a function declared in module() into machine code. Its values
+    // are labeled with the ids of `locals` if debug_info() is set.
+    fn emit_function(&mut self, func_id: FuncId, ctx: &mut CodegenContext, locals: &[Local]) -> NulaResult<()>;
+
+    // Whether functions should carry what debug info needs: value labels
+    fn debug_info(&self) -> bool {
+        false
+    }
+
+    // Data private to the program, like string literals
+    fn emit_data(&mut self, name: &str, bytes: Vec<u8>) -> NulaResult<DataId> {
+        let module = self.module();
+        let data_id = module.declare_data(name, Linkage::Local, true, false)?;
+        let mut data = DataDescription::new();
+        data.define(bytes.into_boxed_slice());
+        module.define_data(data_id, &data)?;
+        Ok(data_id)
+    }
+
+    // Called once everything is emitted, before the output is used
+    fn finish(&mut self) -> NulaResult<()>;
+}
+
+pub struct ObjectBackend {
+    module: Option<ObjectModule>, // taken by finish
+    debug_info: Option<DebugInfo>,
+    bytes: Vec<u8>,
+}
+
+impl ObjectBackend {
+    // `function_sections` puts each function in its own section, so the linker
+    // can drop the ones nothing calls
+    pub fn new(isa: OwnedTargetIsa, name: &str, function_sections: bool) -> Self {
+        let mut builder = ObjectBuilder::new(isa, name, default_libcall_names()).unwrap();
+        builder.per_function_section(function_sections);
+        ObjectBackend { module: Some(ObjectModule::new(builder)), debug_info: None, bytes: Vec::new() }
+    }
+
+    // Writes DWARF for the functions into the object, with `files` as the
+    // names of the program's files relative to `comp_dir`
+    pub fn with_debug_info(&mut self, files: Vec<String>, comp_dir: String) {
+        let isa = self.module().isa();
+        self.debug_info = Some(DebugInfo::new(files, comp_dir, isa));
+    }
+
+    // Contents of the object file, once finished
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+impl Backend for ObjectBackend {
+    fn module(&mut self) -> &mut dyn Module {
+        self.module.as_mut().expect("object already finished")
+    }
+
+    fn emit_function(&mut self, func_id: FuncId, ctx: &mut CodegenContext, locals: &[Local]) -> NulaResult<()> {
+        let module = self.module.as_mut().expect("object already finished");
+        module.define_function(func_id, ctx)?;
+        if let Some(debug_info) = &mut self.debug_info {
+            let name = module.declarations().get_function_decl(func_id).linkage_name(func_id);
+            debug_info.add_function(func_id, &name, ctx, locals, module.isa());
+        }
+        Ok(())
+    }
+
+    fn debug_info(&self) -> bool {
+        self.debug_info.is_some()
+    }
+
+    fn finish(&mut self) -> NulaResult<()> {
+        let module = self.module.take().expect("object already finished");
+        let mut product = module.finish();
+        if let Some(debug_info) = self.debug_info.take() {
+            debug_info.write(&mut product)?;
+        }
+        self.bytes = product.emit().map_err(|err| Diagnostic::error(format!("Couldn't write the object file: {}", err)))?;
+        Ok(())
+    }
+}
+
+pub struct JitBackend {
+    module: JITModule,
+}
+
+impl JitBackend {
+    // Symbols the program imports are looked up in the running process
+    pub fn new(isa: OwnedTargetIsa) -> Self {
+        JitBackend { module: JITModule::new(JITBuilder::with_isa(isa, default_libcall_names())) }
+    }
+
+    // Address of a function's machine code, once finished
+    pub fn function(&self, func_id: FuncId) -> *const u8 {
+        self.module.get_finalized_function(func_id)
+    }
+}
+
+impl Backend for JitBackend {
+    fn module(&mut self) -> &mut dyn Module {
+        &mut self.module
+    }
+
+    fn emit_function(&mut self, func_id: FuncId, ctx: &mut CodegenContext, _locals: &[Local]) -> NulaResult<()> {
+        Ok(self.module.define_function(func_id, ctx)?)
+    }
+
+    fn finish(&mut self) -> NulaResult<()> {
+        Ok(self.module.finalize_definitions()?)
+    }
+}
@@ -1,88 +1,215 @@
 // src/codegen.rs - Code generation
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 use cranelift::prelude::*;
-use cranelift_codegen::ir::{self, AbiParam, InstBuilder, MemFlags};
-use cranelift_codegen::isa::CallConv;
+use cranelift_codegen::ir::condcodes::FloatCC;
+use cranelift_codegen::ir::{self, AbiParam, FuncRef, InstBuilder, MemFlags, StackSlot, ValueLabel};
 use cranelift_codegen::Context as CodegenContext;
 use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext};
-use cranelift_module::{DataContext, FuncId, Linkage, Module};
+use cranelift_module::{DataId, FuncId, Linkage, ModuleError};
 
-use crate::ast::Ast;
+use crate::ast::Span;
+use crate::backend::Backend;
+use crate::debuginfo;
+use crate::diagnostic::{Diagnostic, NulaResult};
+use crate::hir::{self, Alloc, BinOp, Expr, ExprKind, Stmt, VarId};
+use crate::profile::{Measure, Sample};
+
+// Bytes of the runtime's nula_header, in front of every string and array:
+// reference count, counted elements, length and capacity
+const HEADER_SIZE: usize = 32;
+const HEADER_LEN: i32 = 16; // offset of the length in it
 
 pub struct CodeGen<'a, 'b> {
-    pub module: &'a mut dyn Module,
+    pub backend: &'a mut dyn Backend,
     pub builder: &'b mut FunctionBuilder<'a>,
-    variables: HashMap<String, Variable>,
-    var_index: u32,
+    variables: Vec<Variable>, // locals of the function being generated, by VarId
+    counted: Vec<VarId>,      // its locals holding strings or arrays, which own a reference
+    temporaries: Vec<Value>,  // new references the statement being generated releases once done
+    function: String,         // function being generated, for naming its data
+    strings: u32,             // string literals emitted in it so far
+    string_data: HashMap<String, DataId>, // every string literal emitted, so each is only there once
+    frame: Option<StackSlot>, // its frame in the runtime's stack trace chain, once pushed
+    span: Span,               // innermost expression being generated
+    files: Vec<DataId>,       // names of the program's files, by Span::file
     functions: HashMap<String, FuncId>,
-    printf: FuncId,
-    // Add for arrays: array_vars: HashMap<String, (Value, u32)> // ptr, size
+    async_functions: HashMap<String, FuncId>, // name -> task trampoline
+    imports: HashMap<String, FuncId>,   // runtime functions declared in the module so far
+    func_refs: HashMap<FuncId, FuncRef>, // functions the one being generated refers to, each declared in it once
+    tail_call_target: Option<(String, Block)>, // function being generated and the block its body starts at
+    pub asm_blocks: Vec<String>, // bodies of asm("...") blocks, emitted as __nula_asm_<n>
+    pub print_clif: bool,        // --emit clif: print each function's IR as it is defined
+    pub print_asm: bool,         // --emit asm: print each function's machine code as it is defined
+    pub exports: HashSet<String>, // @export-ed functions, visible outside the object
+    pub file_names: Vec<String>,  // for stack traces
+    pub function_times: Vec<Sample>, // time spent generating and compiling each function, for --timings
 }
 
 impl<'a, 'b> CodeGen<'a, 'b> {
-    pub fn new(module: &'a mut dyn Module, builder: &'b mut FunctionBuilder<'a>, printf: FuncId) -> Self {
+    pub fn new(backend: &'a mut dyn Backend, builder: &'b mut FunctionBuilder<'a>) -> Self {
         CodeGen {
-            module,
+            backend,
             builder,
-            variables: HashMap::new(),
-            var_index: 0,
+            variables: Vec::new(),
+            counted: Vec::new(),
+            temporaries: Vec::new(),
+            function: "main".to_string(),
+            strings: 0,
+            string_data: HashMap::new(),
+            frame: None,
+            span: Span::default(),
+            files: Vec::new(),
             functions: HashMap::new(),
-            printf,
+            async_functions: HashMap::new(),
+            imports: HashMap::new(),
+            func_refs: HashMap::new(),
+            tail_call_target: None,
+            asm_blocks: Vec::new(),
+            print_clif: false,
+            print_asm: false,
+            exports: HashSet::new(),
+            file_names: Vec::new(),
+            function_times: Vec::new(),
         }
     }
 
-    pub fn gen_ast(&mut self, ast: &Ast) -> Value {
-        match ast {
-            Ast::Literal(val) => self.builder.ins().fconst(types::F64, *val),
-            Ast::StrLit(s) => {
-                let mut data_ctx = DataContext::new();
-                data_ctx.define(format!("{}\n\0", s).into_bytes().into_boxed_slice());
-                let data_id = self.module.declare_data(&format!("str_{}", self.var_index), Linkage::Local, true, false).unwrap();
-                self.var_index += 1;
-                self.module.define_data(data_id, &data_ctx).unwrap();
-                self.builder.ins().global_value(types::I64, data_id)
-            }
-            Ast::Var(name) => self.builder.use_var(*self.variables.get(name).expect("Undefined var")),
-            Ast::BinOp(op, left, right) => {
-                let l = self.gen_ast(left);
-                let r = self.gen_ast(right);
-                match op.as_str() {
-                    "+" => self.builder.ins().fadd(l, r),
-                    "-" => self.builder.ins().fsub(l, r),
-                    "*" => self.builder.ins().fmul(l, r),
-                    "/" => self.builder.ins().fdiv(l, r),
-                    "^" => {
-                        // For pow, declare powf
-                        let mut sig = self.module.make_signature();
-                        sig.params.push(AbiParam::new(types::F64));
-                        sig.params.push(AbiParam::new(types::F64));
-                        sig.returns.push(AbiParam::new(types::F64));
-                        sig.call_conv = CallConv::C;
-                        let powf = self.module.declare_function("powf", Linkage::Import, &sig).unwrap();
-                        self.builder.ins().call(powf, &[l, r])[0]
-                    }
-                    _ => panic!("Unknown op"),
-                }
+    // Defines every function, then generates the top-level statements into
+    // the current (main) function, short of its return
+    pub fn gen_program(&mut self, program: &hir::Program) -> NulaResult<()> {
+        for (i, name) in self.file_names.clone().into_iter().enumerate() {
+            let data_id = self.backend.emit_data(&format!("__nula_file_{}", i), format!("{}\0", name).into_bytes())?;
+            self.files.push(data_id);
+        }
+        // Declared up front so calls may come before a definition or from inside another function's body
+        for function in &program.functions {
+            self.declare_func(&function.name, function.params)?;
+            if function.is_async {
+                let task_id = self.declare_task(&function.name)?;
+                self.async_functions.insert(function.name.clone(), task_id);
             }
-            Ast::Assign(name, expr) | Ast::VarDecl(name, expr) => {
-                let val = self.gen_ast(expr);
-                let var = if let Some(&v) = self.variables.get(name) {
-                    v
+        }
+        for function in &program.functions {
+            let measure = Measure::start();
+            let func_id = self.gen_function(function)?;
+            if function.is_async {
+                self.gen_task_trampoline(&function.name, func_id, function.params)?;
+            }
+            self.function_times.push(measure.finish(function.name.as_str()));
+        }
+        self.declare_locals(&program.main.locals);
+        self.push_frame()?;
+        self.gen_block(&program.main.body)?;
+        self.pop_frame()
+    }
+
+    // Links the function's frame into the runtime's chain, which aborts print
+    // as a stack trace: { prev, function name, file, line }, with the file and
+    // line stored before each call
+    fn push_frame(&mut self) -> NulaResult<()> {
+        let name = self.backend.emit_data(&format!("__nula_fn_{}", self.function), format!("{}\0", self.function).into_bytes())?;
+        let global = self.backend.module().declare_data_in_func(name, self.builder.func);
+        let name = self.builder.ins().global_value(types::I64, global);
+        let zero = self.builder.ins().iconst(types::I64, 0);
+        let slot = self.builder.create_sized_stack_slot(StackSlotData::new(StackSlotKind::ExplicitSlot, 32, 3));
+        self.builder.ins().stack_store(name, slot, 8);
+        self.builder.ins().stack_store(zero, slot, 16);
+        self.builder.ins().stack_store(zero, slot, 24);
+        let frame = self.builder.ins().stack_addr(types::I64, slot, 0);
+        self.call_runtime("nula_frame_push", &[types::I64], None, &[frame])?;
+        self.frame = Some(slot);
+        Ok(())
+    }
+
+    // Unlinks the frame before the function returns
+    fn pop_frame(&mut self) -> NulaResult<()> {
+        if self.frame.is_some() {
+            self.call_runtime("nula_frame_pop", &[], None, &[])?;
+        }
+        Ok(())
+    }
+
+    // Stores where the function is in its frame, ahead of a call that may abort
+    // or lead to one
+    fn store_frame_line(&mut self) {
+        // Code the compiler made up, like the test harness, has no line to give
+        let Some(slot) = self.frame.filter(|_| self.span.line != 0) else { return };
+        let global = self.backend.module().declare_data_in_func(self.files[self.span.file], self.builder.func);
+        let file = self.builder.ins().global_value(types::I64, global);
+        let line = self.builder.ins().iconst(types::I64, self.span.line as i64);
+        self.builder.ins().stack_store(file, slot, 16);
+        self.builder.ins().stack_store(line, slot, 24);
+    }
+
+    // File name and line of the expression being generated, for runtime errors
+    // to point at; a null file for code the compiler made up
+    fn location(&mut self) -> (Value, Value) {
+        let file = if self.span.line == 0 {
+            self.builder.ins().iconst(types::I64, 0)
+        } else {
+            let global = self.backend.module().declare_data_in_func(self.files[self.span.file], self.builder.func);
+            self.builder.ins().global_value(types::I64, global)
+        };
+        (file, self.builder.ins().iconst(types::I64, self.span.line as i64))
+    }
+
+    // Fills in `block`, the cold path taken once a check fails: a call to the
+    // runtime function `name` that panics, with `args` and the location
+    fn gen_failed_check(&mut self, block: Block, name: &str, params: &[Type], args: &[Value]) -> NulaResult<()> {
+        self.builder.switch_to_block(block);
+        self.builder.seal_block(block);
+        self.builder.set_cold_block(block);
+        let (file, line) = self.location();
+        let params = [params, &[types::I64, types::I64]].concat();
+        let args = [args, &[file, line]].concat();
+        self.call_runtime(name, &params, None, &args)?;
+        self.builder.ins().trap(TrapCode::UnreachableCodeReached);
+        Ok(())
+    }
+
+    fn declare_locals(&mut self, locals: &[hir::Local]) {
+        for (id, local) in locals.iter().enumerate() {
+            let var = Variable::new(id);
+            self.builder.declare_var(var, repr(&local.ty));
+            self.variables.push(var);
+            if local.ty.is_pointer() {
+                self.counted.push(id);
+            }
+        }
+    }
+
+    fn gen_block(&mut self, stmts: &[Stmt]) -> NulaResult<()> {
+        for stmt in stmts {
+            self.gen_stmt(stmt)?;
+        }
+        Ok(())
+    }
+
+    fn gen_stmt(&mut self, stmt: &Stmt) -> NulaResult<()> {
+        match stmt {
+            Stmt::Assign(id, value) => {
+                let var = self.variables[*id];
+                let val = if value.ty.is_pointer() {
+                    // The new value is taken before the old one is let go, which it may be made from
+                    let val = self.gen_owned(value)?;
+                    let old = self.builder.use_var(var);
+                    self.call_runtime("nula_release", &[types::I64], None, &[old])?;
+                    val
                 } else {
-                    let v = Variable::new(self.var_index as usize);
-                    self.var_index += 1;
-                    self.builder.declare_var(v, types::F64);
-                    self.variables.insert(name.clone(), v);
-                    v
+                    self.gen_expr(value)?
                 };
                 self.builder.def_var(var, val);
-                val
+                self.builder.set_val_label(val, ValueLabel::new(*id));
+                self.release_temporaries(0)?;
+            }
+            Stmt::Expr(expr) => {
+                self.gen_expr(expr)?;
+                self.release_temporaries(0)?;
             }
-            Ast::If(cond, then_body, else_body) => {
-                let c = self.gen_ast(cond);
-                let cond_bool = self.builder.ins().fcmp(ir::condcodes::FloatCC::Ne, c, self.builder.ins().fconst(types::F64, 0.0));
+            Stmt::If(cond, then_body, else_body) => {
+                let c = self.gen_f64(cond)?;
+                self.release_temporaries(0)?;
+                let cond_bool = self.is_true(c);
                 let then_block = self.builder.create_block();
                 let else_block = self.builder.create_block();
                 let merge_block = self.builder.create_block();
@@ -91,168 +218,821 @@ impl<'a, 'b> CodeGen<'a, 'b> {
 
                 self.builder.switch_to_block(then_block);
                 self.builder.seal_block(then_block);
-                for stmt in then_body {
-                    self.gen_ast(stmt);
-                }
+                self.gen_block(then_body)?;
                 if !self.builder.is_unreachable() {
                     self.builder.ins().jump(merge_block, &[]);
                 }
 
                 self.builder.switch_to_block(else_block);
                 self.builder.seal_block(else_block);
-                if let Some(eb) = else_body {
-                    for stmt in eb {
-                        self.gen_ast(stmt);
-                    }
-                }
+                self.gen_block(else_body)?;
                 if !self.builder.is_unreachable() {
                     self.builder.ins().jump(merge_block, &[]);
                 }
 
                 self.builder.switch_to_block(merge_block);
                 self.builder.seal_block(merge_block);
-                self.builder.ins().fconst(types::F64, 0.0) // Dummy
             }
-            Ast::While(cond, body) => {
+            Stmt::While(cond, body) => {
                 let header_block = self.builder.create_block();
                 let body_block = self.builder.create_block();
                 let exit_block = self.builder.create_block();
 
                 self.builder.ins().jump(header_block, &[]);
                 self.builder.switch_to_block(header_block);
-                let c = self.gen_ast(cond);
-                let cond_bool = self.builder.ins().fcmp(ir::condcodes::FloatCC::Ne, c, self.builder.ins().fconst(types::F64, 0.0));
+                let c = self.gen_f64(cond)?;
+                self.release_temporaries(0)?;
+                let cond_bool = self.is_true(c);
                 self.builder.ins().brif(cond_bool, body_block, &[], exit_block, &[]);
 
                 self.builder.switch_to_block(body_block);
                 self.builder.seal_block(body_block);
-                for stmt in body {
-                    self.gen_ast(stmt);
-                }
+                self.gen_block(body)?;
                 self.builder.ins().jump(header_block, &[]);
 
                 self.builder.switch_to_block(exit_block);
                 self.builder.seal_block(header_block);
                 self.builder.seal_block(exit_block);
-                self.builder.ins().fconst(types::F64, 0.0)
             }
-            Ast::For(var_name, start, end, body) => {
-                let start_val = self.gen_ast(start);
-                let end_val = self.gen_ast(end);
-                let loop_var = Variable::new(self.var_index as usize);
-                self.var_index += 1;
-                self.builder.declare_var(loop_var, types::F64);
-                self.builder.def_var(loop_var, start_val);
-                self.variables.insert(var_name.clone(), loop_var);
+            Stmt::Return(Expr { kind: ExprKind::Call(name, args), .. })
+                if self.tail_call_target.as_ref().is_some_and(|(target, _)| target == name) =>
+            {
+                // return f(...) inside f: rebind the parameters and start over
+                // instead of growing the stack. The arguments have the
+                // parameters' types, and replace the references they own.
+                let mut values = Vec::new();
+                for arg in args {
+                    values.push(self.gen_owned(arg)?);
+                }
+                self.release_temporaries(0)?;
+                for (i, val) in values.into_iter().enumerate() {
+                    if args[i].ty.is_pointer() {
+                        let old = self.builder.use_var(self.variables[i]);
+                        self.call_runtime("nula_release", &[types::I64], None, &[old])?;
+                    }
+                    self.builder.def_var(self.variables[i], val);
+                    self.builder.set_val_label(val, ValueLabel::new(i));
+                }
+                let (_, body_block) = self.tail_call_target.clone().unwrap();
+                self.builder.ins().jump(body_block, &[]);
+                let after = self.builder.create_block();
+                self.builder.switch_to_block(after);
+                self.builder.seal_block(after);
+            }
+            Stmt::Return(value) => {
+                let val = self.gen_escaping(value)?;
+                self.release_temporaries(0)?;
+                self.release_locals()?;
+                self.pop_frame()?;
+                // main returns the program's exit code as an i32
+                let val = if self.builder.func.signature.returns[0].value_type == types::I32 {
+                    self.builder.ins().fcvt_to_sint_sat(types::I32, val)
+                } else {
+                    val
+                };
+                self.builder.ins().return_(&[val]);
+                // Statements after the return still need a block to be emitted into
+                let after = self.builder.create_block();
+                self.builder.switch_to_block(after);
+                self.builder.seal_block(after);
+            }
+        }
+        Ok(())
+    }
 
-                let header_block = self.builder.create_block();
-                let body_block = self.builder.create_block();
-                let exit_block = self.builder.create_block();
+    // Releases the references the function's locals own, before it returns
+    fn release_locals(&mut self) -> NulaResult<()> {
+        for id in self.counted.clone() {
+            // Zero if the local was never assigned, which the runtime ignores
+            let val = self.builder.use_var(self.variables[id]);
+            self.call_runtime("nula_release", &[types::I64], None, &[val])?;
+        }
+        Ok(())
+    }
 
-                self.builder.ins().jump(header_block, &[]);
-                self.builder.switch_to_block(header_block);
-                let current = self.builder.use_var(loop_var);
-                let cond = self.builder.ins().fcmp(ir::condcodes::FloatCC::Olt, current, end_val);
-                self.builder.ins().brif(cond, body_block, &[], exit_block, &[]);
+    // Releases the temporaries made since there were `from` of them
+    fn release_temporaries(&mut self, from: usize) -> NulaResult<()> {
+        for val in self.temporaries.split_off(from) {
+            self.call_runtime("nula_release", &[types::I64], None, &[val])?;
+        }
+        Ok(())
+    }
 
-                self.builder.switch_to_block(body_block);
-                self.builder.seal_block(body_block);
-                for stmt in body {
-                    self.gen_ast(stmt);
-                }
-                let next = self.builder.ins().fadd(self.builder.use_var(loop_var), self.builder.ins().fconst(types::F64, 1.0));
-                self.builder.def_var(loop_var, next);
-                self.builder.ins().jump(header_block, &[]);
+    // Value of `expr`, which the statement releases when done if it's a new
+    // reference
+    pub fn gen_expr(&mut self, expr: &Expr) -> NulaResult<Value> {
+        let val = self.gen_value(expr)?;
+        if is_new_reference(expr) {
+            self.temporaries.push(val);
+        }
+        Ok(val)
+    }
 
-                self.builder.switch_to_block(exit_block);
-                self.builder.seal_block(header_block);
-                self.builder.seal_block(exit_block);
-                self.builder.ins().fconst(types::F64, 0.0)
+    // Value of `expr` holding a reference of its own, for storing where it
+    // outlives the statement
+    fn gen_owned(&mut self, expr: &Expr) -> NulaResult<Value> {
+        let val = self.gen_value(expr)?;
+        if expr.ty.is_pointer() && matches!(expr.kind, ExprKind::Local(_) | ExprKind::Index(..)) {
+            self.call_runtime("nula_retain", &[types::I64], None, &[val])?;
+        }
+        Ok(val)
+    }
+
+    // gen_owned as an f64, for values that leave the function or go into an
+    // array. Once a pointer is just bits nothing releases it, so it's kept.
+    fn gen_escaping(&mut self, expr: &Expr) -> NulaResult<Value> {
+        let val = self.gen_owned(expr)?;
+        Ok(if expr.ty.is_pointer() { self.builder.ins().bitcast(types::F64, MemFlags::new(), val) } else { val })
+    }
+
+    fn gen_value(&mut self, expr: &Expr) -> NulaResult<Value> {
+        let outer = std::mem::replace(&mut self.span, expr.span);
+        self.builder.set_srcloc(debuginfo::source_loc(expr.span));
+        // Errors without a location of their own point at the innermost expression being generated
+        let val = self.gen_expr_kind(expr).map_err(|err| err.or_span(expr.span));
+        self.span = outer;
+        self.builder.set_srcloc(debuginfo::source_loc(outer));
+        val
+    }
+
+    // Value of `expr` as a pointer: strings and arrays already are, other
+    // values carry pointer bits (handles, functions, arrays passed as arguments)
+    fn gen_pointer(&mut self, expr: &Expr) -> NulaResult<Value> {
+        let val = self.gen_expr(expr)?;
+        Ok(if expr.ty.is_pointer() { val } else { self.builder.ins().bitcast(types::I64, MemFlags::new(), val) })
+    }
+
+    // Value of `expr` as an f64, with strings and arrays as their pointer bits
+    fn gen_f64(&mut self, expr: &Expr) -> NulaResult<Value> {
+        let val = self.gen_expr(expr)?;
+        Ok(if expr.ty.is_pointer() { self.builder.ins().bitcast(types::F64, MemFlags::new(), val) } else { val })
+    }
+
+    fn gen_expr_kind(&mut self, expr: &Expr) -> NulaResult<Value> {
+        let val = match &expr.kind {
+            ExprKind::Number(val) => self.builder.ins().f64const(*val),
+            ExprKind::Str(s) => {
+                let data_id = match self.string_data.get(s) {
+                    Some(&data_id) => data_id,
+                    None => {
+                        // Named after the first function using it rather than a program-wide
+                        // count, so a change to one function leaves most other symbols as they were
+                        let name = format!("__nula_str_{}_{}", self.function, self.strings);
+                        // Behind a header like the runtime's counted strings, that says it's never freed
+                        let (len, cap) = (s.len() as i64, s.len() as i64 + 1);
+                        let mut bytes: Vec<u8> = [-1, 0, len, cap].iter().flat_map(|field: &i64| field.to_le_bytes()).collect();
+                        bytes.extend(s.as_bytes());
+                        bytes.push(0);
+                        let data_id = self.backend.emit_data(&name, bytes)?;
+                        self.strings += 1;
+                        self.string_data.insert(s.clone(), data_id);
+                        data_id
+                    }
+                };
+                let global = self.backend.module().declare_data_in_func(data_id, self.builder.func);
+                let header = self.builder.ins().global_value(types::I64, global);
+                self.builder.ins().iadd_imm(header, HEADER_SIZE as i64)
+            }
+            ExprKind::Local(id) => self.builder.use_var(self.variables[*id]),
+            ExprKind::Function(name) => {
+                // Function used as a value: pass its address around as f64 bits
+                let func_ref = self.func_ref(self.functions[name]);
+                let addr = self.builder.ins().func_addr(types::I64, func_ref);
+                self.builder.ins().bitcast(types::F64, MemFlags::new(), addr)
             }
-            Ast::FuncDef(name, params, body) => {
-                let mut sig = self.module.make_signature();
-                for _ in params {
-                    sig.params.push(AbiParam::new(types::F64));
+            ExprKind::Array(elements, alloc) => {
+                // Fixed-size array; every element takes 8 bytes
+                let ptr = match alloc {
+                    Alloc::Stack => self.stack_array(elements.len(), 3).1,
+                    Alloc::Heap => {
+                        // Freeing the array releases its elements if they're all counted
+                        let pointers = if elements.iter().all(|elem| elem.ty.is_pointer()) { elements.len() } else { 0 };
+                        let len = self.builder.ins().iconst(types::I64, elements.len() as i64);
+                        let pointers = self.builder.ins().iconst(types::I64, pointers as i64);
+                        self.call_runtime("nula_array_new", &[types::I64, types::I64], Some(types::I64), &[len, pointers])?
+                    }
+                };
+                for (i, elem) in elements.iter().enumerate() {
+                    let val = self.gen_escaping(elem)?;
+                    self.builder.ins().store(MemFlags::trusted(), val, ptr, (i * 8) as i32);
                 }
-                sig.returns.push(AbiParam::new(types::F64));
-                let func_id = self.module.declare_function(name, Linkage::Local, &sig).unwrap();
-                self.functions.insert(name.clone(), func_id);
-
-                let mut local_ctx = CodegenContext::new();
-                local_ctx.func.signature = sig.clone();
-
-                let mut local_builder_ctx = FunctionBuilderContext::new();
-                let mut local_builder = FunctionBuilder::new(&mut local_ctx.func, &mut local_builder_ctx);
-
-                let entry = local_builder.create_block();
-                local_builder.append_block_params_for_function_params(entry);
-                local_builder.switch_to_block(entry);
-                local_builder.seal_block(entry);
-
-                let mut local_codegen = CodeGen::new(self.module, &mut local_builder, self.printf);
-
-                let block_params = local_builder.block_params(entry).to_vec();
-                for (i, param_name) in params.iter().enumerate() {
-                    let param_val = block_params[i];
-                    let param_var = Variable::new(local_codegen.var_index as usize);
-                    local_codegen.var_index += 1;
-                    local_codegen.builder.declare_var(param_var, types::F64);
-                    local_codegen.builder.def_var(param_var, param_val);
-                    local_codegen.variables.insert(param_name.clone(), param_var);
+                ptr
+            }
+            ExprKind::Index(array, index) => {
+                let ptr = self.gen_pointer(array)?;
+                let idx = self.gen_f64(index)?;
+                // A null array or an index outside its elements is a panic
+                let check_block = self.builder.create_block();
+                let fail_block = self.builder.create_block();
+                let ok_block = self.builder.create_block();
+                let is_null = self.builder.ins().icmp_imm(IntCC::Equal, ptr, 0);
+                self.builder.ins().brif(is_null, fail_block, &[], check_block, &[]);
+                self.builder.switch_to_block(check_block);
+                self.builder.seal_block(check_block);
+                let len = self.builder.ins().load(types::I64, MemFlags::trusted(), ptr, HEADER_LEN - HEADER_SIZE as i32);
+                let len = self.builder.ins().fcvt_from_sint(types::F64, len);
+                let zero = self.builder.ins().f64const(0.0);
+                // Both are false for NaN
+                let not_negative = self.builder.ins().fcmp(FloatCC::GreaterThanOrEqual, idx, zero);
+                let below_len = self.builder.ins().fcmp(FloatCC::LessThan, idx, len);
+                let in_range = self.builder.ins().band(not_negative, below_len);
+                self.builder.ins().brif(in_range, ok_block, &[], fail_block, &[]);
+                self.gen_failed_check(fail_block, "nula_index_error", &[types::I64, types::F64], &[ptr, idx])?;
+                self.builder.switch_to_block(ok_block);
+                self.builder.seal_block(ok_block);
+                let idx_i64 = self.builder.ins().fcvt_to_sint(types::I64, idx);
+                if matches!(array.ty, hir::Type::String | hir::Type::Bytes) {
+                    // Elements of a string or bytes are its bytes
+                    let addr = self.builder.ins().iadd(ptr, idx_i64);
+                    let byte = self.builder.ins().uload8(types::I32, MemFlags::new(), addr, 0);
+                    self.builder.ins().fcvt_from_uint(types::F64, byte)
+                } else {
+                    let offset = self.builder.ins().imul_imm(idx_i64, 8);
+                    let addr = self.builder.ins().iadd(ptr, offset);
+                    let elem = self.builder.ins().load(types::F64, MemFlags::new(), addr, 0);
+                    if expr.ty.is_pointer() {
+                        self.builder.ins().bitcast(types::I64, MemFlags::new(), elem)
+                    } else {
+                        elem
+                    }
                 }
-
-                for stmt in body {
-                    local_codegen.gen_ast(stmt);
+            }
+            ExprKind::Contains(x, len, array) => {
+                // x in arr: 1 if any element equals x, else 0
+                let x = self.gen_f64(x)?;
+                let ptr = self.gen_pointer(array)?;
+                let len = self.builder.ins().iconst(types::I64, *len as i64);
+                self.call_runtime("nula_array_contains", &[types::I64, types::I64, types::F64], Some(types::F64), &[ptr, len, x])?
+            }
+            ExprKind::Binary(op @ (BinOp::And | BinOp::Or), left, right) => {
+                // Short-circuits: the right operand only runs when it decides the result
+                let l = self.gen_f64(left)?;
+                let l_true = self.is_true(l);
+                let l_val = self.bool_to_f64(l_true);
+                let rhs_block = self.builder.create_block();
+                let merge_block = self.builder.create_block();
+                self.builder.append_block_param(merge_block, types::F64);
+                if *op == BinOp::And {
+                    self.builder.ins().brif(l_true, rhs_block, &[], merge_block, &[l_val]);
+                } else {
+                    self.builder.ins().brif(l_true, merge_block, &[l_val], rhs_block, &[]);
                 }
 
-                let ret_val = local_codegen.builder.ins().fconst(types::F64, 0.0);
-                local_codegen.builder.ins().return_(&[ret_val]);
+                self.builder.switch_to_block(rhs_block);
+                self.builder.seal_block(rhs_block);
+                // What the right operand makes is released here, where it's certain to exist
+                let temporaries = self.temporaries.len();
+                let r = self.gen_f64(right)?;
+                self.release_temporaries(temporaries)?;
+                let r_true = self.is_true(r);
+                let r_val = self.bool_to_f64(r_true);
+                self.builder.ins().jump(merge_block, &[r_val]);
 
-                self.module.define_function(func_id, &mut local_ctx).unwrap();
-
-                self.builder.ins().fconst(types::F64, 0.0)
+                self.builder.switch_to_block(merge_block);
+                self.builder.seal_block(merge_block);
+                self.builder.block_params(merge_block)[0]
             }
-            Ast::FuncCall(name, args) => {
-                if name == "write" {
-                    let arg = self.gen_ast(&args[0]);
-                    self.builder.ins().call(self.printf, &[arg]);
-                    self.builder.ins().fconst(types::F64, 0.0)
+            ExprKind::Binary(BinOp::Add, left, right) if expr.ty == hir::Type::String => {
+                let l = self.gen_pointer(left)?;
+                let r = self.gen_pointer(right)?;
+                self.call_runtime("nula_str_concat", &[types::I64, types::I64], Some(types::I64), &[l, r])?
+            }
+            ExprKind::Binary(op @ (BinOp::Eq | BinOp::Ne), left, right) if matches!(left.ty, hir::Type::String | hir::Type::Bytes) => {
+                let l = self.gen_pointer(left)?;
+                let r = self.gen_pointer(right)?;
+                let eq = self.call_runtime("nula_str_eq", &[types::I64, types::I64], Some(types::F64), &[l, r])?;
+                if *op == BinOp::Eq {
+                    eq
                 } else {
-                    let func_id = *self.functions.get(name).expect("Undefined function");
-                    let mut call_args = Vec::new();
-                    for arg in args {
-                        call_args.push(self.gen_ast(arg));
+                    let one = self.builder.ins().f64const(1.0);
+                    self.builder.ins().fsub(one, eq)
+                }
+            }
+            ExprKind::Binary(op, left, right) => {
+                let l = self.gen_f64(left)?;
+                let r = self.gen_f64(right)?;
+                match op {
+                    BinOp::Add => self.builder.ins().fadd(l, r),
+                    BinOp::Sub => self.builder.ins().fsub(l, r),
+                    BinOp::Mul => self.builder.ins().fmul(l, r),
+                    BinOp::Div => {
+                        // Dividing by zero stops the program rather than giving inf or NaN
+                        let zero = self.builder.ins().f64const(0.0);
+                        let is_zero = self.builder.ins().fcmp(FloatCC::Equal, r, zero);
+                        let fail_block = self.builder.create_block();
+                        let ok_block = self.builder.create_block();
+                        self.builder.ins().brif(is_zero, fail_block, &[], ok_block, &[]);
+                        self.gen_failed_check(fail_block, "nula_divide_by_zero", &[], &[])?;
+                        self.builder.switch_to_block(ok_block);
+                        self.builder.seal_block(ok_block);
+                        self.builder.ins().fdiv(l, r)
                     }
-                    let inst = self.builder.ins().call(func_id, &call_args);
-                    self.builder.inst_results(inst)[0]
+                    BinOp::Pow => self.call_runtime("nula_pow", &[types::F64, types::F64], Some(types::F64), &[l, r])?,
+                    BinOp::Shl | BinOp::Shr => {
+                        // Shifts work on the integer part of both operands, with NaN
+                        // as 0 and numbers past an i64 as its nearest end
+                        let li = self.builder.ins().fcvt_to_sint_sat(types::I64, l);
+                        let ri = self.builder.ins().fcvt_to_sint_sat(types::I64, r);
+                        let shifted = if *op == BinOp::Shl { self.builder.ins().ishl(li, ri) } else { self.builder.ins().sshr(li, ri) };
+                        self.builder.ins().fcvt_from_sint(types::F64, shifted)
+                    }
+                    BinOp::Eq | BinOp::Ne | BinOp::Lt | BinOp::Gt | BinOp::Le | BinOp::Ge => {
+                        // Comparisons give 1 or 0
+                        let cc = match op {
+                            BinOp::Eq => FloatCC::Equal,
+                            BinOp::Ne => FloatCC::NotEqual,
+                            BinOp::Lt => FloatCC::LessThan,
+                            BinOp::Gt => FloatCC::GreaterThan,
+                            BinOp::Le => FloatCC::LessThanOrEqual,
+                            _ => FloatCC::GreaterThanOrEqual,
+                        };
+                        let cmp = self.builder.ins().fcmp(cc, l, r);
+                        self.bool_to_f64(cmp)
+                    }
+                    BinOp::And | BinOp::Or => unreachable!("handled above"),
                 }
             }
-            Ast::Array(elements) => {
-                // Allocate array on stack (simple, fixed size)
-                let size = elements.len() as i64;
-                let ptr = self.builder.ins().stack_alloc(types::F64, size as u32, MemFlags::new());
-                for (i, elem) in elements.iter().enumerate() {
-                    let val = self.gen_ast(elem);
-                    let offset = self.builder.ins().iconst(types::I64, i as i64 * 8); // F64 = 8 bytes
-                    let addr = self.builder.ins().iadd(ptr, offset);
-                    self.builder.ins().store(MemFlags::new(), val, addr, 0);
+            ExprKind::Call(name, args) => {
+                let mut call_args = Vec::new();
+                for arg in args {
+                    call_args.push(self.gen_escaping(arg)?);
                 }
-                // Return ptr (but for simplicity, we might store in var)
-                // For now, assume assigned to var
-                ptr
+                let func_ref = self.func_ref(self.functions[name]);
+                self.store_frame_line();
+                let inst = self.builder.ins().call(func_ref, &call_args);
+                let val = self.builder.inst_results(inst)[0];
+                // Strings and arrays come back as bits, owned by the caller
+                if expr.ty.is_pointer() { self.builder.ins().bitcast(types::I64, MemFlags::new(), val) } else { val }
+            }
+            ExprKind::StartTask(name, args) => {
+                // Calling an async fn only creates a task; its body runs once awaited
+                let slot = self.builder.create_sized_stack_slot(StackSlotData::new(StackSlotKind::ExplicitSlot, (args.len() * 8) as u32, 3));
+                for (i, arg) in args.iter().enumerate() {
+                    let val = self.gen_escaping(arg)?;
+                    self.builder.ins().stack_store(val, slot, (i * 8) as i32);
+                }
+                let args_ptr = self.builder.ins().stack_addr(types::I64, slot, 0);
+                let task_ref = self.func_ref(self.async_functions[name]);
+                let task_fn = self.builder.ins().func_addr(types::I64, task_ref);
+                let argc = self.builder.ins().iconst(types::I64, args.len() as i64);
+                let handle = self.call_runtime("nula_async", &[types::I64, types::I64, types::I64], Some(types::I64), &[task_fn, args_ptr, argc])?;
+                self.builder.ins().bitcast(types::F64, MemFlags::new(), handle)
+            }
+            ExprKind::CallIndirect(callee, args) => {
+                // Function values are addresses carried as f64 bits
+                let addr = self.gen_pointer(callee)?;
+                let mut call_args = Vec::new();
+                for arg in args {
+                    call_args.push(self.gen_escaping(arg)?);
+                }
+                let sig = self.func_signature(args.len());
+                let sig_ref = self.builder.import_signature(sig);
+                self.store_frame_line();
+                let call = self.builder.ins().call_indirect(sig_ref, addr, &call_args);
+                self.builder.inst_results(call)[0]
+            }
+            ExprKind::Builtin(name, args) => self.gen_builtin(name, args)?,
+            ExprKind::Vec4(op, a, b, alloc) => {
+                // Element-wise on 4-element arrays, as two f64x2 halves
+                let a = self.gen_pointer(a)?;
+                let b = self.gen_pointer(b)?;
+                let result = match alloc {
+                    Alloc::Stack => self.stack_array(4, 4).1,
+                    Alloc::Heap => {
+                        let len = self.builder.ins().iconst(types::I64, 4);
+                        let pointers = self.builder.ins().iconst(types::I64, 0);
+                        self.call_runtime("nula_array_new", &[types::I64, types::I64], Some(types::I64), &[len, pointers])?
+                    }
+                };
+                for offset in [0, 16] {
+                    let va = self.builder.ins().load(types::F64X2, MemFlags::new(), a, offset);
+                    let vb = self.builder.ins().load(types::F64X2, MemFlags::new(), b, offset);
+                    let v = match op {
+                        BinOp::Add => self.builder.ins().fadd(va, vb),
+                        BinOp::Sub => self.builder.ins().fsub(va, vb),
+                        BinOp::Mul => self.builder.ins().fmul(va, vb),
+                        _ => self.builder.ins().fdiv(va, vb),
+                    };
+                    self.builder.ins().store(MemFlags::new(), v, result, offset);
+                }
+                result
             }
-            Ast::Index(name, index) => {
-                // Assume array var is ptr
-                let ptr = self.builder.use_var(*self.variables.get(name).expect("Undefined array"));
-                let idx = self.gen_ast(index);
-                let idx_i64 = self.builder.ins().fcvt_to_sint(types::I64, idx); // Assume index is f64, convert to i64
-                // Bounds check
-                // For memory safety: assume size stored somewhere, but for expansion, let's add size map later
-                // Skip for now
-                let offset = self.builder.ins().imul_imm(idx_i64, 8);
-                let addr = self.builder.ins().iadd(ptr, offset);
-                self.builder.ins().load(types::F64, MemFlags::new(), addr, 0)
+            ExprKind::Await(task) => {
+                // await task: run the scheduler until the task has finished
+                let handle = self.gen_pointer(task)?;
+                self.call_runtime("nula_await", &[types::I64], Some(types::F64), &[handle])?
             }
+        };
+        Ok(val)
+    }
+
+    fn func_signature(&mut self, arity: usize) -> Signature {
+        let mut sig = self.backend.module().make_signature();
+        for _ in 0..arity {
+            sig.params.push(AbiParam::new(types::F64));
+        }
+        sig.returns.push(AbiParam::new(types::F64));
+        sig
+    }
+
+    // Declaring the same name again returns the existing id
+    fn declare_func(&mut self, name: &str, arity: usize) -> NulaResult<FuncId> {
+        let sig = self.func_signature(arity);
+        let linkage = if self.exports.contains(name) { Linkage::Export } else { Linkage::Local };
+        let func_id = self.backend.module().declare_function(name, linkage, &sig)?;
+        self.functions.insert(name.to_string(), func_id);
+        Ok(func_id)
+    }
+
+    // Task entry points take a pointer to their arguments
+    fn task_signature(&mut self) -> Signature {
+        let mut sig = self.backend.module().make_signature();
+        sig.params.push(AbiParam::new(types::I64));
+        sig.returns.push(AbiParam::new(types::F64));
+        sig
+    }
+
+    fn declare_task(&mut self, name: &str) -> NulaResult<FuncId> {
+        let sig = self.task_signature();
+        Ok(self.backend.module().declare_function(&format!("__nula_task_{}", name), Linkage::Local, &sig)?)
+    }
+
+    fn gen_function(&mut self, function: &hir::Function) -> NulaResult<FuncId> {
+        let func_id = self.declare_func(&function.name, function.params)?;
+
+        let mut local_ctx = CodegenContext::new();
+        local_ctx.func.signature = self.func_signature(function.params);
+        if self.backend.debug_info() {
+            local_ctx.func.dfg.collect_debug_info();
+        }
+
+        let mut local_builder_ctx = FunctionBuilderContext::new();
+        let mut local_builder = FunctionBuilder::new(&mut local_ctx.func, &mut local_builder_ctx);
+
+        let entry = local_builder.create_block();
+        local_builder.append_block_params_for_function_params(entry);
+        local_builder.switch_to_block(entry);
+        local_builder.seal_block(entry);
+        let block_params = local_builder.block_params(entry).to_vec();
+
+        let mut local_codegen = CodeGen::new(self.backend, &mut local_builder);
+        local_codegen.function = function.name.clone();
+        local_codegen.functions = self.functions.clone();
+        local_codegen.async_functions = self.async_functions.clone();
+        local_codegen.exports = self.exports.clone();
+        local_codegen.asm_blocks = std::mem::take(&mut self.asm_blocks);
+        local_codegen.string_data = std::mem::take(&mut self.string_data);
+        local_codegen.imports = std::mem::take(&mut self.imports);
+        local_codegen.files = self.files.clone();
+
+        local_codegen.declare_locals(&function.locals);
+        for (i, &param_val) in block_params.iter().enumerate() {
+            // Strings and arrays come as their pointer bits, with a reference the function now owns
+            let param_val = if function.locals[i].ty.is_pointer() {
+                local_codegen.builder.ins().bitcast(types::I64, MemFlags::new(), param_val)
+            } else {
+                param_val
+            };
+            local_codegen.builder.def_var(local_codegen.variables[i], param_val);
+            local_codegen.builder.set_val_label(param_val, ValueLabel::new(i));
+        }
+        local_codegen.push_frame()?;
+        // Self tail calls jump back here, so it is sealed once the body is done
+        let body_block = local_codegen.builder.create_block();
+        local_codegen.builder.ins().jump(body_block, &[]);
+        local_codegen.builder.switch_to_block(body_block);
+        local_codegen.tail_call_target = Some((function.name.clone(), body_block));
+        local_codegen.gen_block(&function.body)?;
+        local_codegen.builder.seal_block(body_block);
+
+        local_codegen.release_locals()?;
+        local_codegen.pop_frame()?;
+        let ret_val = local_codegen.builder.ins().f64const(0.0);
+        local_codegen.builder.ins().return_(&[ret_val]);
+        self.asm_blocks = std::mem::take(&mut local_codegen.asm_blocks);
+        self.string_data = std::mem::take(&mut local_codegen.string_data);
+        self.imports = std::mem::take(&mut local_codegen.imports);
+        local_builder.finalize();
+
+        self.define(&function.name, func_id, &mut local_ctx, &function.locals)?;
+        Ok(func_id)
+    }
+
+    // Task entry point for an async fn: double task(double *args) { return name(args[0], ...); }
+    fn gen_task_trampoline(&mut self, name: &str, func_id: FuncId, arity: usize) -> NulaResult<FuncId> {
+        let task_id = self.declare_task(name)?;
+
+        let mut task_ctx = CodegenContext::new();
+        task_ctx.func.signature = self.task_signature();
+
+        let mut task_builder_ctx = FunctionBuilderContext::new();
+        let mut task_builder = FunctionBuilder::new(&mut task_ctx.func, &mut task_builder_ctx);
+
+        let entry = task_builder.create_block();
+        task_builder.append_block_params_for_function_params(entry);
+        task_builder.switch_to_block(entry);
+        task_builder.seal_block(entry);
+
+        let args_ptr = task_builder.block_params(entry)[0];
+        let mut call_args = Vec::new();
+        for i in 0..arity {
+            call_args.push(task_builder.ins().load(types::F64, MemFlags::trusted(), args_ptr, (i * 8) as i32));
+        }
+        let func_ref = self.backend.module().declare_func_in_func(func_id, task_builder.func);
+        let inst = task_builder.ins().call(func_ref, &call_args);
+        let result = task_builder.inst_results(inst)[0];
+        task_builder.ins().return_(&[result]);
+        task_builder.finalize();
+
+        self.define(&format!("__nula_task_{}", name), task_id, &mut task_ctx, &[])?;
+        Ok(task_id)
+    }
+
+    // Compiles a finished function, printing it along the way for --emit
+    fn define(&mut self, name: &str, func_id: FuncId, ctx: &mut CodegenContext, locals: &[hir::Local]) -> NulaResult<()> {
+        if self.print_clif {
+            println!("{}", ctx.func.display());
+        }
+        ctx.set_disasm(self.print_asm);
+        self.backend.emit_function(func_id, ctx, locals)?;
+        if let Some(vcode) = ctx.compiled_code().and_then(|code| code.vcode.as_ref()).filter(|_| self.print_asm) {
+            println!("{}:\n{}", name, vcode);
         }
+        Ok(())
+    }
+
+    fn gen_builtin(&mut self, name: &str, args: &[Expr]) -> NulaResult<Value> {
+        let val = match name {
+            "write" => {
+                if matches!(args[0].ty, hir::Type::String | hir::Type::Bytes) {
+                    let text = self.gen_expr(&args[0])?;
+                    self.call_runtime("nula_write_string", &[types::I64], None, &[text])?;
+                } else {
+                    let val = self.gen_f64(&args[0])?;
+                    self.call_runtime("nula_write_number", &[types::F64], None, &[val])?;
+                }
+                self.builder.ins().f64const(0.0)
+            }
+            "spawn" => {
+                // spawn(fn_name, arg): run a Nula function on a new OS thread
+                let func_ptr = self.gen_pointer(&args[0])?;
+                let arg = self.gen_escaping(&args[1])?;
+                let handle = self.call_runtime("nula_spawn", &[types::I64, types::F64], Some(types::I64), &[func_ptr, arg])?;
+                self.builder.ins().bitcast(types::F64, MemFlags::new(), handle)
+            }
+            "join" => {
+                // join(handle): wait for the thread and return its function's result
+                let handle = self.gen_pointer(&args[0])?;
+                self.call_runtime("nula_join", &[types::I64], Some(types::F64), &[handle])?
+            }
+            "channel" => {
+                // channel(): new unbounded queue shared between threads
+                let ch = self.call_runtime("nula_channel_new", &[], Some(types::I64), &[])?;
+                self.builder.ins().bitcast(types::F64, MemFlags::new(), ch)
+            }
+            "send" => {
+                // send(ch, v): push a value, waking one receiver
+                let ch = self.gen_pointer(&args[0])?;
+                let val = self.gen_escaping(&args[1])?;
+                self.call_runtime("nula_channel_send", &[types::I64, types::F64], None, &[ch, val])?
+            }
+            "recv" => {
+                // recv(ch): pop the oldest value, blocking while the channel is empty
+                let ch = self.gen_pointer(&args[0])?;
+                self.call_runtime("nula_channel_recv", &[types::I64], Some(types::F64), &[ch])?
+            }
+            "mutex" => {
+                let m = self.call_runtime("nula_mutex_new", &[], Some(types::I64), &[])?;
+                self.builder.ins().bitcast(types::F64, MemFlags::new(), m)
+            }
+            "lock" | "unlock" => {
+                let m = self.gen_pointer(&args[0])?;
+                let func = if name == "lock" { "nula_mutex_lock" } else { "nula_mutex_unlock" };
+                self.call_runtime(func, &[types::I64], None, &[m])?
+            }
+            "atomic" => {
+                // atomic(n): integer cell for counters shared between threads
+                let init = self.gen_f64(&args[0])?;
+                let cell = self.call_runtime("nula_atomic_new", &[types::F64], Some(types::I64), &[init])?;
+                self.builder.ins().bitcast(types::F64, MemFlags::new(), cell)
+            }
+            "atomic_add" => {
+                // atomic_add(cell, n): returns the updated value
+                let cell = self.gen_pointer(&args[0])?;
+                let n = self.gen_f64(&args[1])?;
+                // NaN is 0 and numbers past the cell's range its end, rather than a trap
+                let n = self.builder.ins().fcvt_to_sint_sat(types::I64, n);
+                let old = self.builder.ins().atomic_rmw(types::I64, MemFlags::trusted(), ir::AtomicRmwOp::Add, cell, n);
+                let new = self.builder.ins().iadd(old, n);
+                self.builder.ins().fcvt_from_sint(types::F64, new)
+            }
+            "atomic_load" => {
+                let cell = self.gen_pointer(&args[0])?;
+                let val = self.builder.ins().atomic_load(types::I64, MemFlags::trusted(), cell);
+                self.builder.ins().fcvt_from_sint(types::F64, val)
+            }
+            "atomic_store" => {
+                let cell = self.gen_pointer(&args[0])?;
+                let val = self.gen_f64(&args[1])?;
+                let n = self.builder.ins().fcvt_to_sint_sat(types::I64, val);
+                self.builder.ins().atomic_store(MemFlags::trusted(), n, cell);
+                val
+            }
+            "run" => {
+                // run(cmd, args): [stdout, stderr, exit status]
+                let hir::Type::Array(_, argc) = args[1].ty else { unreachable!("lowering checks run's arguments") };
+                let cmd = self.gen_pointer(&args[0])?;
+                let argv = self.gen_pointer(&args[1])?;
+                let argc = self.builder.ins().iconst(types::I64, argc as i64);
+                self.call_runtime("nula_run", &[types::I64, types::I64, types::I64], Some(types::I64), &[cmd, argv, argc])?
+            }
+            "now" => {
+                self.call_runtime("nula_now", &[], Some(types::F64), &[])?
+            }
+            "format_time" => {
+                // format_time(t, fmt): strftime-style formatting in local time
+                let t = self.gen_f64(&args[0])?;
+                let fmt = self.gen_pointer(&args[1])?;
+                self.call_runtime("nula_format_time", &[types::F64, types::I64], Some(types::I64), &[t, fmt])?
+            }
+            "parse_time" => {
+                // parse_time(s, fmt): timestamp, or -1 if s doesn't match fmt
+                let text = self.gen_pointer(&args[0])?;
+                let fmt = self.gen_pointer(&args[1])?;
+                self.call_runtime("nula_parse_time", &[types::I64, types::I64], Some(types::F64), &[text, fmt])?
+            }
+            "sha256" | "md5" => {
+                // Hex digest of a string
+                let text = self.gen_pointer(&args[0])?;
+                self.call_runtime(&format!("nula_{}", name), &[types::I64], Some(types::I64), &[text])?
+            }
+            "fnv" => {
+                // 32-bit FNV-1a hash as a number, for hash tables and quick checksums
+                let text = self.gen_pointer(&args[0])?;
+                self.call_runtime("nula_fnv", &[types::I64], Some(types::F64), &[text])?
+            }
+            "base64_encode" | "base64_decode" | "hex_encode" | "hex_decode" => {
+                let text = self.gen_pointer(&args[0])?;
+                self.call_runtime(&format!("nula_{}", name), &[types::I64], Some(types::I64), &[text])?
+            }
+            "bytes" => {
+                // bytes(s): byte buffer holding a copy of the string
+                let text = self.gen_pointer(&args[0])?;
+                self.call_runtime("nula_bytes_from_string", &[types::I64], Some(types::I64), &[text])?
+            }
+            "bytes_len" => {
+                let buf = self.gen_pointer(&args[0])?;
+                self.call_runtime("nula_bytes_len", &[types::I64], Some(types::F64), &[buf])?
+            }
+            "byte_at" => {
+                // byte_at(b, i): bounds-checked read of a single byte
+                let buf = self.gen_pointer(&args[0])?;
+                let index = self.gen_f64(&args[1])?;
+                self.call_runtime("nula_bytes_at", &[types::I64, types::F64], Some(types::F64), &[buf, index])?
+            }
+            "slice" => {
+                // slice(b, start, end): copy of bytes [start, end)
+                let buf = self.gen_pointer(&args[0])?;
+                let start = self.gen_f64(&args[1])?;
+                let end = self.gen_f64(&args[2])?;
+                self.call_runtime("nula_bytes_slice", &[types::I64, types::F64, types::F64], Some(types::I64), &[buf, start, end])?
+            }
+            "bytes_to_string" => {
+                let buf = self.gen_pointer(&args[0])?;
+                self.call_runtime("nula_bytes_to_string", &[types::I64], Some(types::I64), &[buf])?
+            }
+            "read_file" => {
+                // read_file(path): the file's contents, or a panic if it can't be read
+                let path = self.gen_pointer(&args[0])?;
+                self.call_runtime("nula_read_file", &[types::I64], Some(types::I64), &[path])?
+            }
+            "write_file" => {
+                // write_file(path, data): replaces the file's contents with a string or bytes
+                let path = self.gen_pointer(&args[0])?;
+                let data = self.gen_pointer(&args[1])?;
+                self.call_runtime("nula_write_file", &[types::I64, types::I64], None, &[path, data])?
+            }
+            "asm" => {
+                // asm("..."): assembled out of line as a function returning its xmm0 value
+                let source = match args {
+                    [Expr { kind: ExprKind::Str(source), .. }] => source.clone(),
+                    _ => unreachable!("lowering checks asm's argument"),
+                };
+                let symbol = format!("__nula_asm_{}", self.asm_blocks.len());
+                self.asm_blocks.push(source);
+                self.call_runtime(&symbol, &[], Some(types::F64), &[])?
+            }
+            "vec4_dot" => {
+                let a = self.gen_pointer(&args[0])?;
+                let b = self.gen_pointer(&args[1])?;
+                let mut halves = Vec::new();
+                for offset in [0, 16] {
+                    let va = self.builder.ins().load(types::F64X2, MemFlags::new(), a, offset);
+                    let vb = self.builder.ins().load(types::F64X2, MemFlags::new(), b, offset);
+                    halves.push(self.builder.ins().fmul(va, vb));
+                }
+                let sum = self.builder.ins().fadd(halves[0], halves[1]);
+                let lo = self.builder.ins().extractlane(sum, 0);
+                let hi = self.builder.ins().extractlane(sum, 1);
+                self.builder.ins().fadd(lo, hi)
+            }
+            "exit" => {
+                let code = self.gen_f64(&args[0])?;
+                self.call_runtime("nula_exit", &[types::F64], None, &[code])?
+            }
+            "assert" => {
+                let cond = self.gen_f64(&args[0])?;
+                let (file, line) = self.location();
+                self.call_runtime("nula_assert", &[types::F64, types::I64, types::I64], None, &[cond, file, line])?
+            }
+            // Only in the harness `nula test` generates, see lower::test_harness
+            "run_test" => {
+                let test = self.gen_pointer(&args[0])?;
+                let name = self.gen_pointer(&args[1])?;
+                self.call_runtime("nula_run_test", &[types::I64, types::I64], None, &[test, name])?
+            }
+            "test_summary" => self.call_runtime("nula_test_summary", &[], None, &[])?,
+            // Only first thing in main, see lower::enable_gc
+            "gc_enable" => self.call_runtime("nula_gc_enable", &[], None, &[])?,
+            _ => unreachable!("`{}` is not a builtin", name),
+        };
+        Ok(val)
+    }
+
+    // Array of `len` elements in the function's frame, behind a header like
+    // the runtime's counted values that says it's never freed. Returns the
+    // slot and the address of the first element.
+    fn stack_array(&mut self, len: usize, align_shift: u8) -> (StackSlot, Value) {
+        let size = HEADER_SIZE + len * 8;
+        let slot = self.builder.create_sized_stack_slot(StackSlotData::new(StackSlotKind::ExplicitSlot, size as u32, align_shift.max(3)));
+        for (i, field) in [-1, 0, len as i64, (len * 8) as i64].into_iter().enumerate() {
+            let field = self.builder.ins().iconst(types::I64, field);
+            self.builder.ins().stack_store(field, slot, (i * 8) as i32);
+        }
+        (slot, self.builder.ins().stack_addr(types::I64, slot, HEADER_SIZE as i32))
+    }
+
+    // Nula's truthiness: any number other than 0
+    fn is_true(&mut self, val: Value) -> Value {
+        let zero = self.builder.ins().f64const(0.0);
+        self.builder.ins().fcmp(FloatCC::NotEqual, val, zero)
+    }
+
+    fn bool_to_f64(&mut self, cond: Value) -> Value {
+        let one = self.builder.ins().f64const(1.0);
+        let zero = self.builder.ins().f64const(0.0);
+        self.builder.ins().select(cond, one, zero)
+    }
+
+    // Reference to a function of the module in the one being generated
+    fn func_ref(&mut self, func_id: FuncId) -> FuncRef {
+        *self.func_refs.entry(func_id).or_insert_with(|| self.backend.module().declare_func_in_func(func_id, self.builder.func))
+    }
+
+    // Calls a function from the runtime library (runtime/nula_rt.c)
+    fn call_runtime(&mut self, name: &str, params: &[Type], ret: Option<Type>, args: &[Value]) -> NulaResult<Value> {
+        let func_id = match self.imports.get(name) {
+            Some(&func_id) => func_id,
+            None => {
+                let mut sig = self.backend.module().make_signature();
+                for &param in params {
+                    sig.params.push(AbiParam::new(param));
+                }
+                if let Some(ret) = ret {
+                    sig.returns.push(AbiParam::new(ret));
+                }
+                let func_id = self.backend.module().declare_function(name, Linkage::Import, &sig)?;
+                self.imports.insert(name.to_string(), func_id);
+                func_id
+            }
+        };
+        let func_ref = self.func_ref(func_id);
+        self.store_frame_line();
+        let inst = self.builder.ins().call(func_ref, args);
+        match ret {
+            Some(_) => Ok(self.builder.inst_results(inst)[0]),
+            None => Ok(self.builder.ins().f64const(0.0)),
+        }
+    }
+}
+
+// Whether `expr` makes a string, bytes or array with a reference of its own,
+// rather than using one a local or an array holds (or a literal)
+fn is_new_reference(expr: &Expr) -> bool {
+    expr.ty.is_pointer() && matches!(expr.kind, ExprKind::Binary(..) | ExprKind::Builtin(..) | ExprKind::Call(..) | ExprKind::Array(_, Alloc::Heap) | ExprKind::Vec4(.., Alloc::Heap))
+}
+
+// How a value of the given type is held in registers
+fn repr(ty: &hir::Type) -> Type {
+    if ty.is_pointer() {
+        types::I64
+    } else {
+        types::F64
+    }
+}
+
+impl From<ModuleError> for Diagnostic {
+    fn from(err: ModuleError) -> Self {
+        Diagnostic::error(format!("Code generation failed: {}", err))
     }
 }
@@ -0,0 +1,265 @@
+// src/const_eval.rs - Compile-time evaluation of pure function calls
+
+use std::collections::{HashMap, HashSet};
+
+use crate::ast::{Ast, Node};
+use crate::hir::BinOp;
+
+// Statements interpreted per folded call before giving up, so a function that
+// doesn't terminate is left for runtime instead of hanging the compiler
+const STEP_LIMIT: usize = 1_000_000;
+// Calls nested this deep are left for runtime too, before the compiler's own
+// stack runs out
+const DEPTH_LIMIT: usize = 1000;
+
+enum Flow {
+    Normal,
+    Return(f64),
+}
+
+// Variables of the call being interpreted, innermost block last
+type Env = Vec<HashMap<String, f64>>;
+
+fn lookup<'e>(env: &'e mut Env, name: &str) -> Option<&'e mut f64> {
+    env.iter_mut().rev().find_map(|scope| scope.get_mut(name))
+}
+
+struct Evaluator<'a> {
+    functions: &'a HashMap<String, (Vec<String>, Vec<Node>)>,
+    pure: &'a HashSet<String>,
+    steps: usize,
+    depth: usize, // calls being interpreted
+}
+
+// Replaces calls to side-effect-free top-level functions whose arguments are
+// all constants with the value the call returns.
+pub fn eval_pure_calls(ast: Vec<Node>) -> Vec<Node> {
+    let mut functions = HashMap::new();
+    for node in &ast {
+        if let Ast::FuncDef(name, params, body) = &node.ast {
+            functions.insert(name.clone(), (params.clone(), body.clone()));
+        }
+    }
+    let pure = pure_functions(&functions);
+    let mut evaluator = Evaluator { functions: &functions, pure: &pure, steps: 0, depth: 0 };
+    ast.into_iter().map(|node| evaluator.fold(node)).collect()
+}
+
+// Functions that only compute on numbers and call other pure functions.
+// Starts from every function and drops impure ones until nothing changes,
+// so (mutually) recursive functions stay pure.
+fn pure_functions(functions: &HashMap<String, (Vec<String>, Vec<Node>)>) -> HashSet<String> {
+    let mut pure: HashSet<String> = functions.keys().cloned().collect();
+    loop {
+        let impure: Vec<String> = pure
+            .iter()
+            .filter(|name| !functions[*name].1.iter().all(|stmt| is_pure(stmt, &pure)))
+            .cloned()
+            .collect();
+        if impure.is_empty() {
+            return pure;
+        }
+        for name in impure {
+            pure.remove(&name);
+        }
+    }
+}
+
+fn is_pure(node: &Node, pure: &HashSet<String>) -> bool {
+    match &node.ast {
+        Ast::Literal(_) | Ast::Var(_) => true,
+        Ast::BinOp(op, left, right) => op != "in" && is_pure(left, pure) && is_pure(right, pure),
+        Ast::VarDecl(_, expr) | Ast::Assign(_, expr) | Ast::Return(expr) => is_pure(expr, pure),
+        Ast::If(cond, then_body, else_body) => {
+            is_pure(cond, pure)
+                && then_body.iter().all(|s| is_pure(s, pure))
+                && else_body.iter().flatten().all(|s| is_pure(s, pure))
+        }
+        Ast::While(cond, body) => is_pure(cond, pure) && body.iter().all(|s| is_pure(s, pure)),
+        Ast::For(_, start, end, body) => {
+            is_pure(start, pure) && is_pure(end, pure) && body.iter().all(|s| is_pure(s, pure))
+        }
+        Ast::FuncCall(name, args) => pure.contains(name) && args.iter().all(|a| is_pure(a, pure)),
+        _ => false,
+    }
+}
+
+impl<'a> Evaluator<'a> {
+    fn fold(&mut self, node: Node) -> Node {
+        let span = node.span;
+        let ast = match node.ast {
+            Ast::FuncCall(name, args) => {
+                let args: Vec<Node> = args.into_iter().map(|a| self.fold(a)).collect();
+                if self.pure.contains(&name) {
+                    let values: Option<Vec<f64>> = args
+                        .iter()
+                        .map(|a| if let Ast::Literal(v) = a.ast { Some(v) } else { None })
+                        .collect();
+                    if let Some(values) = values {
+                        self.steps = 0;
+                        if let Some(result) = self.call(&name, &values) {
+                            return Node { ast: Ast::Literal(result), span };
+                        }
+                    }
+                }
+                Ast::FuncCall(name, args)
+            }
+            Ast::VarDecl(name, expr) => Ast::VarDecl(name, Box::new(self.fold(*expr))),
+            Ast::Assign(name, expr) => Ast::Assign(name, Box::new(self.fold(*expr))),
+            Ast::Return(expr) => Ast::Return(Box::new(self.fold(*expr))),
+            Ast::Await(expr) => Ast::Await(Box::new(self.fold(*expr))),
+            Ast::BinOp(op, left, right) => Ast::BinOp(op, Box::new(self.fold(*left)), Box::new(self.fold(*right))),
+            Ast::Index(array, index) => Ast::Index(Box::new(self.fold(*array)), Box::new(self.fold(*index))),
+            Ast::Call(callee, args) => Ast::Call(Box::new(self.fold(*callee)), self.fold_block(args)),
+            Ast::If(cond, then_body, else_body) => Ast::If(
+                Box::new(self.fold(*cond)),
+                self.fold_block(then_body),
+                else_body.map(|eb| self.fold_block(eb)),
+            ),
+            Ast::While(cond, body) => Ast::While(Box::new(self.fold(*cond)), self.fold_block(body)),
+            Ast::For(var, start, end, body) => {
+                Ast::For(var, Box::new(self.fold(*start)), Box::new(self.fold(*end)), self.fold_block(body))
+            }
+            Ast::ForEach(index, elem, array, body) => {
+                Ast::ForEach(index, elem, Box::new(self.fold(*array)), self.fold_block(body))
+            }
+            Ast::FuncDef(name, params, body) => Ast::FuncDef(name, params, self.fold_block(body)),
+            Ast::AsyncFuncDef(name, params, body) => Ast::AsyncFuncDef(name, params, self.fold_block(body)),
+            Ast::Array(elements) => Ast::Array(self.fold_block(elements)),
+            other => other,
+        };
+        Node { ast, span }
+    }
+
+    fn fold_block(&mut self, stmts: Vec<Node>) -> Vec<Node> {
+        stmts.into_iter().map(|s| self.fold(s)).collect()
+    }
+
+    // Interprets a call, or returns None if it can't be evaluated at compile time
+    fn call(&mut self, name: &str, args: &[f64]) -> Option<f64> {
+        let (params, body) = self.functions.get(name)?;
+        if params.len() != args.len() || self.depth == DEPTH_LIMIT {
+            return None;
+        }
+        let mut env = vec![params.iter().cloned().zip(args.iter().cloned()).collect()];
+        self.depth += 1;
+        let flow = self.exec_block(body, &mut env);
+        self.depth -= 1;
+        match flow? {
+            Flow::Return(v) => Some(v),
+            Flow::Normal => Some(0.0),
+        }
+    }
+
+    // Runs a nested block in its own scope
+    fn exec_scoped(&mut self, stmts: &[Node], env: &mut Env) -> Option<Flow> {
+        env.push(HashMap::new());
+        let flow = self.exec_block(stmts, env);
+        env.pop();
+        flow
+    }
+
+    fn exec_block(&mut self, stmts: &[Node], env: &mut Env) -> Option<Flow> {
+        for stmt in stmts {
+            if let Flow::Return(v) = self.exec(stmt, env)? {
+                return Some(Flow::Return(v));
+            }
+        }
+        Some(Flow::Normal)
+    }
+
+    fn exec(&mut self, stmt: &Node, env: &mut Env) -> Option<Flow> {
+        self.steps += 1;
+        if self.steps > STEP_LIMIT {
+            return None;
+        }
+        match &stmt.ast {
+            Ast::Return(expr) => Some(Flow::Return(self.eval(expr, env)?)),
+            Ast::VarDecl(name, expr) => {
+                let v = self.eval(expr, env)?;
+                env.last_mut()?.insert(name.clone(), v);
+                Some(Flow::Normal)
+            }
+            Ast::Assign(name, expr) => {
+                let v = self.eval(expr, env)?;
+                match lookup(env, name) {
+                    Some(slot) => *slot = v,
+                    None => {
+                        env.last_mut()?.insert(name.clone(), v);
+                    }
+                }
+                Some(Flow::Normal)
+            }
+            Ast::If(cond, then_body, else_body) => {
+                if self.eval(cond, env)? != 0.0 {
+                    self.exec_scoped(then_body, env)
+                } else if let Some(eb) = else_body {
+                    self.exec_scoped(eb, env)
+                } else {
+                    Some(Flow::Normal)
+                }
+            }
+            Ast::While(cond, body) => {
+                while self.eval(cond, env)? != 0.0 {
+                    if let Flow::Return(v) = self.exec_scoped(body, env)? {
+                        return Some(Flow::Return(v));
+                    }
+                    self.steps += 1;
+                    if self.steps > STEP_LIMIT {
+                        return None;
+                    }
+                }
+                Some(Flow::Normal)
+            }
+            Ast::For(var, start, end, body) => {
+                // Same shape as the codegen loop: end is evaluated once, the
+                // counter is re-read after the body in case it was assigned
+                let start = self.eval(start, env)?;
+                let end = self.eval(end, env)?;
+                env.push(HashMap::from([(var.clone(), start)]));
+                let flow = self.exec_for(var, end, body, env);
+                env.pop();
+                flow
+            }
+            _ => {
+                self.eval(stmt, env)?;
+                Some(Flow::Normal)
+            }
+        }
+    }
+
+    // The loop of a for statement, whose counter is bound in the innermost scope
+    fn exec_for(&mut self, var: &str, end: f64, body: &[Node], env: &mut Env) -> Option<Flow> {
+        while env.last()?[var] < end {
+            if let Flow::Return(v) = self.exec_scoped(body, env)? {
+                return Some(Flow::Return(v));
+            }
+            self.steps += 1;
+            if self.steps > STEP_LIMIT {
+                return None;
+            }
+            *env.last_mut()?.get_mut(var)? += 1.0;
+        }
+        Some(Flow::Normal)
+    }
+
+    fn eval(&mut self, expr: &Node, env: &mut Env) -> Option<f64> {
+        match &expr.ast {
+            Ast::Literal(v) => Some(*v),
+            Ast::Var(name) => lookup(env, name).copied(),
+            Ast::BinOp(op, left, right) => {
+                let l = self.eval(left, env)?;
+                let r = self.eval(right, env)?;
+                BinOp::from_symbol(op)?.eval(l, r)
+            }
+            Ast::FuncCall(name, args) if self.pure.contains(name) => {
+                let mut values = Vec::new();
+                for arg in args {
+                    values.push(self.eval(arg, env)?);
+                }
+                self.call(name, &values)
+            }
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,365 @@
+// src/debuginfo.rs - DWARF debug info for -g
+//
+// Codegen tags each instruction with the line it came from (source_loc) and
+// each value assigned to a local with the local's id. Once Cranelift has
+// compiled a function, DebugInfo keeps what became of those: where in the
+// machine code each line starts and which register or stack slot holds each
+// local. When the object is finished they're written into it as DWARF line
+// tables, a subprogram per function with its locals, and the frame info
+// debuggers need to walk the stack.
+
+use std::collections::HashMap;
+
+use cranelift_codegen::gimli::write::{
+    Address, AttributeValue, CommonInformationEntry, DwarfUnit, EndianVec, Expression, FrameTable, LineProgram,
+    LineString, Location, LocationList, Range, RangeList, Sections, UnitEntryId, Writer,
+};
+use cranelift_codegen::gimli::{self, Encoding, Format, LineEncoding, RunTimeEndian, SectionId};
+use cranelift_codegen::ir::{SourceLoc, ValueLabel};
+use cranelift_codegen::isa::unwind::{systemv, UnwindInfo};
+use cranelift_codegen::isa::TargetIsa;
+use cranelift_codegen::{Context as CodegenContext, LabelValueLoc};
+use cranelift_module::FuncId;
+use cranelift_object::object::write::{Relocation, StandardSegment};
+use cranelift_object::object::{BinaryFormat, RelocationEncoding, RelocationFlags, RelocationKind, SectionKind};
+use cranelift_object::ObjectProduct;
+
+use crate::ast::Span;
+use crate::diagnostic::{Diagnostic, NulaResult};
+use crate::hir::{Local, Type};
+
+// Cranelift keeps a u32 per instruction, so the file index goes above the line
+const LINE_BITS: u32 = 20;
+const LINE_MASK: u32 = (1 << LINE_BITS) - 1;
+
+// Source location to tag the instructions generated for `span` with, if it
+// has one
+pub fn source_loc(span: Span) -> SourceLoc {
+    if span.line == 0 {
+        return SourceLoc::default();
+    }
+    SourceLoc::new(((span.file as u32) << LINE_BITS) | (span.line as u32).min(LINE_MASK))
+}
+
+struct Function {
+    func_id: FuncId,
+    name: String,
+    size: u32,
+    lines: Vec<(u32, SourceLoc)>, // code offset where each run of instructions from one line starts
+    locals: Vec<Variable>,
+    unwind: Option<systemv::UnwindInfo>,
+}
+
+struct Variable {
+    name: String,
+    ty: Type,
+    locations: Vec<(u32, u32, Expression)>, // where it is over each range of code
+}
+
+pub struct DebugInfo {
+    files: Vec<String>, // by Span::file
+    comp_dir: String,   // relative file names are relative to it
+    cie: Option<CommonInformationEntry>,
+    functions: Vec<Function>,
+}
+
+impl DebugInfo {
+    pub fn new(files: Vec<String>, comp_dir: String, isa: &dyn TargetIsa) -> Self {
+        DebugInfo { files, comp_dir, cie: isa.create_systemv_cie(), functions: Vec::new() }
+    }
+
+    // Records a function just compiled in `ctx`, whose values are labeled with
+    // the ids of `locals`
+    pub fn add_function(&mut self, func_id: FuncId, name: &str, ctx: &CodegenContext, locals: &[Local], isa: &dyn TargetIsa) {
+        let code = ctx.compiled_code().expect("function was compiled");
+        let mut lines: Vec<(u32, SourceLoc)> = Vec::new();
+        for srcloc in code.buffer.get_srclocs_sorted() {
+            if !srcloc.loc.is_default() && lines.last().is_none_or(|&(_, loc)| loc != srcloc.loc) {
+                lines.push((srcloc.start, srcloc.loc));
+            }
+        }
+        let locals = locals
+            .iter()
+            .enumerate()
+            .filter(|(_, local)| !local.temporary)
+            .map(|(id, local)| {
+                let locations = code.value_labels_ranges.get(&ValueLabel::from_u32(id as u32)).map_or(Vec::new(), |ranges| {
+                    ranges
+                        .iter()
+                        .filter_map(|range| {
+                            let mut location = Expression::new();
+                            match range.loc {
+                                LabelValueLoc::Reg(reg) => location.op_reg(gimli::Register(isa.map_regalloc_reg_to_dwarf(reg).ok()?)),
+                                // The frame base is the CFA, see write
+                                LabelValueLoc::CFAOffset(offset) => location.op_fbreg(offset),
+                            }
+                            Some((range.start, range.end, location))
+                        })
+                        .collect()
+                });
+                Variable { name: local.name.clone(), ty: local.ty.clone(), locations }
+            })
+            .collect();
+        let unwind = match code.create_unwind_info(isa) {
+            Ok(Some(UnwindInfo::SystemV(info))) => Some(info),
+            _ => None,
+        };
+        self.functions.push(Function { func_id, name: name.to_string(), size: code.code_info().total_size, lines, locals, unwind });
+    }
+
+    // Adds the .debug_* sections to the finished object
+    pub fn write(self, product: &mut ObjectProduct) -> NulaResult<()> {
+        let encoding = Encoding { format: Format::Dwarf32, version: 4, address_size: 8 };
+        let mut dwarf = DwarfUnit::new(encoding);
+        let comp_dir = LineString::new(self.comp_dir.as_bytes(), encoding, &mut dwarf.line_strings);
+        let comp_name = LineString::new(self.files[0].as_bytes(), encoding, &mut dwarf.line_strings);
+        let mut line_program = LineProgram::new(encoding, LineEncoding::default(), comp_dir, comp_name, None);
+        let dir = line_program.default_directory();
+        let file_ids: Vec<_> = self
+            .files
+            .iter()
+            .map(|file| {
+                let name = LineString::new(file.as_bytes(), encoding, &mut dwarf.line_strings);
+                line_program.add_file(name, dir, None)
+            })
+            .collect();
+
+        // Nula's values are C doubles and char/double pointers, so debuggers
+        // show them best as C
+        let root = dwarf.unit.root();
+        let producer = dwarf.strings.add("nula");
+        let name = dwarf.strings.add(self.files[0].as_bytes());
+        let comp_dir = dwarf.strings.add(self.comp_dir.as_bytes());
+        let entry = dwarf.unit.get_mut(root);
+        entry.set(gimli::DW_AT_producer, AttributeValue::StringRef(producer));
+        entry.set(gimli::DW_AT_language, AttributeValue::Language(gimli::DW_LANG_C99));
+        entry.set(gimli::DW_AT_name, AttributeValue::StringRef(name));
+        entry.set(gimli::DW_AT_comp_dir, AttributeValue::StringRef(comp_dir));
+        let number = base_type(&mut dwarf, "double", gimli::DW_ATE_float, 8);
+        let char = base_type(&mut dwarf, "char", gimli::DW_ATE_signed_char, 1);
+        let string = pointer_type(&mut dwarf, char);
+        let array = pointer_type(&mut dwarf, number);
+
+        let mut ranges = Vec::new();
+        for (symbol, function) in self.functions.iter().enumerate() {
+            let start = Address::Symbol { symbol, addend: 0 };
+            ranges.push(Range::StartLength { begin: start, length: function.size as u64 });
+
+            // Line table. Instructions before the first tagged one are the
+            // prologue, which breakpoints on the function skip.
+            if let Some(&(_, first)) = function.lines.first() {
+                line_program.begin_sequence(Some(start));
+                let mut rows = function.lines.clone();
+                if rows[0].0 != 0 {
+                    rows.insert(0, (0, first));
+                }
+                let prologue_end = rows.len() - function.lines.len();
+                for (i, &(offset, loc)) in rows.iter().enumerate() {
+                    let row = line_program.row();
+                    row.address_offset = offset as u64;
+                    row.file = file_ids[(loc.bits() >> LINE_BITS) as usize];
+                    row.line = (loc.bits() & LINE_MASK) as u64;
+                    row.prologue_end = i == prologue_end;
+                    line_program.generate_row();
+                }
+                line_program.end_sequence(function.size as u64);
+            }
+
+            let name = dwarf.strings.add(function.name.as_bytes());
+            let subprogram = dwarf.unit.add(root, gimli::DW_TAG_subprogram);
+            let mut frame_base = Expression::new();
+            frame_base.op(gimli::DW_OP_call_frame_cfa);
+            let entry = dwarf.unit.get_mut(subprogram);
+            entry.set(gimli::DW_AT_name, AttributeValue::StringRef(name));
+            entry.set(gimli::DW_AT_low_pc, AttributeValue::Address(start));
+            entry.set(gimli::DW_AT_high_pc, AttributeValue::Udata(function.size as u64));
+            entry.set(gimli::DW_AT_frame_base, AttributeValue::Exprloc(frame_base));
+            if let Some(&(_, loc)) = function.lines.first() {
+                entry.set(gimli::DW_AT_decl_file, AttributeValue::FileIndex(Some(file_ids[(loc.bits() >> LINE_BITS) as usize])));
+                entry.set(gimli::DW_AT_decl_line, AttributeValue::Udata((loc.bits() & LINE_MASK) as u64));
+            }
+
+            for local in &function.locals {
+                let name = dwarf.strings.add(local.name.as_bytes());
+                let ty = match local.ty {
+                    Type::String | Type::Bytes => string,
+                    Type::Array(..) | Type::Tuple(_) => array,
+                    _ => number,
+                };
+                let locations = LocationList(
+                    local
+                        .locations
+                        .iter()
+                        .map(|(begin, end, data)| Location::StartEnd {
+                            begin: Address::Symbol { symbol, addend: *begin as i64 },
+                            end: Address::Symbol { symbol, addend: *end as i64 },
+                            data: data.clone(),
+                        })
+                        .collect(),
+                );
+                let variable = dwarf.unit.add(subprogram, gimli::DW_TAG_variable);
+                let location = (!locations.0.is_empty()).then(|| dwarf.unit.locations.add(locations));
+                let entry = dwarf.unit.get_mut(variable);
+                entry.set(gimli::DW_AT_name, AttributeValue::StringRef(name));
+                entry.set(gimli::DW_AT_type, AttributeValue::UnitRef(ty));
+                // Without one the debugger says it's optimized out
+                if let Some(location) = location {
+                    entry.set(gimli::DW_AT_location, AttributeValue::LocationListRef(location));
+                }
+            }
+        }
+        dwarf.unit.line_program = line_program;
+        let ranges = dwarf.unit.ranges.add(RangeList(ranges));
+        let entry = dwarf.unit.get_mut(root);
+        entry.set(gimli::DW_AT_low_pc, AttributeValue::Address(Address::Constant(0)));
+        entry.set(gimli::DW_AT_ranges, AttributeValue::RangeListRef(ranges));
+
+        let format = product.object.format();
+        // Mach-O leaves debug sections out of the link, so offsets into them are final
+        let mut sections = Sections::new(SectionWriter::new(format != BinaryFormat::MachO));
+        dwarf.write(&mut sections).map_err(write_error)?;
+        if let Some(cie) = self.cie {
+            let mut frames = FrameTable::default();
+            let cie = frames.add_cie(cie);
+            for (symbol, function) in self.functions.iter().enumerate() {
+                if let Some(unwind) = &function.unwind {
+                    frames.add_fde(cie, unwind.to_fde(Address::Symbol { symbol, addend: 0 }));
+                }
+            }
+            frames.write_debug_frame(&mut sections.debug_frame).map_err(write_error)?;
+        }
+
+        let symbols: Vec<_> = self.functions.iter().map(|function| product.function_symbol(function.func_id)).collect();
+        let object = &mut product.object;
+        let mut section_ids = HashMap::new();
+        sections
+            .for_each(|id, section| -> NulaResult<()> {
+                if section.data.slice().is_empty() {
+                    return Ok(());
+                }
+                let name = if format == BinaryFormat::MachO { id.name().replace('.', "__") } else { id.name().to_string() };
+                let segment = object.segment_name(StandardSegment::Debug).to_vec();
+                let section_id = object.add_section(segment, name.into_bytes(), SectionKind::Debug);
+                object.append_section_data(section_id, section.data.slice(), 1);
+                section_ids.insert(id, section_id);
+                Ok(())
+            })?;
+        sections.for_each(|id, section| -> NulaResult<()> {
+            for reloc in &section.relocs {
+                let (symbol, kind) = match reloc.target {
+                    RelocTarget::Function(i) => (symbols[i], RelocationKind::Absolute),
+                    RelocTarget::Section(target) => {
+                        let symbol = object.section_symbol(section_ids[&target]);
+                        (symbol, if format == BinaryFormat::Coff { RelocationKind::SectionOffset } else { RelocationKind::Absolute })
+                    }
+                };
+                let flags = RelocationFlags::Generic { kind, encoding: RelocationEncoding::Generic, size: reloc.size * 8 };
+                object
+                    .add_relocation(section_ids[&id], Relocation { offset: reloc.offset, symbol, addend: reloc.addend, flags })
+                    .map_err(|err| Diagnostic::error(format!("Couldn't write debug info: {}", err)))?;
+            }
+            Ok(())
+        })
+    }
+}
+
+fn write_error(err: gimli::write::Error) -> Diagnostic {
+    Diagnostic::error(format!("Couldn't write debug info: {}", err))
+}
+
+fn base_type(dwarf: &mut DwarfUnit, name: &str, encoding: gimli::DwAte, size: u8) -> UnitEntryId {
+    let name = dwarf.strings.add(name);
+    let root = dwarf.unit.root();
+    let id = dwarf.unit.add(root, gimli::DW_TAG_base_type);
+    let entry = dwarf.unit.get_mut(id);
+    entry.set(gimli::DW_AT_name, AttributeValue::StringRef(name));
+    entry.set(gimli::DW_AT_encoding, AttributeValue::Encoding(encoding));
+    entry.set(gimli::DW_AT_byte_size, AttributeValue::Data1(size));
+    id
+}
+
+fn pointer_type(dwarf: &mut DwarfUnit, to: UnitEntryId) -> UnitEntryId {
+    let root = dwarf.unit.root();
+    let id = dwarf.unit.add(root, gimli::DW_TAG_pointer_type);
+    let entry = dwarf.unit.get_mut(id);
+    entry.set(gimli::DW_AT_type, AttributeValue::UnitRef(to));
+    entry.set(gimli::DW_AT_byte_size, AttributeValue::Data1(8));
+    id
+}
+
+// What an address or offset written into a debug section refers to: a
+// function, by its index in DebugInfo::functions, or another debug section
+#[derive(Clone, Copy)]
+enum RelocTarget {
+    Function(usize),
+    Section(SectionId),
+}
+
+#[derive(Clone)]
+struct Reloc {
+    offset: u64,
+    size: u8,
+    target: RelocTarget,
+    addend: i64,
+}
+
+// Contents of a debug section, with the relocations the linker fills its
+// addresses in with
+#[derive(Clone)]
+struct SectionWriter {
+    data: EndianVec<RunTimeEndian>,
+    relocs: Vec<Reloc>,
+    relocate_sections: bool,
+}
+
+impl SectionWriter {
+    fn new(relocate_sections: bool) -> Self {
+        // Every --arch is little-endian
+        SectionWriter { data: EndianVec::new(RunTimeEndian::Little), relocs: Vec::new(), relocate_sections }
+    }
+}
+
+impl Writer for SectionWriter {
+    type Endian = RunTimeEndian;
+
+    fn endian(&self) -> RunTimeEndian {
+        self.data.endian()
+    }
+
+    fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> gimli::write::Result<()> {
+        self.data.write(bytes)
+    }
+
+    fn write_at(&mut self, offset: usize, bytes: &[u8]) -> gimli::write::Result<()> {
+        self.data.write_at(offset, bytes)
+    }
+
+    fn write_address(&mut self, address: Address, size: u8) -> gimli::write::Result<()> {
+        match address {
+            Address::Constant(val) => self.write_udata(val, size),
+            Address::Symbol { symbol, addend } => {
+                let offset = self.len() as u64;
+                self.relocs.push(Reloc { offset, size, target: RelocTarget::Function(symbol), addend });
+                self.write_udata(0, size)
+            }
+        }
+    }
+
+    fn write_offset(&mut self, val: usize, section: SectionId, size: u8) -> gimli::write::Result<()> {
+        let offset = self.len();
+        self.write_udata(0, size)?;
+        self.write_offset_at(offset, val, section, size)
+    }
+
+    fn write_offset_at(&mut self, offset: usize, val: usize, section: SectionId, size: u8) -> gimli::write::Result<()> {
+        if !self.relocate_sections {
+            return self.write_udata_at(offset, val as u64, size);
+        }
+        self.relocs.push(Reloc { offset: offset as u64, size, target: RelocTarget::Section(section), addend: val as i64 });
+        self.write_udata_at(offset, 0, size)
+    }
+}
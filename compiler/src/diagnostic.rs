@@ -0,0 +1,196 @@
+// src/diagnostic.rs - Errors and warnings reported to the user
+
+use std::collections::HashSet;
+use std::fmt;
+use std::io;
+
+use ariadne::{Color, Config, IndexType, Label, Report, ReportKind, Source};
+
+use crate::ast::Span;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl Severity {
+    fn label(self) -> &'static str {
+        match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub span: Option<Span>,
+    pub code: Option<&'static str>, // lint name for warnings, used by -A/-W
+}
+
+pub type NulaResult<T> = Result<T, Diagnostic>;
+
+impl Diagnostic {
+    pub fn error(message: impl Into<String>) -> Self {
+        Diagnostic { severity: Severity::Error, message: message.into(), span: None, code: None }
+    }
+
+    pub fn warning(code: &'static str, message: impl Into<String>) -> Self {
+        Diagnostic { severity: Severity::Warning, message: message.into(), span: None, code: Some(code) }
+    }
+
+    pub fn with_span(mut self, span: Span) -> Self {
+        self.span = Some(span);
+        self
+    }
+
+    // Attaches span unless the diagnostic already points somewhere more precise
+    pub fn or_span(self, span: Span) -> Self {
+        if self.span.is_some() { self } else { self.with_span(span) }
+    }
+
+    // Prints to stderr with the offending source underlined
+    pub fn eprint(&self, file: &str, source: &str, colored: bool) -> io::Result<()> {
+        let span = match self.span {
+            Some(span) => span,
+            None => {
+                eprintln!("{}", self);
+                return Ok(());
+            }
+        };
+        let (kind, color) = match self.severity {
+            Severity::Error => (ReportKind::Error, Color::Red),
+            Severity::Warning => (ReportKind::Warning, Color::Yellow),
+        };
+        // Errors at the end of the file have an empty span past the last character; point at
+        // the last character instead so there is something to underline
+        let range = if span.start < source.len() {
+            span.start..span.end.max(span.start + 1)
+        } else {
+            source.char_indices().last().map_or(0..0, |(i, c)| i..i + c.len_utf8())
+        };
+        let config = Config::default().with_index_type(IndexType::Byte).with_color(colored);
+        let mut report = Report::build(kind, (file, range.clone()))
+            .with_config(config)
+            .with_message(&self.message)
+            .with_label(Label::new((file, range)).with_message(&self.message).with_color(color));
+        if let Some(code) = self.code {
+            report = report.with_code(code);
+        }
+        report.finish().eprint((file, Source::from(source)))
+    }
+
+    // One-line JSON object for --message-format=json
+    pub fn to_json(&self, file: &str) -> String {
+        let span = match self.span {
+            Some(span) => format!(
+                "{{\"start\":{},\"end\":{},\"line\":{},\"column\":{}}}",
+                span.start, span.end, span.line, span.col
+            ),
+            None => "null".to_string(),
+        };
+        format!(
+            "{{\"severity\":\"{}\",\"message\":{},\"file\":{},\"span\":{},\"code\":{}}}",
+            self.severity.label(),
+            json_string(&self.message),
+            json_string(file),
+            span,
+            self.code.map_or("null".to_string(), json_string)
+        )
+    }
+}
+
+// Which warnings to report, from -A/-W/--deny-warnings
+#[derive(Debug, Default)]
+pub struct WarningConfig {
+    allowed: HashSet<String>,
+    pub deny_warnings: bool,
+}
+
+impl WarningConfig {
+    pub fn allow(&mut self, name: &str) {
+        self.allowed.insert(name.to_string());
+    }
+
+    pub fn warn(&mut self, name: &str) {
+        self.allowed.remove(name);
+    }
+
+    // Drops allowed warnings and, with --deny-warnings, turns the rest into errors
+    pub fn apply(&self, diagnostics: Vec<Diagnostic>) -> Vec<Diagnostic> {
+        diagnostics
+            .into_iter()
+            .filter(|d| d.severity != Severity::Warning || !d.code.is_some_and(|code| self.allowed.contains(code)))
+            .map(|mut d| {
+                if self.deny_warnings {
+                    d.severity = Severity::Error;
+                }
+                d
+            })
+            .collect()
+    }
+}
+
+pub fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.severity.label(), self.message)?;
+        if let Some(span) = self.span {
+            write!(f, "\n  --> {}:{}", span.line, span.col)?;
+        }
+        Ok(())
+    }
+}
+
+// SARIF 2.1.0 log with one result per diagnostic, for code scanning dashboards.
+// The severity labels double as SARIF result levels. `files` are the names spans point into.
+pub fn to_sarif(diagnostics: &[Diagnostic], files: &[&str]) -> String {
+    let results: Vec<String> = diagnostics
+        .iter()
+        .map(|d| {
+            let region = match d.span {
+                Some(span) => format!(
+                    ",\"region\":{{\"startLine\":{},\"startColumn\":{},\"byteOffset\":{},\"byteLength\":{}}}",
+                    span.line,
+                    span.col,
+                    span.start,
+                    span.end - span.start
+                ),
+                None => String::new(),
+            };
+            let rule = d.code.map_or(String::new(), |code| format!("\"ruleId\":{},", json_string(code)));
+            format!(
+                "{{{}\"level\":\"{}\",\"message\":{{\"text\":{}}},\"locations\":[{{\"physicalLocation\":{{\"artifactLocation\":{{\"uri\":{}}}{}}}}}]}}",
+                rule,
+                d.severity.label(),
+                json_string(&d.message),
+                json_string(files[d.span.map_or(0, |span| span.file)]),
+                region
+            )
+        })
+        .collect();
+    format!(
+        "{{\"version\":\"2.1.0\",\"$schema\":\"https://json.schemastore.org/sarif-2.1.0.json\",\"runs\":[{{\"tool\":{{\"driver\":{{\"name\":\"nula-compiler\",\"version\":\"{}\"}}}},\"columnKind\":\"unicodeCodePoints\",\"results\":[{}]}}]}}",
+        env!("CARGO_PKG_VERSION"),
+        results.join(",")
+    )
+}
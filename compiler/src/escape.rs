@@ -0,0 +1,142 @@
+// src/escape.rs - Escape analysis deciding where array literals and vec4 results live
+//
+// An array starts out on the stack of the function creating it, which is gone
+// once that function returns. One that may be used after that goes on the heap
+// instead: one that is returned, stored in an array that escapes, or handed
+// to a task or another thread. So does one holding strings or arrays, which
+// only heap arrays release when they go. Values escape through the locals
+// they are assigned to and through the parameters of the functions they are
+// passed to, so each function is gone over until no more of either are found.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::hir::{Alloc, Expr, ExprKind, Function, Program, Stmt, VarId};
+
+pub fn place_arrays(program: &mut Program) {
+    // Which parameters of each function escape from it
+    let mut params: HashMap<String, Vec<bool>> =
+        program.functions.iter().map(|function| (function.name.clone(), vec![false; function.params])).collect();
+    loop {
+        let mut changed = false;
+        for function in &mut program.functions {
+            let escaping = place_in_function(function, &params, false);
+            for (id, escapes) in params.get_mut(&function.name).unwrap().iter_mut().enumerate() {
+                if !*escapes && escaping.contains(&id) {
+                    *escapes = true;
+                    changed = true;
+                }
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+    // The top-level statements' frame lasts until the program exits
+    place_in_function(&mut program.main, &params, true);
+}
+
+// Moves the function's escaping arrays to the heap, returning its escaping locals
+fn place_in_function(function: &mut Function, params: &HashMap<String, Vec<bool>>, is_main: bool) -> HashSet<VarId> {
+    let mut escapes = Escapes { params, is_main, locals: HashSet::new(), changed: true };
+    while escapes.changed {
+        escapes.changed = false;
+        escapes.block(&mut function.body);
+    }
+    escapes.locals
+}
+
+struct Escapes<'a> {
+    params: &'a HashMap<String, Vec<bool>>,
+    is_main: bool,
+    locals: HashSet<VarId>,
+    changed: bool, // a local was found to escape in this pass over the body
+}
+
+impl Escapes<'_> {
+    fn block(&mut self, stmts: &mut [Stmt]) {
+        for stmt in stmts {
+            match stmt {
+                Stmt::Assign(id, value) => {
+                    self.visit(value);
+                    if self.locals.contains(id) {
+                        self.escape(value);
+                    }
+                }
+                Stmt::Expr(expr) => self.visit(expr),
+                Stmt::If(cond, then_body, else_body) => {
+                    self.visit(cond);
+                    self.block(then_body);
+                    self.block(else_body);
+                }
+                Stmt::While(cond, body) => {
+                    self.visit(cond);
+                    self.block(body);
+                }
+                Stmt::Return(value) => {
+                    self.visit(value);
+                    if !self.is_main {
+                        self.escape(value);
+                    }
+                }
+            }
+        }
+    }
+
+    // Looks for values that escape inside `expr`, through the arguments it passes
+    fn visit(&mut self, expr: &mut Expr) {
+        match &mut expr.kind {
+            ExprKind::Call(name, args) => {
+                for (arg, &escapes) in args.iter_mut().zip(&self.params[name.as_str()]) {
+                    if escapes {
+                        self.escape(arg);
+                    }
+                }
+            }
+            // Tasks run once awaited, maybe after the caller has returned, and
+            // nothing is known of what a function value does with its arguments
+            ExprKind::StartTask(_, args) | ExprKind::CallIndirect(_, args) => args.iter_mut().for_each(|arg| self.escape(arg)),
+            // The value goes to another thread
+            ExprKind::Builtin(name, args) if name == "spawn" || name == "send" => self.escape(&mut args[1]),
+            ExprKind::Array(elements, alloc) if !elements.is_empty() && elements.iter().all(|elem| elem.ty.is_pointer()) => {
+                *alloc = Alloc::Heap
+            }
+            _ => {}
+        }
+        match &mut expr.kind {
+            ExprKind::Array(elements, _) => elements.iter_mut().for_each(|elem| self.visit(elem)),
+            ExprKind::Index(array, index) => {
+                self.visit(array);
+                self.visit(index);
+            }
+            ExprKind::Binary(_, left, right) | ExprKind::Contains(left, _, right) | ExprKind::Vec4(_, left, right, _) => {
+                self.visit(left);
+                self.visit(right);
+            }
+            ExprKind::Call(_, args) | ExprKind::StartTask(_, args) | ExprKind::Builtin(_, args) => {
+                args.iter_mut().for_each(|arg| self.visit(arg))
+            }
+            ExprKind::CallIndirect(callee, args) => {
+                self.visit(callee);
+                args.iter_mut().for_each(|arg| self.visit(arg));
+            }
+            ExprKind::Await(task) => self.visit(task),
+            ExprKind::Number(_) | ExprKind::Str(_) | ExprKind::Local(_) | ExprKind::Function(_) => {}
+        }
+    }
+
+    // Marks the arrays `expr` may evaluate to as escaping
+    fn escape(&mut self, expr: &mut Expr) {
+        match &mut expr.kind {
+            ExprKind::Local(id) => self.changed |= self.locals.insert(*id),
+            ExprKind::Array(elements, alloc) => {
+                *alloc = Alloc::Heap;
+                elements.iter_mut().for_each(|elem| self.escape(elem));
+            }
+            // Its operands are only read
+            ExprKind::Vec4(_, _, _, alloc) => *alloc = Alloc::Heap,
+            // An element is reached through its array
+            ExprKind::Index(array, _) => self.escape(array),
+            _ => {}
+        }
+    }
+}
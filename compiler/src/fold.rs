@@ -0,0 +1,148 @@
+// src/fold.rs - Constant folding on HIR
+//
+// Computes operators whose operands are known at compile time and drops the
+// branches of ifs and whiles whose conditions are, so `2 * 3 + 1` becomes 7
+// and the body of `if 0 { ... }` is never generated.
+
+use crate::hir::{BinOp, Expr, ExprKind, Function, Program, Stmt};
+
+pub fn fold_constants(program: &mut Program) {
+    for function in &mut program.functions {
+        fold_function(function);
+    }
+    fold_function(&mut program.main);
+}
+
+fn fold_function(function: &mut Function) {
+    function.body = fold_block(std::mem::take(&mut function.body));
+}
+
+fn fold_block(stmts: Vec<Stmt>) -> Vec<Stmt> {
+    let mut out = Vec::new();
+    for stmt in stmts {
+        match stmt {
+            Stmt::Assign(id, mut value) => {
+                fold_expr(&mut value);
+                out.push(Stmt::Assign(id, value));
+            }
+            Stmt::Expr(mut expr) => {
+                fold_expr(&mut expr);
+                out.push(Stmt::Expr(expr));
+            }
+            Stmt::If(mut cond, then_body, else_body) => {
+                fold_expr(&mut cond);
+                // Locals belong to the whole function, so a taken branch can be inlined as is
+                match constant(&cond) {
+                    Some(c) if c != 0.0 => out.extend(fold_block(then_body)),
+                    Some(_) => out.extend(fold_block(else_body)),
+                    None => out.push(Stmt::If(cond, fold_block(then_body), fold_block(else_body))),
+                }
+            }
+            Stmt::While(mut cond, body) => {
+                fold_expr(&mut cond);
+                if constant(&cond) != Some(0.0) {
+                    out.push(Stmt::While(cond, fold_block(body)));
+                }
+            }
+            Stmt::Return(mut value) => {
+                fold_expr(&mut value);
+                out.push(Stmt::Return(value));
+            }
+        }
+    }
+    out
+}
+
+fn fold_expr(expr: &mut Expr) {
+    match &mut expr.kind {
+        ExprKind::Array(elements, _) => elements.iter_mut().for_each(fold_expr),
+        ExprKind::Index(array, index) | ExprKind::Vec4(_, array, index, _) => {
+            fold_expr(array);
+            fold_expr(index);
+        }
+        ExprKind::Contains(x, _, array) => {
+            fold_expr(x);
+            fold_expr(array);
+        }
+        ExprKind::Call(_, args) | ExprKind::StartTask(_, args) | ExprKind::Builtin(_, args) => {
+            args.iter_mut().for_each(fold_expr)
+        }
+        ExprKind::CallIndirect(callee, args) => {
+            fold_expr(callee);
+            args.iter_mut().for_each(fold_expr);
+        }
+        ExprKind::Await(task) => fold_expr(task),
+        ExprKind::Binary(op, left, right) => {
+            fold_expr(left);
+            fold_expr(right);
+            if let Some(folded) = fold_binary(*op, left, right) {
+                expr.kind = folded;
+            }
+        }
+        ExprKind::Number(_) | ExprKind::Str(_) | ExprKind::Local(_) | ExprKind::Function(_) => {}
+    }
+}
+
+fn fold_binary(op: BinOp, left: &Expr, right: &Expr) -> Option<ExprKind> {
+    if let (ExprKind::Str(l), ExprKind::Str(r)) = (&left.kind, &right.kind) {
+        return match op {
+            BinOp::Add => Some(ExprKind::Str(format!("{}{}", l, r))),
+            BinOp::Eq => Some(ExprKind::Number(f64::from(l == r))),
+            BinOp::Ne => Some(ExprKind::Number(f64::from(l != r))),
+            _ => None,
+        };
+    }
+    match (op, constant(left), constant(right)) {
+        (_, Some(l), Some(r)) => op.eval(l, r).map(ExprKind::Number),
+        // The right operand never runs when the left one decides the result
+        (BinOp::And, Some(0.0), None) => Some(ExprKind::Number(0.0)),
+        (BinOp::Or, Some(l), None) if l != 0.0 => Some(ExprKind::Number(1.0)),
+        _ => None,
+    }
+}
+
+fn constant(expr: &Expr) -> Option<f64> {
+    match expr.kind {
+        ExprKind::Number(n) => Some(n),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+
+    // --emit hir listing of the folded program
+    fn folded(code: &str) -> String {
+        let ast = Parser::new(code, 0).parse().expect("no syntax errors");
+        let mut program = crate::lower::lower(&ast).expect("lowers");
+        fold_constants(&mut program);
+        program.dump()
+    }
+
+    #[test]
+    fn operators_on_constants_are_computed() {
+        assert_eq!(folded("write 2 * 3 + 1"), "fn main()\n  Builtin write: number\n    Number 7: number\n");
+        assert_eq!(folded("write \"a\" + \"b\""), "fn main()\n  Builtin write: number\n    Str \"ab\": string\n");
+        assert_eq!(folded("write \"a\" != \"b\""), "fn main()\n  Builtin write: number\n    Number 1: number\n");
+        // Left to runtime, where it is an error
+        assert_eq!(folded("write 1 / 0"), "fn main()\n  Builtin write: number\n    Binary Div: number\n      Number 1: number\n      Number 0: number\n");
+    }
+
+    #[test]
+    fn and_or_stop_at_a_deciding_left_operand() {
+        assert_eq!(
+            folded("var x = 2\nwrite 0 && x\nwrite 1 || x"),
+            "fn main()\n  local x#0: number\n  Assign x#0\n    Number 2: number\n  Builtin write: number\n    Number 0: number\n  Builtin write: number\n    Number 1: number\n"
+        );
+    }
+
+    #[test]
+    fn branches_with_constant_conditions_are_dropped() {
+        assert_eq!(
+            folded("if 0 {\n  write 1\n} else {\n  write 2\n}\nwhile 1 - 1 {\n  write 3\n}"),
+            "fn main()\n  Builtin write: number\n    Number 2: number\n"
+        );
+    }
+}
@@ -0,0 +1,262 @@
+// src/hir.rs - Typed intermediate representation between the AST and codegen
+//
+// Built by lower.rs once sema has accepted the program. Names are bound to
+// locals and functions, for loops are plain while loops and every expression
+// carries its type, so codegen only has to pick instructions.
+
+use crate::ast::Span;
+
+// Strings, bytes and arrays are reference counted, see the runtime's nula_header
+#[derive(Debug, Clone, PartialEq)]
+pub enum Type {
+    Number,
+    String,                  // pointer to a string's bytes, with a NUL after them for C
+    Bytes,                   // pointer to binary data, laid out like a string
+    Array(Box<Type>, usize), // pointer to `len` 8-byte elements of the given type
+    Tuple(Vec<Type>),        // pointer to 8-byte elements of these types, like run's result
+    Handle,                  // thread, channel, mutex, task or atomic, as f64 bits
+    Function,                // function address, as f64 bits
+}
+
+impl Type {
+    // Strings, bytes and arrays are pointers in registers; everything else is an f64
+    pub fn is_pointer(&self) -> bool {
+        matches!(self, Type::String | Type::Bytes | Type::Array(..) | Type::Tuple(_))
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Type::Number => "number",
+            Type::String => "string",
+            Type::Bytes => "bytes",
+            Type::Array(..) | Type::Tuple(_) => "array",
+            Type::Handle => "handle",
+            Type::Function => "function",
+        }
+    }
+}
+
+pub type VarId = usize; // index into Function::locals
+
+pub struct Program {
+    pub functions: Vec<Function>, // every fn in the source, nested ones included
+    pub main: Function,           // top-level statements
+}
+
+pub struct Function {
+    pub name: String,
+    pub params: usize, // the first `params` locals hold the arguments
+    pub locals: Vec<Local>,
+    pub body: Vec<Stmt>,
+    pub is_async: bool,
+}
+
+pub struct Local {
+    pub name: String, // for messages and debug info; loop temporaries are named after their loop
+    pub ty: Type,
+    pub temporary: bool, // no name refers to it, so debuggers don't show it
+}
+
+pub enum Stmt {
+    Assign(VarId, Expr),
+    Expr(Expr),
+    If(Expr, Vec<Stmt>, Vec<Stmt>),
+    While(Expr, Vec<Stmt>),
+    Return(Expr),
+}
+
+pub struct Expr {
+    pub kind: ExprKind,
+    pub ty: Type,
+    pub span: Span,
+}
+
+pub enum ExprKind {
+    Number(f64),
+    Str(String),
+    Local(VarId),
+    Function(String), // address of a user function
+    Array(Vec<Expr>, Alloc),
+    Index(Box<Expr>, Box<Expr>),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+    Contains(Box<Expr>, usize, Box<Expr>), // x in arr, with the array's length
+    Call(String, Vec<Expr>),
+    StartTask(String, Vec<Expr>), // call of an async fn
+    CallIndirect(Box<Expr>, Vec<Expr>),
+    Builtin(String, Vec<Expr>),
+    Vec4(BinOp, Box<Expr>, Box<Expr>, Alloc), // vec4_add and co, element-wise on 4-element arrays
+    Await(Box<Expr>),
+}
+
+// Where an array literal's (or vec4 result's) elements live, decided by escape.rs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Alloc {
+    Stack, // in the frame of the function creating it
+    Heap,  // from the runtime, for arrays used after that function returns
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Pow,
+    Shl,
+    Shr,
+    Eq,
+    Ne,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+    And, // short-circuiting
+    Or,
+}
+
+impl BinOp {
+    pub fn from_symbol(op: &str) -> Option<BinOp> {
+        Some(match op {
+            "+" => BinOp::Add,
+            "-" => BinOp::Sub,
+            "*" => BinOp::Mul,
+            "/" => BinOp::Div,
+            "^" => BinOp::Pow,
+            "<<" => BinOp::Shl,
+            ">>" => BinOp::Shr,
+            "==" => BinOp::Eq,
+            "!=" => BinOp::Ne,
+            "<" => BinOp::Lt,
+            ">" => BinOp::Gt,
+            "<=" => BinOp::Le,
+            ">=" => BinOp::Ge,
+            "&&" => BinOp::And,
+            "||" => BinOp::Or,
+            _ => return None,
+        })
+    }
+
+    // Result of the operator on two known numbers, the same value the generated
+    // code computes, or None when that can only be left to runtime
+    pub fn eval(self, l: f64, r: f64) -> Option<f64> {
+        Some(match self {
+            BinOp::Add => l + r,
+            BinOp::Sub => l - r,
+            BinOp::Mul => l * r,
+            // Division by zero is an error at runtime
+            BinOp::Div if r == 0.0 => return None,
+            BinOp::Div => l / r,
+            BinOp::Pow => l.powf(r),
+            BinOp::Eq => f64::from(l == r),
+            BinOp::Ne => f64::from(l != r),
+            BinOp::Lt => f64::from(l < r),
+            BinOp::Gt => f64::from(l > r),
+            BinOp::Le => f64::from(l <= r),
+            BinOp::Ge => f64::from(l >= r),
+            BinOp::And => f64::from(l != 0.0 && r != 0.0),
+            BinOp::Or => f64::from(l != 0.0 || r != 0.0),
+            BinOp::Shl | BinOp::Shr => {
+                // `as` saturates like fcvt_to_sint_sat, and the shifts mask the
+                // amount to 6 bits like ishl and sshr
+                let (li, ri) = (l as i64, r as i64 as u32);
+                (if self == BinOp::Shl { li.wrapping_shl(ri) } else { li.wrapping_shr(ri) }) as f64
+            }
+        })
+    }
+}
+
+impl Program {
+    // Indented listing for --emit hir: each function with its locals, then its
+    // statements with the type of every expression
+    pub fn dump(&self) -> String {
+        let mut out = String::new();
+        for function in self.functions.iter().chain([&self.main]) {
+            function.dump(&mut out);
+        }
+        out
+    }
+}
+
+impl Function {
+    fn dump(&self, out: &mut String) {
+        let params: Vec<&str> = self.locals[..self.params].iter().map(|local| local.name.as_str()).collect();
+        let keyword = if self.is_async { "async fn" } else { "fn" };
+        out.push_str(&format!("{} {}({})\n", keyword, self.name, params.join(", ")));
+        for (id, local) in self.locals.iter().enumerate() {
+            dump_line(&format!("local {}#{}: {}", local.name, id, describe(&local.ty)), 1, out);
+        }
+        self.dump_block(&self.body, 1, out);
+    }
+
+    fn dump_block(&self, stmts: &[Stmt], depth: usize, out: &mut String) {
+        for stmt in stmts {
+            match stmt {
+                Stmt::Assign(id, value) => {
+                    dump_line(&format!("Assign {}#{}", self.locals[*id].name, id), depth, out);
+                    self.dump_expr(value, depth + 1, out);
+                }
+                Stmt::Expr(expr) => self.dump_expr(expr, depth, out),
+                Stmt::If(cond, then_body, else_body) => {
+                    dump_line("If", depth, out);
+                    self.dump_expr(cond, depth + 1, out);
+                    dump_line("then", depth + 1, out);
+                    self.dump_block(then_body, depth + 2, out);
+                    if !else_body.is_empty() {
+                        dump_line("else", depth + 1, out);
+                        self.dump_block(else_body, depth + 2, out);
+                    }
+                }
+                Stmt::While(cond, body) => {
+                    dump_line("While", depth, out);
+                    self.dump_expr(cond, depth + 1, out);
+                    dump_line("do", depth + 1, out);
+                    self.dump_block(body, depth + 2, out);
+                }
+                Stmt::Return(value) => {
+                    dump_line("Return", depth, out);
+                    self.dump_expr(value, depth + 1, out);
+                }
+            }
+        }
+    }
+
+    fn dump_expr(&self, expr: &Expr, depth: usize, out: &mut String) {
+        let (label, children): (String, Vec<&Expr>) = match &expr.kind {
+            ExprKind::Number(n) => (format!("Number {}", n), vec![]),
+            ExprKind::Str(s) => (format!("Str {:?}", s), vec![]),
+            ExprKind::Local(id) => (format!("Local {}#{}", self.locals[*id].name, id), vec![]),
+            ExprKind::Function(name) => (format!("Function {}", name), vec![]),
+            ExprKind::Array(elements, alloc) => (format!("Array ({})", if *alloc == Alloc::Heap { "heap" } else { "stack" }), elements.iter().collect()),
+            ExprKind::Index(array, index) => ("Index".to_string(), vec![&**array, &**index]),
+            ExprKind::Binary(op, left, right) => (format!("Binary {:?}", op), vec![&**left, &**right]),
+            ExprKind::Contains(x, len, array) => (format!("Contains (length {})", len), vec![&**x, &**array]),
+            ExprKind::Call(name, args) => (format!("Call {}", name), args.iter().collect()),
+            ExprKind::StartTask(name, args) => (format!("StartTask {}", name), args.iter().collect()),
+            ExprKind::CallIndirect(callee, args) => ("CallIndirect".to_string(), [&**callee].into_iter().chain(args).collect()),
+            ExprKind::Builtin(name, args) => (format!("Builtin {}", name), args.iter().collect()),
+            ExprKind::Vec4(op, a, b, alloc) => {
+                (format!("Vec4 {:?} ({})", op, if *alloc == Alloc::Heap { "heap" } else { "stack" }), vec![&**a, &**b])
+            }
+            ExprKind::Await(task) => ("Await".to_string(), vec![&**task]),
+        };
+        dump_line(&format!("{}: {}", label, describe(&expr.ty)), depth, out);
+        for child in children {
+            self.dump_expr(child, depth + 1, out);
+        }
+    }
+}
+
+fn dump_line(text: &str, depth: usize, out: &mut String) {
+    out.push_str(&"  ".repeat(depth));
+    out.push_str(text);
+    out.push('\n');
+}
+
+// Type name with array element types and lengths, like [string; 3]
+pub fn describe(ty: &Type) -> String {
+    match ty {
+        Type::Array(elem, len) => format!("[{}; {}]", describe(elem), len),
+        Type::Tuple(elems) => format!("[{}]", elems.iter().map(describe).collect::<Vec<_>>().join(", ")),
+        _ => ty.name().to_string(),
+    }
+}
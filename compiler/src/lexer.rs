@@ -0,0 +1,253 @@
+// src/lexer.rs - Turns source text into tokens for the parser
+
+use std::iter::Peekable;
+use std::str::Chars;
+
+use unicode_ident::{is_xid_continue, is_xid_start};
+
+use crate::ast::Span;
+use crate::diagnostic::Diagnostic;
+
+// Text in tokens is borrowed from the source, so they're cheap to copy around
+#[derive(Debug, Clone, Copy)]
+pub enum Token<'a> {
+    Ident(&'a str),
+    Number(f64),
+    StringLit(&'a str),
+    Operator(&'a str),
+    Keyword(&'a str),
+    Symbol(&'a str),
+    Directive(&'a str), // @name
+    Eof,
+}
+
+const KEYWORDS: [&str; 11] = ["if", "else", "while", "for", "fn", "var", "write", "async", "await", "in", "return"];
+
+// Words that turn `@word` into a directive instead of a comment
+const DIRECTIVES: [&str; 2] = ["link", "export"];
+
+// Operators made of two characters, matched before their one-character prefixes
+const TWO_CHAR_OPERATORS: [&str; 11] = ["==", "!=", "<=", ">=", "&&", "||", "..", "+=", "->", "<<", ">>"];
+
+// Character iterator that keeps track of where it is in the source
+#[derive(Clone)]
+struct Cursor<'a> {
+    chars: Peekable<Chars<'a>>,
+    offset: usize,
+    line: usize,
+    col: usize,
+    file: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(code: &'a str, file: usize) -> Self {
+        Cursor { chars: code.chars().peekable(), offset: 0, line: 1, col: 1, file }
+    }
+
+    fn peek(&mut self) -> Option<&char> {
+        self.chars.peek()
+    }
+
+    // The character after the next one
+    fn peek_second(&self) -> Option<char> {
+        let mut ahead = self.chars.clone();
+        ahead.next();
+        ahead.next()
+    }
+
+    // Empty span at the current position
+    fn here(&self) -> Span {
+        Span { start: self.offset, end: self.offset, line: self.line, col: self.col, file: self.file }
+    }
+}
+
+impl Iterator for Cursor<'_> {
+    type Item = char;
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.chars.next()?;
+        self.offset += c.len_utf8();
+        if c == '\n' {
+            self.line += 1;
+            self.col = 1;
+        } else {
+            self.col += 1;
+        }
+        Some(c)
+    }
+}
+
+// Splits the source of a file into tokens as they're asked for, ending with
+// Eof. Errors found on the way are kept in `errors` and the broken part is
+// skipped.
+pub struct Lexer<'a> {
+    code: &'a str,
+    chars: Cursor<'a>,
+    done: bool, // Eof has been returned
+    pub errors: Vec<Diagnostic>,
+}
+
+impl<'a> Lexer<'a> {
+    // `file` is the index spans point into, see Span
+    pub fn new(code: &'a str, file: usize) -> Self {
+        Lexer { code, chars: Cursor::new(code, file), done: false, errors: Vec::new() }
+    }
+}
+
+impl<'a> Iterator for Lexer<'a> {
+    type Item = (Token<'a>, Span);
+
+    fn next(&mut self) -> Option<(Token<'a>, Span)> {
+        let code = self.code;
+        let chars = &mut self.chars;
+        while chars.peek().is_some() {
+            let ch = *chars.peek().unwrap();
+            let start = chars.here();
+            match ch {
+                ' ' | '\t' | '\n' | '\r' => { chars.next(); continue; }
+                // Identifiers follow Unicode's XID rules (plus a leading _), so `café` and `数値` work
+                c if c == '_' || is_xid_start(c) => {
+                    while chars.peek().is_some_and(|&c| is_xid_continue(c)) {
+                        chars.next();
+                    }
+                    let id = &code[start.start..chars.offset];
+                    let tok = if KEYWORDS.contains(&id) { Token::Keyword(id) } else { Token::Ident(id) };
+                    return Some((tok, start.to(chars.here())));
+                }
+                // A dot only belongs to a number when a digit follows it, so `0..3` is 0, .., 3
+                '0'..='9' | '.' if ch != '.' || chars.peek_second().is_some_and(|c| c.is_ascii_digit()) => {
+                    let mut has_dot = false;
+                    while let Some(&c) = chars.peek() {
+                        if c.is_ascii_digit() {
+                            chars.next();
+                        } else if c == '.' && !has_dot && chars.peek_second().is_some_and(|c| c.is_ascii_digit()) {
+                            has_dot = true;
+                            chars.next();
+                        } else {
+                            break;
+                        }
+                    }
+                    let number = &code[start.start..chars.offset];
+                    return Some((Token::Number(number.parse().unwrap_or(0.0)), start.to(chars.here())));
+                }
+                '/' if chars.peek_second() == Some('*') => {
+                    // Block comment: /* ... */, which may contain other block comments
+                    chars.next();
+                    chars.next();
+                    let mut depth = 1;
+                    while depth > 0 {
+                        match chars.next() {
+                            Some('/') if chars.peek() == Some(&'*') => {
+                                chars.next();
+                                depth += 1;
+                            }
+                            Some('*') if chars.peek() == Some(&'/') => {
+                                chars.next();
+                                depth -= 1;
+                            }
+                            Some(_) => {}
+                            None => {
+                                let opening = Span { end: start.start + 2, ..start };
+                                self.errors.push(Diagnostic::error("Unterminated block comment".to_string()).with_span(opening));
+                                break;
+                            }
+                        }
+                    }
+                }
+                '"' => {
+                    chars.next();
+                    while chars.peek().is_some_and(|&c| c != '"') {
+                        chars.next();
+                    }
+                    let text = &code[start.start + 1..chars.offset];
+                    if chars.next().is_none() {
+                        // Kept as a string up to the end of the file, which is as good a guess as any
+                        let opening = Span { end: start.start + 1, ..start };
+                        self.errors.push(Diagnostic::error("Unterminated string".to_string()).with_span(opening));
+                    }
+                    return Some((Token::StringLit(text), start.to(chars.here())));
+                }
+                '+' | '-' | '*' | '/' | '^' | '=' | '<' | '>' | '!' | '&' | '|' | '.' | '[' | ']' | '(' | ')' | '{' | '}' | ':' | ';' | ',' => {
+                    let c = chars.next().unwrap();
+                    // Every operator and symbol character is ASCII, one byte long
+                    if code.get(start.start..start.start + 2).is_some_and(|pair| TWO_CHAR_OPERATORS.contains(&pair)) {
+                        chars.next();
+                    }
+                    let op = &code[start.start..chars.offset];
+                    let tok = if "+-*/^=<>!&|.".contains(c) { Token::Operator(op) } else { Token::Symbol(op) };
+                    return Some((tok, start.to(chars.here())));
+                }
+                '@' => {
+                    chars.next();
+                    let word_len = code[chars.offset..].find(|c: char| !(c.is_alphanumeric() || c == '_')).unwrap_or(code.len() - chars.offset);
+                    let word = &code[chars.offset..chars.offset + word_len];
+                    if DIRECTIVES.contains(&word) {
+                        for _ in 0..word.chars().count() { chars.next(); }
+                        return Some((Token::Directive(word), start.to(chars.here())));
+                    }
+                    // Single line comment
+                    while let Some(&c) = chars.peek() {
+                        if c == '\n' { break; }
+                        chars.next();
+                    }
+                }
+                _ => {
+                    // Reported and skipped
+                    chars.next();
+                    self.errors.push(Diagnostic::error(format!("Unexpected character `{}`", ch)).with_span(start.to(chars.here())));
+                }
+            }
+        }
+        if self.done {
+            return None;
+        }
+        self.done = true;
+        Some((Token::Eof, chars.here()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Tokens as their Debug text, with the lexer's error messages
+    fn lex(code: &str) -> (Vec<String>, Vec<String>) {
+        let mut lexer = Lexer::new(code, 0);
+        let tokens = lexer.by_ref().map(|(token, _)| format!("{:?}", token)).collect();
+        (tokens, lexer.errors.iter().map(|err| err.message.clone()).collect())
+    }
+
+    #[test]
+    fn unicode_identifiers() {
+        let (tokens, errors) = lex("var café = 数値 + _x1");
+        assert_eq!(tokens, ["Keyword(\"var\")", "Ident(\"café\")", "Operator(\"=\")", "Ident(\"数値\")", "Operator(\"+\")", "Ident(\"_x1\")", "Eof"]);
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn spans_are_bytes_and_columns_are_chars() {
+        let spans: Vec<Span> = Lexer::new("café = 1", 0).map(|(_, span)| span).collect();
+        assert_eq!((spans[0].start, spans[0].end), (0, 5));
+        assert_eq!((spans[1].start, spans[1].col), (6, 6));
+    }
+
+    #[test]
+    fn dots_only_belong_to_numbers_before_a_digit() {
+        assert_eq!(lex("0..3").0, ["Number(0.0)", "Operator(\"..\")", "Number(3.0)", "Eof"]);
+        assert_eq!(lex("1.5 .5").0, ["Number(1.5)", "Number(0.5)", "Eof"]);
+    }
+
+    #[test]
+    fn comments_are_skipped() {
+        assert_eq!(lex("/* a /* nested */ b */ x @ rest of the line\ny").0, ["Ident(\"x\")", "Ident(\"y\")", "Eof"]);
+        assert_eq!(lex("@link \"m\"").0, ["Directive(\"link\")", "StringLit(\"m\")", "Eof"]);
+    }
+
+    #[test]
+    fn errors_are_kept_and_lexing_goes_on() {
+        assert_eq!(lex("/* open").1, ["Unterminated block comment"]);
+        let (tokens, errors) = lex("a $ b \"open");
+        assert_eq!(tokens, ["Ident(\"a\")", "Ident(\"b\")", "StringLit(\"open\")", "Eof"]);
+        assert_eq!(errors, ["Unexpected character `$`", "Unterminated string"]);
+    }
+}
@@ -0,0 +1,659 @@
+// src/lower.rs - Lowers the checked AST to HIR (see hir.rs)
+
+use std::collections::HashMap;
+
+use crate::ast::{Ast, Node, Span};
+use crate::diagnostic::{Diagnostic, NulaResult};
+use crate::hir::{describe, Alloc, BinOp, Expr, ExprKind, Function, Local, Program, Stmt, Type, VarId};
+
+struct Lowerer {
+    signatures: HashMap<String, Signature>, // user functions
+    params: HashMap<String, Vec<Type>>,     // their parameters' types, from the calls of the last pass
+    passed: HashMap<String, Vec<Option<Passed>>>, // what the calls lowered in this pass give them
+    returns: HashMap<String, Type>,         // their result types, from the returns of the last pass
+    returned: HashMap<String, Passed>,      // what the returns lowered in this pass give back
+    functions: Vec<Function>,
+    // State of the function being lowered
+    locals: Vec<Local>,
+    scopes: Vec<HashMap<String, VarId>>, // innermost block last
+    function: Option<String>,            // None for the top-level statements
+}
+
+struct Signature {
+    is_async: bool,
+    params: Vec<String>,
+}
+
+// The types of the values calls give a parameter, or returns give back: the
+// one it takes, and the first giving another, if any. Numbers, handles and
+// functions are all f64s, so those only conflict with strings and arrays.
+struct Passed {
+    ty: Type,
+    span: Span,
+    conflict: Option<(Type, Span)>,
+}
+
+// Expects a program sema has accepted, so names resolve and calls have the
+// right number of arguments; what is left to report is what only types tell.
+pub fn lower(ast: &[Node]) -> NulaResult<Program> {
+    let mut signatures = HashMap::new();
+    collect_functions(ast, &mut signatures);
+    // A parameter takes the type of the arguments calls give it, and a call
+    // the type of what its function returns. Those can be other parameters and
+    // calls, so the program is lowered again with the types found until they
+    // stop changing; a parameter no call gives a value, or a function that
+    // returns nothing, is a number.
+    let (mut params, mut returns) = (HashMap::new(), HashMap::new());
+    let mut passes = 0;
+    loop {
+        let mut lowerer = Lowerer {
+            signatures,
+            params,
+            passed: HashMap::new(),
+            returns,
+            returned: HashMap::new(),
+            functions: Vec::new(),
+            locals: Vec::new(),
+            scopes: vec![HashMap::new()],
+            function: None,
+        };
+        let program = lowerer.program(ast);
+        let inferred = lowerer.inferred_types();
+        passes += 1;
+        if inferred == (lowerer.params.clone(), lowerer.returns.clone()) || passes > 2 * lowerer.signatures.len() {
+            lowerer.check_passed(&inferred)?;
+            return program;
+        }
+        (signatures, (params, returns)) = (lowerer.signatures, inferred);
+    }
+}
+
+// Replaces the top-level statements with a run of every test_ function, for
+// `nula test`. Tests take no arguments and fail through assert.
+pub fn test_harness(program: &mut Program) {
+    let span = Span::default();
+    let mut body = Vec::new();
+    for function in &program.functions {
+        if function.name.starts_with("test_") && function.params == 0 && !function.is_async {
+            let test = Expr { kind: ExprKind::Function(function.name.clone()), ty: Type::Function, span };
+            let name = Expr { kind: ExprKind::Str(function.name.clone()), ty: Type::String, span };
+            body.push(Stmt::Expr(Expr { kind: ExprKind::Builtin("run_test".to_string(), vec![test, name]), ty: Type::Number, span }));
+        }
+    }
+    body.push(Stmt::Expr(Expr { kind: ExprKind::Builtin("test_summary".to_string(), Vec::new()), ty: Type::Number, span }));
+    program.main = Function { name: "main".to_string(), params: 0, locals: Vec::new(), body, is_async: false };
+}
+
+// Has the program start by turning on the runtime's cycle collector, for --gc
+pub fn enable_gc(program: &mut Program) {
+    let enable = Expr { kind: ExprKind::Builtin("gc_enable".to_string(), Vec::new()), ty: Type::Number, span: Span::default() };
+    program.main.body.insert(0, Stmt::Expr(enable));
+}
+
+// Every fn is callable from anywhere, including ones defined inside a block
+fn collect_functions(stmts: &[Node], signatures: &mut HashMap<String, Signature>) {
+    for node in stmts {
+        match &node.ast {
+            Ast::FuncDef(name, params, body) | Ast::AsyncFuncDef(name, params, body) => {
+                let is_async = matches!(node.ast, Ast::AsyncFuncDef(..));
+                signatures.insert(name.clone(), Signature { is_async, params: params.clone() });
+                collect_functions(body, signatures);
+            }
+            Ast::If(_, then_body, else_body) => {
+                collect_functions(then_body, signatures);
+                collect_functions(else_body.as_deref().unwrap_or_default(), signatures);
+            }
+            Ast::While(_, body) | Ast::For(_, _, _, body) | Ast::ForEach(_, _, _, body) => collect_functions(body, signatures),
+            _ => {}
+        }
+    }
+}
+
+// Result type of a builtin (see CodeGen::gen_builtin), or None for user functions
+fn builtin_type(name: &str) -> Option<Type> {
+    Some(match name {
+        "spawn" | "channel" | "mutex" | "atomic" => Type::Handle,
+        "bytes" | "slice" | "read_file" => Type::Bytes,
+        "format_time" | "sha256" | "md5" | "base64_encode" | "base64_decode" | "hex_encode" | "hex_decode"
+        | "bytes_to_string" => Type::String,
+        // [stdout, stderr, exit status]
+        "run" => Type::Tuple(vec![Type::String, Type::String, Type::Number]),
+        "write" | "join" | "send" | "recv" | "lock" | "unlock" | "atomic_add" | "atomic_load" | "atomic_store"
+        | "now" | "parse_time" | "fnv" | "bytes_len" | "byte_at" | "write_file" | "asm" | "vec4_dot" | "assert" | "exit" => {
+            Type::Number
+        }
+        _ => return None,
+    })
+}
+
+// Type names for messages, like "a string", "bytes" or "a [number; 3]"
+fn with_article(ty: &Type) -> String {
+    match ty {
+        Type::Bytes => describe(ty),
+        _ => format!("a {}", describe(ty)),
+    }
+}
+
+// Checks an argument of a builtin that codegen reads as one of `types`
+fn check_arg(name: &str, arg: &Expr, types: &[Type]) -> NulaResult<()> {
+    if types.contains(&arg.ty) {
+        return Ok(());
+    }
+    let expected = types.iter().map(with_article).collect::<Vec<_>>().join(" or ");
+    let message = format!("`{}` takes {}, not {}", name, expected, with_article(&arg.ty));
+    Err(Diagnostic::error(message).with_span(arg.span))
+}
+
+// The vec4 builtins work on two numbers at a time, so their operands need
+// exactly four
+fn check_vec4(name: &str, args: &[Expr]) -> NulaResult<()> {
+    let vec4 = Type::Array(Box::new(Type::Number), 4);
+    match args.iter().find(|arg| arg.ty != vec4) {
+        Some(arg) => {
+            let message = format!("`{}` takes {}, not {}", name, with_article(&vec4), with_article(&arg.ty));
+            Err(Diagnostic::error(message).with_span(arg.span))
+        }
+        None => Ok(()),
+    }
+}
+
+// Type of the elements of an array literal, which all need one. Numbers,
+// handles and functions are all f64s, so a mix of those is numbers.
+fn array_element_type(elements: &[Expr]) -> NulaResult<Type> {
+    let Some(first) = elements.first() else { return Ok(Type::Number) };
+    for element in &elements[1..] {
+        if element.ty == first.ty {
+            continue;
+        }
+        if first.ty.is_pointer() || element.ty.is_pointer() {
+            let message = format!(
+                "Elements of an array need one type, but the first is {} and this one {}",
+                with_article(&first.ty),
+                with_article(&element.ty)
+            );
+            return Err(Diagnostic::error(message).with_span(element.span));
+        }
+        return Ok(Type::Number);
+    }
+    Ok(first.ty.clone())
+}
+
+// Type of `left op right`. Operators work on numbers, except that `+` of two
+// strings concatenates them and `==` and `!=` compare two strings' or bytes'
+// bytes.
+fn binary_type(symbol: &str, op: BinOp, left: &Expr, right: &Expr) -> NulaResult<Type> {
+    let text = matches!(left.ty, Type::String | Type::Bytes) && left.ty == right.ty;
+    match op {
+        BinOp::Add if left.ty == Type::String && right.ty == Type::String => Ok(Type::String),
+        BinOp::Eq | BinOp::Ne if text => Ok(Type::Number),
+        _ => match [left, right].into_iter().find(|operand| operand.ty.is_pointer()) {
+            Some(operand) => {
+                let message = format!("`{}` can't be used on {}", symbol, with_article(&operand.ty));
+                Err(Diagnostic::error(message).with_span(operand.span))
+            }
+            None => Ok(Type::Number),
+        },
+    }
+}
+
+// Operator of the element-wise vec4 builtins, which get an ExprKind of their own
+// so escape.rs can place their result like an array literal's
+fn vec4_op(name: &str) -> Option<BinOp> {
+    Some(match name {
+        "vec4_add" => BinOp::Add,
+        "vec4_sub" => BinOp::Sub,
+        "vec4_mul" => BinOp::Mul,
+        "vec4_div" => BinOp::Div,
+        _ => return None,
+    })
+}
+
+impl Lowerer {
+    fn program(&mut self, ast: &[Node]) -> NulaResult<Program> {
+        let mut body = Vec::new();
+        // Keeps going after an error, which may only be one until the
+        // parameter types are known, to see the calls after it
+        let mut error = None;
+        for node in ast {
+            if let Err(err) = self.stmt(node, &mut body) {
+                error.get_or_insert(err);
+            }
+        }
+        if let Some(err) = error {
+            return Err(err);
+        }
+        // The program exits with the value of a number it ends on, like `3` or
+        // `check()`, rather than a builtin whose result is a handle or a leftover
+        if let Some(Stmt::Expr(expr)) = body.last() {
+            if expr.ty == Type::Number && !matches!(expr.kind, ExprKind::Builtin(..)) {
+                let Some(Stmt::Expr(expr)) = body.pop() else { unreachable!() };
+                body.push(Stmt::Return(expr));
+            }
+        }
+        let main = Function { name: "main".to_string(), params: 0, locals: std::mem::take(&mut self.locals), body, is_async: false };
+        Ok(Program { functions: std::mem::take(&mut self.functions), main })
+    }
+
+    // Notes the types of the arguments a call gives a user function
+    fn pass(&mut self, name: &str, args: &[Expr]) {
+        let count = self.signatures[name].params.len();
+        let passed = self.passed.entry(name.to_string()).or_insert_with(|| (0..count).map(|_| None).collect());
+        for (param, arg) in passed.iter_mut().zip(args) {
+            match param {
+                Some(param) => param.add(arg),
+                None => *param = Some(Passed::new(arg)),
+            }
+        }
+    }
+
+    // Parameter and result types for the next pass, from the calls and
+    // returns of this one
+    fn inferred_types(&self) -> (HashMap<String, Vec<Type>>, HashMap<String, Type>) {
+        let params = self
+            .passed
+            .iter()
+            .map(|(name, passed)| (name.clone(), passed.iter().map(|param| param.as_ref().map_or(Type::Number, |param| param.ty.clone())).collect()))
+            .collect();
+        let returns = self.returned.iter().map(|(name, returned)| (name.clone(), returned.ty.clone())).collect();
+        (params, returns)
+    }
+
+    // Reports the first call giving a parameter a value of another type than
+    // the rest, or return giving back one, once the types have settled
+    fn check_passed(&self, inferred: &(HashMap<String, Vec<Type>>, HashMap<String, Type>)) -> NulaResult<()> {
+        let params = self.passed.iter().flat_map(|(name, passed)| {
+            passed.iter().enumerate().filter_map(move |(i, param)| {
+                let param = param.as_ref()?;
+                let (other, other_span) = param.conflict.as_ref()?;
+                let message = format!(
+                    "`{}` of `{}` is given {} on line {}, so it can't also be given {}",
+                    self.signatures[name].params[i],
+                    name,
+                    with_article(&param.ty),
+                    param.span.line,
+                    with_article(other)
+                );
+                Some((message, *other_span))
+            })
+        });
+        let returns = self.returned.iter().filter_map(|(name, returned)| {
+            let (other, other_span) = returned.conflict.as_ref()?;
+            let message = format!(
+                "`{}` returns {} on line {}, so it can't also return {}",
+                name,
+                with_article(&returned.ty),
+                returned.span.line,
+                with_article(other)
+            );
+            Some((message, *other_span))
+        });
+        if let Some((message, span)) = params.chain(returns).min_by_key(|(_, span)| (span.file, span.start)) {
+            return Err(Diagnostic::error(message).with_span(span));
+        }
+        let (params, returns) = inferred;
+        if *params != self.params {
+            let name = params.keys().find(|name| params.get(*name) != self.params.get(*name)).expect("some types differ");
+            return Err(Diagnostic::error(format!("The types of the parameters of `{}` can't be worked out from its calls", name)));
+        }
+        if *returns != self.returns {
+            let name = returns.keys().find(|name| returns.get(*name) != self.returns.get(*name)).expect("some types differ");
+            return Err(Diagnostic::error(format!("The type of what `{}` returns can't be worked out", name)));
+        }
+        Ok(())
+    }
+
+    fn block(&mut self, stmts: &[Node]) -> NulaResult<Vec<Stmt>> {
+        self.scopes.push(HashMap::new());
+        let mut out = Vec::new();
+        for stmt in stmts {
+            self.stmt(stmt, &mut out)?;
+        }
+        self.scopes.pop();
+        Ok(out)
+    }
+
+    fn stmt(&mut self, node: &Node, out: &mut Vec<Stmt>) -> NulaResult<()> {
+        match &node.ast {
+            Ast::VarDecl(name, value) => {
+                let value = self.expr(value)?;
+                let id = self.declare(name, value.ty.clone());
+                out.push(Stmt::Assign(id, value));
+            }
+            Ast::Assign(name, value) => {
+                let value = self.expr(value)?;
+                let id = match self.lookup(name) {
+                    Some(id) => {
+                        self.retype(id, &value.ty).map_err(|err| err.with_span(node.span))?;
+                        id
+                    }
+                    None => self.declare(name, value.ty.clone()),
+                };
+                out.push(Stmt::Assign(id, value));
+            }
+            Ast::If(cond, then_body, else_body) => {
+                let cond = self.expr(cond)?;
+                let then_body = self.block(then_body)?;
+                let else_body = self.block(else_body.as_deref().unwrap_or_default())?;
+                out.push(Stmt::If(cond, then_body, else_body));
+            }
+            Ast::While(cond, body) => {
+                let cond = self.expr(cond)?;
+                out.push(Stmt::While(cond, self.block(body)?));
+            }
+            Ast::For(var, from, to, body) => {
+                // var = from; end = to; while var < end { body; var = var + 1 }
+                let from = self.expr(from)?;
+                let to = self.expr(to)?;
+                self.scopes.push(HashMap::new());
+                let end = self.temporary(var, Type::Number);
+                let counter = self.declare(var, Type::Number);
+                out.push(Stmt::Assign(counter, from));
+                out.push(Stmt::Assign(end, to));
+                let mut body = self.block(body)?;
+                body.push(increment(counter, node.span));
+                let cond = number_op(BinOp::Lt, local(counter, Type::Number, node.span), local(end, Type::Number, node.span));
+                out.push(Stmt::While(cond, body));
+                self.scopes.pop();
+            }
+            Ast::ForEach(index, elem, array, body) => {
+                // arr = array; i = 0; while i < len { elem = arr[i]; body; i = i + 1 }
+                let array = self.expr(array)?;
+                let len = self.array_len(&array)?;
+                let elem_ty = self.element_type(&array, None)?;
+                let array_ty = array.ty.clone();
+                self.scopes.push(HashMap::new());
+                let arr = self.temporary(elem, array_ty.clone());
+                out.push(Stmt::Assign(arr, array));
+                let counter = match index {
+                    Some(index) => self.declare(index, Type::Number),
+                    None => self.temporary(elem, Type::Number),
+                };
+                out.push(Stmt::Assign(counter, number(0.0, node.span)));
+                let elem = self.declare(elem, elem_ty.clone());
+                let load = Expr {
+                    kind: ExprKind::Index(Box::new(local(arr, array_ty, node.span)), Box::new(local(counter, Type::Number, node.span))),
+                    ty: elem_ty,
+                    span: node.span,
+                };
+                let mut inner = vec![Stmt::Assign(elem, load)];
+                inner.extend(self.block(body)?);
+                inner.push(increment(counter, node.span));
+                let cond = number_op(BinOp::Lt, local(counter, Type::Number, node.span), number(len as f64, node.span));
+                out.push(Stmt::While(cond, inner));
+                self.scopes.pop();
+            }
+            Ast::FuncDef(name, params, body) | Ast::AsyncFuncDef(name, params, body) => {
+                let function = self.function(name, params, body, matches!(node.ast, Ast::AsyncFuncDef(..)))?;
+                self.functions.push(function);
+            }
+            Ast::Return(value) => {
+                let value = self.expr(value)?;
+                match &self.function {
+                    // Outside a function it ends the program, with the value as its exit code
+                    None if value.ty != Type::Number => {
+                        return Err(Diagnostic::error("The program's exit code must be a number").with_span(value.span));
+                    }
+                    None => {}
+                    // Awaiting a task gives a number, as which a string or array
+                    // couldn't be used at all
+                    Some(name) if self.signatures[name].is_async && value.ty.is_pointer() => {
+                        let message = "Async functions can return numbers but not strings or arrays yet";
+                        return Err(Diagnostic::error(message).with_span(value.span));
+                    }
+                    Some(name) if self.signatures[name].is_async => {}
+                    Some(name) => match self.returned.get_mut(name) {
+                        Some(returned) => returned.add(&value),
+                        None => {
+                            self.returned.insert(name.clone(), Passed::new(&value));
+                        }
+                    },
+                }
+                out.push(Stmt::Return(value));
+            }
+            Ast::Link(_) | Ast::Export(_) => {} // Handled by the driver
+            _ => out.push(Stmt::Expr(self.expr(node)?)),
+        }
+        Ok(())
+    }
+
+    // Function bodies only see their own parameters and locals
+    fn function(&mut self, name: &str, params: &[String], body: &[Node], is_async: bool) -> NulaResult<Function> {
+        let outer_locals = std::mem::take(&mut self.locals);
+        let outer_scopes = std::mem::replace(&mut self.scopes, vec![HashMap::new()]);
+        let outer_function = self.function.replace(name.to_string());
+        for (i, param) in params.iter().enumerate() {
+            let ty = self.params.get(name).and_then(|types| types.get(i)).cloned().unwrap_or(Type::Number);
+            self.declare(param, ty);
+        }
+        let body = self.block(body);
+        let locals = std::mem::replace(&mut self.locals, outer_locals);
+        self.scopes = outer_scopes;
+        self.function = outer_function;
+        Ok(Function { name: name.to_string(), params: params.len(), locals, body: body?, is_async })
+    }
+
+    fn expr(&mut self, node: &Node) -> NulaResult<Expr> {
+        let (kind, ty) = self.expr_kind(&node.ast).map_err(|err| err.or_span(node.span))?;
+        Ok(Expr { kind, ty, span: node.span })
+    }
+
+    fn expr_kind(&mut self, ast: &Ast) -> NulaResult<(ExprKind, Type)> {
+        Ok(match ast {
+            Ast::Literal(n) => (ExprKind::Number(*n), Type::Number),
+            Ast::StrLit(s) => (ExprKind::Str(s.clone()), Type::String),
+            Ast::Var(name) => match self.lookup(name) {
+                Some(id) => (ExprKind::Local(id), self.locals[id].ty.clone()),
+                None if self.signatures.contains_key(name) => (ExprKind::Function(name.clone()), Type::Function),
+                None => return Err(Diagnostic::error(format!("Undefined variable `{}`", name))),
+            },
+            Ast::Array(elements) => {
+                let elements = elements.iter().map(|e| self.expr(e)).collect::<NulaResult<Vec<_>>>()?;
+                let elem_ty = array_element_type(&elements)?;
+                let len = elements.len();
+                (ExprKind::Array(elements, Alloc::Stack), Type::Array(Box::new(elem_ty), len))
+            }
+            Ast::Index(array, index) => {
+                let array = self.expr(array)?;
+                let index = self.expr(index)?;
+                let ty = self.element_type(&array, Some(&index))?;
+                (ExprKind::Index(Box::new(array), Box::new(index)), ty)
+            }
+            Ast::BinOp(op, left, right) if op == "in" => {
+                let x = self.expr(left)?;
+                let array = self.expr(right)?;
+                let len = self.array_len(&array)?;
+                (ExprKind::Contains(Box::new(x), len, Box::new(array)), Type::Number)
+            }
+            Ast::BinOp(symbol, left, right) => {
+                let op = BinOp::from_symbol(symbol).ok_or_else(|| Diagnostic::error(format!("Unknown operator {}", symbol)))?;
+                let (left, right) = (self.expr(left)?, self.expr(right)?);
+                let ty = binary_type(symbol, op, &left, &right)?;
+                (ExprKind::Binary(op, Box::new(left), Box::new(right)), ty)
+            }
+            Ast::Await(task) => (ExprKind::Await(Box::new(self.expr(task)?)), Type::Number),
+            Ast::Call(callee, args) => {
+                let callee = self.expr(callee)?;
+                (ExprKind::CallIndirect(Box::new(callee), self.args(args)?), Type::Number)
+            }
+            Ast::FuncCall(name, args) => {
+                let args = self.args(args)?;
+                if let Some(op) = vec4_op(name) {
+                    check_vec4(name, &args)?;
+                    let Ok([a, b]) = <[Expr; 2]>::try_from(args) else { unreachable!("sema checks the number of arguments") };
+                    (ExprKind::Vec4(op, Box::new(a), Box::new(b), Alloc::Stack), Type::Array(Box::new(Type::Number), 4))
+                } else if let Some(ty) = builtin_type(name) {
+                    self.check_builtin(name, &args)?;
+                    // The new thread calls the function with the argument
+                    if let [Expr { kind: ExprKind::Function(function), .. }, arg] = &args[..] {
+                        if name == "spawn" {
+                            self.pass(function, std::slice::from_ref(arg));
+                        }
+                    }
+                    (ExprKind::Builtin(name.clone(), args), ty)
+                } else {
+                    let Some(signature) = self.signatures.get(name) else {
+                        return Err(Diagnostic::error(format!("Undefined function `{}`", name)));
+                    };
+                    let is_async = signature.is_async;
+                    self.pass(name, &args);
+                    if is_async {
+                        // Calling an async fn only creates a task; its body runs once awaited
+                        (ExprKind::StartTask(name.clone(), args), Type::Handle)
+                    } else {
+                        let ty = self.returns.get(name).cloned().unwrap_or(Type::Number);
+                        (ExprKind::Call(name.clone(), args), ty)
+                    }
+                }
+            }
+            _ => return Err(Diagnostic::error("Expected an expression")),
+        })
+    }
+
+    fn args(&mut self, args: &[Node]) -> NulaResult<Vec<Expr>> {
+        args.iter().map(|arg| self.expr(arg)).collect()
+    }
+
+    // What codegen needs to know about builtin arguments up front
+    fn check_builtin(&self, name: &str, args: &[Expr]) -> NulaResult<()> {
+        match name {
+            "asm" if !matches!(args, [Expr { kind: ExprKind::Str(_), .. }]) => {
+                Err(Diagnostic::error("asm expects a single string literal"))
+            }
+            "run" => match &args[1].ty {
+                Type::Array(elem, len) if **elem == Type::String || *len == 0 => Ok(()),
+                ty => {
+                    let message = format!("The arguments of `run` must be an array of strings, not {}", with_article(ty));
+                    Err(Diagnostic::error(message).with_span(args[1].span))
+                }
+            },
+            "vec4_dot" => check_vec4(name, args),
+            "bytes_len" | "byte_at" | "slice" | "bytes_to_string" => check_arg(name, &args[0], &[Type::Bytes]),
+            "sha256" | "md5" | "fnv" | "base64_encode" | "hex_encode" => check_arg(name, &args[0], &[Type::String, Type::Bytes]),
+            "bytes" | "read_file" => check_arg(name, &args[0], &[Type::String]),
+            "write_file" => check_arg(name, &args[0], &[Type::String]).and(check_arg(name, &args[1], &[Type::String, Type::Bytes])),
+            // The new thread calls the function with the argument
+            "spawn" => match &args[0].kind {
+                ExprKind::Function(function) if self.signatures[function].params.len() == 1 => Ok(()),
+                ExprKind::Function(function) => {
+                    let count = self.signatures[function].params.len();
+                    let message = format!("`spawn` calls `{}` with one argument, but it takes {}", function, count);
+                    Err(Diagnostic::error(message).with_span(args[0].span))
+                }
+                _ => Err(Diagnostic::error("`spawn` takes the name of a function to call").with_span(args[0].span)),
+            },
+            _ => Ok(()),
+        }
+    }
+
+    fn array_len(&self, array: &Expr) -> NulaResult<usize> {
+        match (&array.ty, &array.kind) {
+            (Type::Array(_, len), _) => Ok(*len),
+            (Type::Tuple(elems), _) => Ok(elems.len()),
+            (_, ExprKind::Local(id)) => {
+                Err(Diagnostic::error(format!("Length of array `{}` is unknown", self.locals[*id].name)).with_span(array.span))
+            }
+            _ => Err(Diagnostic::error("Expected an array").with_span(array.span)),
+        }
+    }
+
+    // Type of `array[index]`, or of every element when there's no index.
+    // Elements of a tuple are only known for an index written as a number,
+    // and ones of a string or bytes are its bytes.
+    fn element_type(&self, array: &Expr, index: Option<&Expr>) -> NulaResult<Type> {
+        match (&array.ty, index) {
+            (Type::Array(elem, _), _) => Ok((**elem).clone()),
+            (Type::String | Type::Bytes, Some(_)) => Ok(Type::Number),
+            (Type::Tuple(elems), Some(index)) => match index.kind {
+                ExprKind::Number(i) if i >= 0.0 && i.fract() == 0.0 && (i as usize) < elems.len() => Ok(elems[i as usize].clone()),
+                _ => {
+                    let message = format!(
+                        "Elements of a {} have different types, so it can only be indexed with a number from 0 to {}",
+                        describe(&array.ty),
+                        elems.len() - 1
+                    );
+                    Err(Diagnostic::error(message).with_span(index.span))
+                }
+            },
+            (Type::Tuple(_), None) => {
+                let message = format!("Elements of a {} have different types, so it can't be looped over", describe(&array.ty));
+                Err(Diagnostic::error(message).with_span(array.span))
+            }
+            (ty, _) => {
+                let message = format!("Only arrays, strings and bytes can be indexed, not {}", with_article(ty));
+                Err(Diagnostic::error(message).with_span(array.span))
+            }
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Option<VarId> {
+        self.scopes.iter().rev().find_map(|scope| scope.get(name).copied())
+    }
+
+    fn declare(&mut self, name: &str, ty: Type) -> VarId {
+        let id = self.temporary(name, ty);
+        self.locals[id].temporary = false;
+        self.scopes.last_mut().expect("lowerer always has a scope").insert(name.to_string(), id);
+        id
+    }
+
+    // A local that no name refers to
+    fn temporary(&mut self, name: &str, ty: Type) -> VarId {
+        self.locals.push(Local { name: name.to_string(), ty, temporary: true });
+        self.locals.len() - 1
+    }
+
+    // Assigning changes what a variable holds from then on, as long as it stays
+    // in the same kind of register
+    fn retype(&mut self, id: VarId, ty: &Type) -> NulaResult<()> {
+        let local = &mut self.locals[id];
+        if local.ty.is_pointer() != ty.is_pointer() {
+            let message = format!("`{}` holds a {}, so it can't be assigned a {}", local.name, local.ty.name(), ty.name());
+            return Err(Diagnostic::error(message));
+        }
+        local.ty = ty.clone();
+        Ok(())
+    }
+}
+
+impl Passed {
+    fn new(value: &Expr) -> Passed {
+        Passed { ty: value.ty.clone(), span: value.span, conflict: None }
+    }
+
+    fn add(&mut self, value: &Expr) {
+        if self.ty == value.ty {
+            return;
+        }
+        if !self.ty.is_pointer() && !value.ty.is_pointer() {
+            self.ty = Type::Number;
+            return;
+        }
+        // A number may only be one because it came from a parameter or call
+        // whose type isn't known yet, so a string or array is the better guess
+        let other = if self.ty.is_pointer() {
+            (value.ty.clone(), value.span)
+        } else {
+            let number = (self.ty.clone(), self.span);
+            (self.ty, self.span) = (value.ty.clone(), value.span);
+            number
+        };
+        self.conflict.get_or_insert(other);
+    }
+}
+
+fn number(n: f64, span: Span) -> Expr {
+    Expr { kind: ExprKind::Number(n), ty: Type::Number, span }
+}
+
+fn local(id: VarId, ty: Type, span: Span) -> Expr {
+    Expr { kind: ExprKind::Local(id), ty, span }
+}
+
+fn number_op(op: BinOp, left: Expr, right: Expr) -> Expr {
+    let span = left.span;
+    Expr { kind: ExprKind::Binary(op, Box::new(left), Box::new(right)), ty: Type::Number, span }
+}
+
+fn increment(counter: VarId, span: Span) -> Stmt {
+    Stmt::Assign(counter, number_op(BinOp::Add, local(counter, Type::Number, span), number(1.0, span)))
+}
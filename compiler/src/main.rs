@@ -1,73 +1,979 @@
 // src/main.rs - Main entry point for nula-compiler
 
-use std::env;
 use std::fs;
-use std::io;
-use std::path::Path;
-use std::process;
+use std::collections::{BTreeMap, HashSet};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::env;
+use std::io::{self, IsTerminal};
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, ExitStatus};
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, SystemTime};
 
+use clap::{Args, Parser as _, Subcommand, ValueEnum};
 use cranelift::prelude::*;
-use cranelift_codegen::isa::{self, CallConv};
-use cranelift_codegen::settings;
+use cranelift_codegen::isa::{self, OwnedTargetIsa};
+use cranelift_codegen::settings::{self, Configurable};
 use cranelift_codegen::Context as CodegenContext;
-use cranelift_module::{DataContext, Linkage, Module};
-use cranelift_object::{ObjectBuilder, ObjectModule};
+use cranelift_module::{FuncId, Linkage};
 
-use nula_compiler::ast::Ast;
-use nula_compiler::codegen::CodeGen;
-use nula_compiler::parser::Parser;
+use ast::{Ast, Node};
+use backend::{Backend, JitBackend, ObjectBackend};
+use codegen::CodeGen;
+use parser::Parser;
+use profile::{Measure, Sample};
+use target_lexicon::{Environment, OperatingSystem, Triple};
 
 mod ast;
+mod backend;
+mod debuginfo;
+mod diagnostic;
+mod lexer;
 mod parser;
 mod codegen;
+mod sema;
+mod const_eval;
+mod hir;
+mod lower;
+mod fold;
+mod escape;
+mod manifest;
+mod source;
+mod profile;
+
+// Counts what the compiler allocates, for --self-profile
+#[global_allocator]
+static ALLOCATOR: profile::Counting = profile::Counting;
+
+// Runtime support library, compiled alongside the object by the linker driver
+const RUNTIME_SRC: &str = include_str!("../runtime/nula_rt.c");
+
+// windows links with MSVC's link.exe, windows-gnu with MinGW's gcc
+const PLATFORMS: [&str; 4] = ["linux", "windows", "windows-gnu", "macos"];
+const ARCHES: [&str; 2] = ["x86_64", "aarch64"];
+
+// Exit codes for build scripts. Panics in the compiler exit with 101 too.
+const EXIT_COMPILE_ERROR: i32 = 1; // errors in the program, its manifest or the command line
+const EXIT_LINK_ERROR: i32 = 2;
+const EXIT_INTERNAL_ERROR: i32 = 101;
+
+#[derive(clap::Parser)]
+#[command(name = "nula", version, about = "Compiler for the Nula language")]
+struct Cli {
+    #[command(subcommand)]
+    action: Action,
+}
+
+#[derive(Subcommand)]
+enum Action {
+    /// Compile a program to an executable in the build directory (nula/bin next to it by default)
+    Build(BuildArgs),
+    /// Compile a program and run it
+    Run {
+        #[command(flatten)]
+        build: BuildArgs,
+        /// Arguments for the program
+        #[arg(last = true)]
+        args: Vec<String>,
+    },
+    /// Compile a program in memory and run it, without a linker or C compiler
+    Jit {
+        #[command(flatten)]
+        check: CheckArgs,
+        /// Optimization level
+        #[arg(short = 'O', value_enum, default_value_t = OptLevel::O1)]
+        opt_level: OptLevel,
+        /// Also free values that reference each other, with a tracing collector
+        #[arg(long)]
+        gc: bool,
+        /// Stack size of the program's main thread, like 64M [default: the system's]
+        #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+        stack_size: Option<u64>,
+    },
+    /// Report errors and warnings without compiling
+    Check(CheckArgs),
+    /// Compile the test_ functions into a test runner and run it
+    Test(BuildArgs),
+    /// Delete the build directory
+    Clean {
+        /// Program whose build directory to delete [default: the project in ./nula.toml, or main.nula]
+        file: Option<String>,
+        /// Build directory to delete [default: the one in nula.toml, or nula/bin next to the program]
+        #[arg(long, value_name = "DIR")]
+        build_dir: Option<PathBuf>,
+    },
+    /// Create a project directory with a nula.toml and a program to start from
+    New {
+        /// Name of the directory and the package
+        name: String,
+    },
+}
+
+#[derive(Args, Default)]
+struct CheckArgs {
+    /// Files of the program, or - to read one from stdin. Functions are shared
+    /// between them and top-level statements run file by file, in this order
+    /// [default: the project in ./nula.toml, or main.nula]
+    files: Vec<String>,
+    /// How errors and warnings are printed
+    #[arg(long, value_enum, default_value_t = MessageFormat::Human)]
+    message_format: MessageFormat,
+    /// Don't report the given warning
+    #[arg(short = 'A', value_name = "WARNING", value_parser = clap::builder::PossibleValuesParser::new(sema::LINTS))]
+    allow: Vec<String>,
+    /// Report the given warning, even if allowed with -A
+    #[arg(short = 'W', value_name = "WARNING", value_parser = clap::builder::PossibleValuesParser::new(sema::LINTS))]
+    warn: Vec<String>,
+    /// Treat warnings as errors
+    #[arg(long)]
+    deny_warnings: bool,
+    /// When to color diagnostics
+    #[arg(long, value_enum, default_value_t = ColorChoice::Auto)]
+    color: ColorChoice,
+    /// Print how long each phase of the compilation took
+    #[arg(long)]
+    timings: bool,
+    /// Write how long each phase and function took and how much it allocated
+    /// to FILE: a summary, or a Chrome trace if FILE ends in .json
+    #[arg(long, value_name = "FILE")]
+    self_profile: Option<PathBuf>,
+    /// Read big files instead of mapping them, which --watch passes its builds
+    /// as the files may change under them
+    #[arg(long, hide = true)]
+    no_map: bool,
+}
+
+#[derive(Args, Default)]
+struct BuildArgs {
+    #[command(flatten)]
+    check: CheckArgs,
+    /// Platform to compile for [default: the ones in nula.toml, or this machine's]
+    #[arg(long, value_parser = PLATFORMS)]
+    platform: Option<String>,
+    /// Processor to compile for [default: this machine's]
+    #[arg(long, value_parser = ARCHES)]
+    arch: Option<String>,
+    /// Target triple to compile for instead of a platform, like aarch64-unknown-linux-musl
+    #[arg(long, value_name = "TRIPLE", conflicts_with_all = ["platform", "arch", "static_link"])]
+    target: Option<String>,
+    /// Linker driver to use instead of the platform's (needed for --target triples of other systems)
+    #[arg(long, value_name = "COMMAND")]
+    linker: Option<String>,
+    /// Where the target's C library and startup files are, for linking for another system
+    #[arg(long, value_name = "DIR")]
+    sysroot: Option<PathBuf>,
+    /// Pass the rest of the command line to the linker as it is
+    #[arg(long, value_name = "ARGS", num_args = 1.., allow_hyphen_values = true)]
+    link_args: Vec<String>,
+    /// Extra library to link against
+    #[arg(long = "link", value_name = "LIB")]
+    link_libs: Vec<String>,
+    /// Optimization level [default: 1, or s with --release]
+    #[arg(short = 'O', value_enum)]
+    opt_level: Option<OptLevel>,
+    /// Optimize for size and drop unused code when linking
+    #[arg(long)]
+    release: bool,
+    /// Leave symbols and debug info out of the executable
+    #[arg(long)]
+    strip: bool,
+    /// Put debug info in the object file, so gdb and lldb can step through the source and print variables
+    #[arg(short = 'g')]
+    debug: bool,
+    /// Also free values that reference each other, with a tracing collector
+    #[arg(long)]
+    gc: bool,
+    /// Stack size of the program's main thread, like 64M [default: the system's]
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, conflicts_with = "lib")]
+    stack_size: Option<u64>,
+    /// Link against musl statically, so the executable runs on any Linux distribution
+    #[arg(long = "static")]
+    static_link: bool,
+    /// Where to write the executable, or the object file when not linking [default: <program name> in the build directory]
+    #[arg(short, long, value_name = "PATH")]
+    output: Option<PathBuf>,
+    /// Directory for the object file, the runtime and the executable [default: the one in nula.toml, or nula/bin next to the program]
+    #[arg(long, value_name = "DIR")]
+    build_dir: Option<PathBuf>,
+    /// Print an intermediate form of the program instead of compiling it
+    #[arg(long, value_enum)]
+    emit: Option<Emit>,
+    /// Stop after writing the object file, next to the runtime's C source, for linking by hand
+    #[arg(long)]
+    no_link: bool,
+    /// Build a shared library of the @export-ed functions instead of an executable
+    #[arg(long)]
+    lib: bool,
+    /// Build again whenever a file of the program or nula.toml changes
+    #[arg(long)]
+    watch: bool,
+    /// Executable name from nula.toml
+    #[arg(skip)]
+    name: Option<String>,
+    /// Extra linker flags from nula.toml
+    #[arg(skip)]
+    linker_flags: Vec<String>,
+    /// Linker and sysroot for each platform or triple, from nula.toml
+    #[arg(skip)]
+    targets: BTreeMap<String, manifest::Target>,
+    /// Build the test runner instead of the program
+    #[arg(skip)]
+    test: bool,
+}
+
+impl BuildArgs {
+    fn link(&self) -> bool {
+        !self.no_link && self.emit != Some(Emit::Obj)
+    }
+
+    fn apply_manifest(&mut self, manifest: &manifest::Manifest) {
+        self.link_libs.extend(manifest.build.link.iter().cloned());
+        self.linker_flags = manifest.build.linker_flags.clone();
+        self.targets = manifest.target.clone();
+        self.name = Some(manifest.build.output.clone().unwrap_or_else(|| manifest.package.name.clone()));
+        if self.build_dir.is_none() {
+            self.build_dir = manifest.build.dir.as_ref().map(PathBuf::from);
+        }
+        if let (None, Some(size)) = (self.stack_size, &manifest.build.stack_size) {
+            self.stack_size = Some(parse_size(size).unwrap_or_else(|err| {
+                eprintln!("Invalid stack-size in {}: {}", manifest::FILE_NAME, err);
+                process::exit(EXIT_COMPILE_ERROR);
+            }));
+        }
+    }
+
+    fn build_dir(&self) -> PathBuf {
+        self.build_dir.clone().unwrap_or_else(|| {
+            let project_dir = Path::new(&self.check.files[0]).parent().unwrap_or(Path::new("."));
+            project_dir.join("nula").join("bin")
+        })
+    }
+
+    // --target or --platform, else the manifest's platforms, else this machine's.
+    // build() prefers --target over the platform it is given.
+    fn platforms(&self, manifest: Option<&manifest::Manifest>) -> Vec<String> {
+        if let Some(target) = &self.target {
+            return vec![target.clone()];
+        }
+        if let Some(platform) = &self.platform {
+            return vec![platform.clone()];
+        }
+        match manifest {
+            Some(manifest) if !manifest.build.platforms.is_empty() => {
+                if let Some(unknown) = manifest.build.platforms.iter().find(|p| !PLATFORMS.contains(&p.as_str())) {
+                    eprintln!("{}: unsupported platform {} (expected one of {})", manifest::FILE_NAME, unknown, PLATFORMS.join(", "));
+                    process::exit(EXIT_COMPILE_ERROR);
+                }
+                manifest.build.platforms.clone()
+            }
+            _ => vec![env::consts::OS.to_string()],
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Emit {
+    /// The lexer's tokens, one per line with where they start and their byte range
+    Tokens,
+    /// The parsed program as an indented tree
+    Ast,
+    /// The typed IR codegen works from, after the optimization passes
+    Hir,
+    /// The Cranelift IR of every generated function
+    Clif,
+    /// The machine code of every generated function, as assembly
+    Asm,
+    /// The object file, without linking it (same as --no-link)
+    Obj,
+}
+
+fn main() {
+    // What's left are failures to write the build's files or start the program
+    if let Err(err) = dispatch(Cli::parse().action) {
+        eprintln!("{}", diagnostic::Diagnostic::error(err.to_string()));
+        process::exit(EXIT_COMPILE_ERROR);
+    }
+}
+
+fn dispatch(action: Action) -> io::Result<()> {
+    match action {
+        Action::Build(mut args) => {
+            let manifest = resolve_files(&mut args.check);
+            if args.watch {
+                watch(&args.check.files)?;
+            }
+            if let Some(manifest) = &manifest {
+                args.apply_manifest(manifest);
+            }
+            let platforms = args.platforms(manifest.as_ref());
+            let name = args.name.clone();
+            for platform in &platforms {
+                // Executables for several platforms would overwrite each other
+                if platforms.len() > 1 {
+                    let base = name.clone().unwrap_or_else(|| program_name(&args.check.files[0]).to_string());
+                    args.name = Some(format!("{}-{}", base, platform));
+                }
+                args.platform = Some(platform.clone());
+                let exe_path = build(&args)?;
+                // Keep stdout a valid SARIF document
+                if args.check.message_format != MessageFormat::Sarif {
+                    println!("Compiled to {:?}", exe_path);
+                }
+            }
+        }
+        Action::Run { build: mut args, args: program_args } => {
+            if let Some(manifest) = resolve_files(&mut args.check) {
+                args.apply_manifest(&manifest);
+            }
+            if !args.link() || args.lib {
+                eprintln!("nula run needs an executable and can't be used with --no-link, --emit obj or --lib");
+                process::exit(EXIT_COMPILE_ERROR);
+            }
+            let exe_path = build(&args)?;
+            let status = Command::new(&exe_path).args(&program_args).status().map_err(|err| at_path(&exe_path, err))?;
+            process::exit(status.code().unwrap_or(1));
+        }
+        Action::Test(mut args) => {
+            if let Some(manifest) = resolve_files(&mut args.check) {
+                args.apply_manifest(&manifest);
+            }
+            if !args.link() || args.lib {
+                eprintln!("nula test needs an executable and can't be used with --no-link, --emit obj or --lib");
+                process::exit(EXIT_COMPILE_ERROR);
+            }
+            let name = args.name.clone().unwrap_or_else(|| program_name(&args.check.files[0]).to_string());
+            args.name = Some(format!("{}-test", name));
+            args.test = true;
+            let exe_path = build(&args)?;
+            let status = Command::new(&exe_path).status().map_err(|err| at_path(&exe_path, err))?;
+            process::exit(status.code().unwrap_or(1));
+        }
+        Action::Jit { mut check, opt_level, gc, stack_size } => {
+            resolve_files(&mut check);
+            let code = jit(&check, opt_level, gc, stack_size)?;
+            process::exit(code);
+        }
+        Action::Check(mut args) => {
+            resolve_files(&mut args);
+            let sources = args.read_sources()?;
+            let mut reporter = Reporter { format: args.message_format, color: args.color.enabled(), sources: &sources, sarif_results: Vec::new() };
+            let mut timings = Timings::default();
+            front_end(&args, &sources, false, false, &mut reporter, &mut timings)?;
+            reporter.finish();
+            timings.finish(&args)?;
+        }
+        Action::Clean { file, build_dir } => {
+            let check = CheckArgs { files: file.into_iter().collect(), ..Default::default() };
+            let mut args = BuildArgs { check, build_dir, ..Default::default() };
+            if let Some(manifest) = resolve_files(&mut args.check) {
+                args.apply_manifest(&manifest);
+            }
+            let dir = args.build_dir();
+            if dir.exists() {
+                fs::remove_dir_all(&dir).map_err(|err| at_path(&dir, err))?;
+                println!("Removed {}", dir.display());
+            }
+        }
+        Action::New { name } => {
+            let dir = Path::new(&name);
+            if dir.exists() {
+                eprintln!("{} already exists", dir.display());
+                process::exit(EXIT_COMPILE_ERROR);
+            }
+            let created = (|| {
+                fs::create_dir_all(dir.join("src"))?;
+                fs::write(dir.join(manifest::FILE_NAME), manifest::new_project(program_name(&name)))?;
+                fs::write(dir.join("src").join("main.nula"), "write \"Hello, Nula!\"\n")?;
+                // Build output goes to nula/bin next to the entry file by default
+                fs::write(dir.join(".gitignore"), "nula/\n")
+            })();
+            created.map_err(|err| at_path(dir, err))?;
+            println!("Created {}", dir.display());
+        }
+    }
+    Ok(())
+}
+
+// Names the file an I/O error is about, for main to print
+fn at_path(path: &Path, err: io::Error) -> io::Error {
+    io::Error::new(err.kind(), format!("{}: {}", path.display(), err))
+}
+
+// With no files given, picks the project in the current directory if it has a
+// manifest, or main.nula
+fn resolve_files(args: &mut CheckArgs) -> Option<manifest::Manifest> {
+    if !args.files.is_empty() {
+        return None;
+    }
+    if !Path::new(manifest::FILE_NAME).exists() {
+        args.files.push("main.nula".to_string());
+        return None;
+    }
+    let dir = Path::new(".");
+    match manifest::load(dir).and_then(|manifest| Ok((manifest.source_files(dir)?, manifest))) {
+        Ok((files, manifest)) => {
+            args.files = files.iter().map(|file| file.to_string_lossy().into_owned()).collect();
+            Some(manifest)
+        }
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(EXIT_COMPILE_ERROR);
+        }
+    }
+}
+
+// Runs this command again without --watch whenever one of the files or the
+// manifest changes. Each build is its own process, since a failed one exits.
+fn watch(files: &[String]) -> io::Result<()> {
+    if files.iter().any(|file| file == "-") {
+        eprintln!("--watch can't be used with a program read from stdin");
+        process::exit(EXIT_COMPILE_ERROR);
+    }
+    let exe = env::current_exe()?;
+    let mut build_args: Vec<String> = env::args().skip(1).filter(|arg| arg != "--watch").collect();
+    build_args.push("--no-map".to_string());
+    let mut watched: Vec<&str> = files.iter().map(String::as_str).collect();
+    watched.push(manifest::FILE_NAME);
+    let mut last_stamps = None;
+    loop {
+        let stamps: Vec<Option<SystemTime>> =
+            watched.iter().map(|file| fs::metadata(file).and_then(|meta| meta.modified()).ok()).collect();
+        if last_stamps.as_ref() != Some(&stamps) {
+            if last_stamps.is_some() {
+                println!("\nChange detected, building again");
+            }
+            Command::new(&exe).args(&build_args).status()?;
+            println!("Watching for changes...");
+            last_stamps = Some(stamps);
+        }
+        thread::sleep(Duration::from_millis(500));
+    }
+}
+
+// Byte count with an optional K, M or G suffix, for --stack-size
+fn parse_size(text: &str) -> Result<u64, String> {
+    let (digits, unit) = match text.char_indices().find(|(_, c)| !c.is_ascii_digit()) {
+        Some((i, _)) => text.split_at(i),
+        None => (text, ""),
+    };
+    let scale: u64 = match unit.to_ascii_uppercase().as_str() {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        _ => return Err(format!("expected a size like 65536 or 64M, not `{}`", text)),
+    };
+    let size = digits.parse::<u64>().ok().and_then(|n| n.checked_mul(scale));
+    match size {
+        // Below this the runtime's overflow check leaves nothing to run in
+        Some(size) if size >= 256 << 10 => Ok(size),
+        Some(_) => Err("the stack needs to be at least 256K".to_string()),
+        None => Err(format!("expected a size like 65536 or 64M, not `{}`", text)),
+    }
+}
+
+// Default executable name: the file name without its extension
+fn program_name(file: &str) -> &str {
+    Path::new(file).file_stem().and_then(|stem| stem.to_str()).unwrap_or("main")
+}
+
+// A file of the program; spans refer to it by its index in the list
+struct Source {
+    name: String, // as shown in diagnostics
+    code: source::Text,
+}
+
+impl CheckArgs {
+    // Exits after reporting it if a file can't be read
+    fn read_sources(&self) -> io::Result<Vec<Source>> {
+        let mut sources = Vec::new();
+        for file in &self.files {
+            let (name, code) = if file == "-" {
+                ("<stdin>".to_string(), source::Text::stdin())
+            } else {
+                (file.clone(), source::Text::read(file, !self.no_map))
+            };
+            match code {
+                Ok(code) => sources.push(Source { name, code }),
+                Err(err) => {
+                    let message = format!("Can't read {}: {}", name, err);
+                    // Reported against the file, which JSON and SARIF output name
+                    let unread = [Source { name, code: source::Text::Owned(String::new()) }];
+                    let mut reporter = Reporter { format: self.message_format, color: self.color.enabled(), sources: &unread, sarif_results: Vec::new() };
+                    reporter.report(&diagnostic::Diagnostic::error(message))?;
+                    reporter.finish();
+                    process::exit(EXIT_COMPILE_ERROR);
+                }
+            }
+        }
+        Ok(sources)
+    }
+
+    fn warning_config(&self) -> diagnostic::WarningConfig {
+        let mut config = diagnostic::WarningConfig::default();
+        for name in &self.allow {
+            config.allow(name);
+        }
+        for name in &self.warn {
+            config.warn(name);
+        }
+        config.deny_warnings = self.deny_warnings;
+        config
+    }
+}
+
+// Parses, checks and lowers the program, with the optional passes if
+// `optimize` is set and the test harness instead of the top-level statements
+// if `test` is. Exits after reporting the errors if there are any.
+fn front_end(
+    args: &CheckArgs,
+    sources: &[Source],
+    optimize: bool,
+    test: bool,
+    reporter: &mut Reporter,
+    timings: &mut Timings,
+) -> io::Result<(Vec<Node>, hir::Program)> {
+    // Parse every file, then check them as one program
+    let mut ast = Vec::new();
+    let mut errors = Vec::new();
+    for (i, source) in sources.iter().enumerate() {
+        // Lexing happens along the way, as the parser gets to each token
+        match timings.time("parse", || Parser::new(&source.code, i).parse()) {
+            Ok(nodes) => ast.extend(nodes),
+            Err(file_errors) => errors.extend(file_errors),
+        }
+    }
+    if !errors.is_empty() {
+        for err in &errors {
+            reporter.report(err)?;
+        }
+        reporter.finish();
+        process::exit(EXIT_COMPILE_ERROR);
+    }
+    // Semantic checks
+    let diagnostics = timings.time("sema", || sema::check(&ast, &args.warning_config()));
+    for diagnostic in &diagnostics {
+        reporter.report(diagnostic)?;
+    }
+    if diagnostics.iter().any(|d| d.severity == diagnostic::Severity::Error) {
+        reporter.finish();
+        process::exit(EXIT_COMPILE_ERROR);
+    }
+
+    // Evaluate pure calls with constant arguments at compile time
+    let ast = if optimize { timings.time("const eval", || const_eval::eval_pure_calls(ast)) } else { ast };
+
+    // Resolve names and types for codegen
+    let mut program = match timings.time("lower", || lower::lower(&ast)) {
+        Ok(program) => program,
+        Err(err) => {
+            reporter.report(&err)?;
+            reporter.finish();
+            process::exit(EXIT_COMPILE_ERROR);
+        }
+    };
+    if test {
+        lower::test_harness(&mut program);
+    }
+    if optimize {
+        timings.time("fold", || fold::fold_constants(&mut program));
+
+        // Leave out functions nothing runs
+        let reachable = timings.time("dead functions", || sema::reachable_functions(&ast, test));
+        program.functions.retain(|function| reachable.contains(&function.name));
+    }
+    // Heap-allocate the arrays that outlive their function
+    timings.time("escape", || escape::place_arrays(&mut program));
+    Ok((ast, program))
+}
+
+// Compiles the program and links it, returning the path of the executable
+// (or of the object file when not linking)
+fn build(args: &BuildArgs) -> io::Result<PathBuf> {
+    let file = &args.check.files[0]; // names the executable and where it goes
+    let (platform, arch, triple) = match &args.target {
+        Some(target) => {
+            let triple = Triple::from_str(target).unwrap_or_else(|err| {
+                eprintln!("Invalid target triple {}: {}", target, err);
+                process::exit(EXIT_COMPILE_ERROR);
+            });
+            (triple_platform(&triple), triple.architecture.to_string(), triple)
+        }
+        None => {
+            let platform = args.platform.as_deref().unwrap_or(env::consts::OS);
+            let arch = args.arch.as_deref().unwrap_or(env::consts::ARCH);
+            (platform, arch.to_string(), target_triple(platform, arch, args.static_link))
+        }
+    };
+    // musl targets are linked statically, like --static does
+    let static_link = args.static_link || triple.environment == Environment::Musl;
+    // Release builds are for shipping, so they optimize for size unless told otherwise
+    let opt_level = args.opt_level.unwrap_or(if args.release { OptLevel::Os } else { OptLevel::O1 });
+    if file == "-" && args.output.is_none() {
+        eprintln!("A program read from stdin needs -o to say where the output goes");
+        process::exit(EXIT_COMPILE_ERROR);
+    }
+    let sources = args.check.read_sources()?;
+    let mut reporter = Reporter {
+        format: args.check.message_format,
+        color: args.check.color.enabled(),
+        sources: &sources,
+        sarif_results: Vec::new(),
+    };
+    // Dumps of the source as the compiler first sees it, even if it has errors
+    if matches!(args.emit, Some(Emit::Tokens | Emit::Ast)) {
+        let mut errors = Vec::new();
+        for (i, source) in sources.iter().enumerate() {
+            if sources.len() > 1 {
+                println!("{}:", source.name);
+            }
+            if args.emit == Some(Emit::Tokens) {
+                let mut lexer = lexer::Lexer::new(&source.code, i);
+                for (token, span) in lexer.by_ref() {
+                    println!("{}:{}\t{}..{}\t{:?}", span.line, span.col, span.start, span.end, token);
+                }
+                errors.extend(lexer.errors);
+            } else {
+                match Parser::new(&source.code, i).parse() {
+                    Ok(ast) => print!("{}", ast::dump(&ast)),
+                    Err(file_errors) => errors.extend(file_errors),
+                }
+            }
+        }
+        for err in &errors {
+            reporter.report(err)?;
+        }
+        reporter.finish();
+        process::exit(if errors.is_empty() { 0 } else { EXIT_COMPILE_ERROR });
+    }
+    let mut timings = Timings::default();
+    let bin_dir = args.build_dir();
+    let name = args.name.as_deref().unwrap_or(program_name(file));
+    let obj_path = match &args.output {
+        Some(path) if !args.link() => path.clone(),
+        _ => bin_dir.join(format!("{}.o", name)),
+    };
+    let exe_path = match &args.output {
+        Some(path) => path.clone(),
+        None if args.lib => bin_dir.join(match platform {
+            "windows" | "windows-gnu" => format!("{}.dll", name),
+            "macos" => format!("lib{}.dylib", name),
+            _ => format!("lib{}.so", name),
+        }),
+        None => bin_dir.join(if platform.starts_with("windows") { format!("{}.exe", name) } else { name.to_string() }),
+    };
+    // Outputs of an earlier build would look like this one's if it fails
+    if matches!(args.emit, None | Some(Emit::Obj)) {
+        let _ = fs::remove_file(&obj_path);
+        if args.link() {
+            let _ = fs::remove_file(&exe_path);
+        }
+    }
+    let (ast, mut program) = front_end(&args.check, &sources, opt_level.run_passes(), args.test, &mut reporter, &mut timings)?;
+    if args.gc {
+        lower::enable_gc(&mut program);
+    }
+    if args.emit == Some(Emit::Hir) {
+        print!("{}", program.dump());
+        process::exit(0);
+    }
+    let mut link_libs = args.link_libs.clone();
+    let mut exports = HashSet::new();
+    for node in &ast {
+        match &node.ast {
+            Ast::Link(lib) => link_libs.push(lib.clone()),
+            Ast::Export(name) => {
+                exports.insert(name.clone());
+            }
+            _ => {}
+        }
+    }
+    if args.lib && exports.is_empty() {
+        eprintln!("A library needs at least one @export-ed function");
+        process::exit(EXIT_COMPILE_ERROR);
+    }
+    // Nothing would run them, as a library has no main
+    if args.lib && !program.main.body.is_empty() {
+        eprintln!("Top-level statements don't run in a library; move them into a function");
+        process::exit(EXIT_COMPILE_ERROR);
+    }
+
+    if args.static_link && platform != "linux" {
+        eprintln!("--static is only supported on linux");
+        process::exit(EXIT_COMPILE_ERROR);
+    }
+    // Always position independent: shared libraries can be loaded at any
+    // address, and executables link as PIE by default, which needs text
+    // without relocations (DT_TEXTREL otherwise)
+    let isa = target_isa(triple.clone(), opt_level, true);
+    // Release builds let the linker drop the functions nothing calls
+    let mut backend = ObjectBackend::new(isa, name, args.release);
+    if args.debug {
+        if platform == "windows" {
+            eprintln!("-g writes DWARF, which the MSVC tools can't read; use --platform windows-gnu");
+            process::exit(EXIT_COMPILE_ERROR);
+        }
+        let comp_dir = env::current_dir()?.to_string_lossy().into_owned();
+        backend.with_debug_info(sources.iter().map(|source| source.name.clone()).collect(), comp_dir);
+    }
+
+    let export_main = !args.lib && args.stack_size.is_none();
+    let (main_id, asm_blocks) = gen_module(&mut backend, &program, &exports, export_main, args.emit, &mut reporter, &mut timings)?;
+    if let Some(size) = args.stack_size.filter(|_| !args.lib) {
+        if let Err(err) = gen_stack_main(&mut backend, main_id, size) {
+            reporter.report(&err)?;
+            process::exit(EXIT_INTERNAL_ERROR);
+        }
+    }
+    if !asm_blocks.is_empty() && platform == "windows" {
+        eprintln!("asm blocks need a gcc/clang toolchain; use --platform windows-gnu");
+        process::exit(EXIT_COMPILE_ERROR);
+    }
+    let object_start = Measure::start();
+    if let Err(err) = backend.finish() {
+        reporter.report(&err)?;
+        process::exit(EXIT_INTERNAL_ERROR);
+    }
+
+    // Write object file
+    let obj_bytes = backend.into_bytes();
+    fs::create_dir_all(&bin_dir).map_err(|err| at_path(&bin_dir, err))?;
+    if let Some(dir) = obj_path.parent() {
+        fs::create_dir_all(dir).map_err(|err| at_path(dir, err))?;
+    }
+    fs::write(&obj_path, obj_bytes).map_err(|err| at_path(&obj_path, err))?;
+    let rt_path = bin_dir.join("nula_rt.c");
+    fs::write(&rt_path, RUNTIME_SRC).map_err(|err| at_path(&rt_path, err))?;
+    let asm_path = bin_dir.join("nula_asm.s");
+    if !asm_blocks.is_empty() {
+        fs::write(&asm_path, asm_source(&asm_blocks, platform)).map_err(|err| at_path(&asm_path, err))?;
+    }
+    timings.add(object_start.finish("object file"));
+    if !args.link() {
+        timings.finish(&args.check)?;
+        return Ok(obj_path);
+    }
 
-fn main() -> io::Result<()> {
-    let args: Vec<String> = env::args().collect();
-    if args.len() < 3 || args[1] != "--platform" {
-        eprintln!("Usage: nula-compiler --platform <platform> <file.nula>");
-        process::exit(1);
+    // Link to executable
+    let host = Triple::host();
+    let cross = triple.operating_system != host.operating_system || triple.architecture != host.architecture;
+    // nula.toml settings for the --target triple or the platform
+    let target_key = args.target.as_deref().unwrap_or(platform);
+    let target_config = args.targets.get(target_key).cloned().unwrap_or_default();
+    let chosen_linker = args.linker.clone().or(target_config.linker);
+    let sysroot = args.sysroot.clone().or(target_config.sysroot.map(PathBuf::from));
+    if let Some(sysroot) = &sysroot {
+        if platform == "windows" {
+            eprintln!("--sysroot is for gcc and clang; link.exe finds its libraries through the LIB environment variable");
+            process::exit(EXIT_COMPILE_ERROR);
+        }
+        if !sysroot.is_dir() {
+            eprintln!("Sysroot {} doesn't exist", sysroot.display());
+            process::exit(EXIT_COMPILE_ERROR);
+        }
+    }
+    let linker = chosen_linker.clone().unwrap_or_else(|| match platform {
+        // Cross toolchains are named after the target they build for
+        "linux" if static_link && !cross => "musl-gcc".to_string(),
+        "linux" if static_link => format!("{}-linux-musl-gcc", arch),
+        "linux" if cross => format!("{}-linux-gnu-gcc", arch),
+        "linux" => "gcc".to_string(),
+        "windows" => "link.exe".to_string(),
+        "windows-gnu" if env::consts::OS != "windows" => format!("{}-w64-mingw32-gcc", arch),
+        "windows-gnu" => "gcc".to_string(),
+        "macos" => "clang".to_string(),
+        _ => {
+            eprintln!("No default linker for {}; pass one with --linker", triple);
+            process::exit(EXIT_COMPILE_ERROR);
+        }
+    });
+    if let Some(dir) = exe_path.parent() {
+        fs::create_dir_all(dir).map_err(|err| at_path(dir, err))?;
+    }
+    let link_start = Measure::start();
+    // The runtime, compiled for the target by the linker driver (or cl.exe for
+    // MSVC) into the build directory. It's named after everything that goes
+    // into it, so later builds reuse it until one of those changes.
+    let rt_flags = runtime_flags(platform, &arch, args.release, args.debug, args.lib, sysroot.as_deref());
+    let mut hasher = DefaultHasher::new();
+    (RUNTIME_SRC, &linker, &rt_flags).hash(&mut hasher);
+    let rt_obj = bin_dir.join(format!("nula_rt-{:016x}.{}", hasher.finish(), if platform == "windows" { "obj" } else { "o" }));
+    let mut status = Ok(ExitStatus::default());
+    if !rt_obj.exists() {
+        status = if platform == "windows" {
+            compile_runtime_msvc(&rt_path, &rt_obj, &arch, &rt_flags, &[&obj_path, &exe_path])
+        } else {
+            compile_runtime(&linker, &rt_path, &rt_obj, &rt_flags)
+        };
+    }
+    let (linker, mut command) = match platform {
+        "windows" if chosen_linker.is_none() => msvc_linker(&arch),
+        _ => (linker.clone(), Command::new(&linker)),
+    };
+    command
+        .arg(obj_path.to_str().unwrap())
+        .arg(rt_obj.to_str().unwrap())
+        .args(if asm_blocks.is_empty() { None } else { asm_path.to_str() })
+        .args(match platform {
+            "windows" => vec![format!("/OUT:{}", exe_path.display())],
+            _ => vec!["-o".to_string(), exe_path.display().to_string()],
+        })
+        .args(if platform == "linux" { &["-lc", "-lm", "-lpthread"][..] } else { &[] })
+        .args(link_libs.iter().map(|lib| if platform == "windows" { format!("{}.lib", lib) } else { format!("-l{}", lib) }))
+        .args(arch_flags(platform, &arch))
+        .args(if static_link { Some("-static") } else { None })
+        .args(if args.lib { lib_flags(platform, &exports) } else { Vec::new() })
+        .args(size_flags(platform, args.release, args.strip))
+        .args(&args.linker_flags)
+        .args(sysroot.as_ref().map(|sysroot| format!("--sysroot={}", sysroot.display())))
+        .args(&args.link_args);
+    if matches!(status, Ok(status) if status.success()) {
+        status = command.status();
     }
-    let platform = &args[2];
-    let file = if args.len() > 3 { &args[3] } else { "main.nula".to_string() };
 
-    // Read code
-    let code = fs::read_to_string(&file)?;
+    let configure = format!("--linker and --sysroot, or `linker` and `sysroot` under [target.{}] in {}", target_key, manifest::FILE_NAME);
+    match status {
+        Ok(status) if status.success() => {}
+        // gcc's own message is about some missing file; say what's likely behind it
+        Ok(_) if cross && sysroot.is_none() => link_failed(
+            &[&obj_path, &exe_path],
+            format!("Linking for {} failed. It needs that system's C library and startup files; point to them with {}", triple, configure),
+        ),
+        Ok(_) => link_failed(&[&obj_path, &exe_path], "Linking failed".to_string()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound && cross && chosen_linker.is_none() => link_failed(
+            &[&obj_path, &exe_path],
+            format!("Building for {} on this machine needs a cross linker, and {} isn't installed. Install it or set one with {}", triple, linker, configure),
+        ),
+        Err(err) if err.kind() == io::ErrorKind::NotFound && chosen_linker.is_none() => link_failed(
+            &[&obj_path, &exe_path],
+            format!("Couldn't find the linker {}. {}", linker, toolchain_hint(platform, static_link)),
+        ),
+        Err(err) => link_failed(&[&obj_path, &exe_path], format!("Couldn't run the linker {}: {}", linker, err)),
+    }
+    timings.add(link_start.finish("link"));
+    timings.finish(&args.check)?;
+    Ok(exe_path)
+}
 
-    // Parse
-    let mut parser = Parser::new(&code);
-    let ast = parser.parse();
+// Compiles the program in memory and runs it, returning its exit code. The
+// runtime is part of the compiler (see build.rs), so no linker is needed.
+fn jit(args: &CheckArgs, opt_level: OptLevel, gc: bool, stack_size: Option<u64>) -> io::Result<i32> {
+    if env::consts::OS == "windows" {
+        eprintln!("nula jit isn't supported on windows yet; use nula run");
+        process::exit(EXIT_COMPILE_ERROR);
+    }
+    let sources = args.read_sources()?;
+    let mut reporter = Reporter { format: args.message_format, color: args.color.enabled(), sources: &sources, sarif_results: Vec::new() };
+    let mut timings = Timings::default();
+    let (ast, mut program) = front_end(args, &sources, opt_level.run_passes(), false, &mut reporter, &mut timings)?;
+    if gc {
+        lower::enable_gc(&mut program);
+    }
+    if ast.iter().any(|node| matches!(node.ast, Ast::Link(_))) {
+        eprintln!("nula jit can't load @link libraries; use nula run");
+        process::exit(EXIT_COMPILE_ERROR);
+    }
+
+    // The JIT knows where everything is, so absolute addresses will do
+    let isa = target_isa(Triple::host(), opt_level, false);
+    let mut backend = JitBackend::new(isa);
+    let (main_id, asm_blocks) = gen_module(&mut backend, &program, &HashSet::new(), true, None, &mut reporter, &mut timings)?;
+    if !asm_blocks.is_empty() {
+        eprintln!("asm blocks are assembled by the linker and can't be run by nula jit; use nula run");
+        process::exit(EXIT_COMPILE_ERROR);
+    }
+    if let Err(err) = backend.finish() {
+        reporter.report(&err)?;
+        process::exit(EXIT_INTERNAL_ERROR);
+    }
+    timings.finish(args)?;
+    // Safety: main was declared as `int main(void)` with the platform's calling convention
+    let main: extern "C" fn() -> i32 = unsafe { std::mem::transmute(backend.function(main_id)) };
+    match stack_size {
+        Some(size) => Ok(thread::Builder::new().stack_size(size as usize).spawn(move || main())?.join().unwrap_or(EXIT_INTERNAL_ERROR)),
+        None => Ok(main()),
+    }
+}
 
-    // Setup Cranelift
-    let triple_str = match platform.as_str() {
-        "linux" => "x86_64-unknown-linux-gnu",
-        "windows" => "x86_64-pc-windows-msvc",
-        "macos" => "x86_64-apple-darwin",
+// Triple for a platform and processor. `musl` picks musl over glibc on linux.
+fn target_triple(platform: &str, arch: &str, musl: bool) -> Triple {
+    let system = match platform {
+        "linux" if musl => "unknown-linux-musl",
+        "linux" => "unknown-linux-gnu",
+        "windows" => "pc-windows-msvc",
+        "windows-gnu" => "pc-windows-gnu",
+        "macos" => "apple-darwin",
         _ => {
-            eprintln!("Unsupported platform: {}", platform);
-            process::exit(1);
+            eprintln!("Unsupported platform: {} (pass one of {} to --platform)", platform, PLATFORMS.join(", "));
+            process::exit(EXIT_COMPILE_ERROR);
         }
     };
-    let flag_builder = settings::builder();
-    let isa_builder = isa::lookup_by_name(triple_str).unwrap();
-    let isa = isa_builder.finish(settings::Flags::new(flag_builder)).unwrap();
+    if !ARCHES.contains(&arch) || (platform.starts_with("windows") && arch == "aarch64") {
+        eprintln!("Unsupported processor for {}: {} (pass one of {} to --arch)", platform, arch, ARCHES.join(", "));
+        process::exit(EXIT_COMPILE_ERROR);
+    }
+    Triple::from_str(&format!("{}-{}", arch, system)).unwrap()
+}
 
-    let builder = ObjectBuilder::new(isa, "nula_bin".to_string(), cranelift_module::default_libcall_names()).unwrap();
-    let mut module = ObjectModule::new(builder);
+// The platform a --target triple belongs to, which picks the linker and its
+// flags, or "" for systems without a default
+fn triple_platform(triple: &Triple) -> &'static str {
+    match (triple.operating_system, triple.environment) {
+        (OperatingSystem::Linux, _) => "linux",
+        (OperatingSystem::Windows, Environment::Gnu) => "windows-gnu",
+        (OperatingSystem::Windows, _) => "windows",
+        (OperatingSystem::Darwin | OperatingSystem::MacOSX { .. }, _) => "macos",
+        _ => "",
+    }
+}
 
-    // printf
-    let mut printf_sig = module.make_signature();
-    printf_sig.params.push(AbiParam::new(types::I64));
-    printf_sig.returns.push(AbiParam::new(types::I32));
-    printf_sig.call_conv = CallConv::C;
-    let printf = module.declare_function("printf", Linkage::Import, &printf_sig).unwrap();
+// Cranelift's target for the triple, with the flags for `opt_level` and
+// position-independent code if `pic` is set
+fn target_isa(triple: Triple, opt_level: OptLevel, pic: bool) -> OwnedTargetIsa {
+    let mut flag_builder = settings::builder();
+    flag_builder.set("is_pic", if pic { "true" } else { "false" }).unwrap();
+    flag_builder.set("opt_level", opt_level.cranelift_opt_level()).unwrap();
+    flag_builder.set("enable_verifier", if opt_level.verify() { "true" } else { "false" }).unwrap();
+    // Frames bigger than a guard page touch each page on the way down, so
+    // they can't skip past it
+    flag_builder.set("enable_probestack", "true").unwrap();
+    flag_builder.set("probestack_strategy", "inline").unwrap();
+    let isa_builder = isa::lookup(triple.clone()).unwrap_or_else(|err| {
+        eprintln!("Can't generate code for {}: {}", triple, err);
+        process::exit(EXIT_COMPILE_ERROR);
+    });
+    isa_builder.finish(settings::Flags::new(flag_builder)).unwrap()
+}
 
+// Generates the program's functions and its main into `backend`, returning
+// main's id and the asm blocks to assemble next to it. Unless `export_main`,
+// main is kept to itself, as in a library or under gen_stack_main. --emit
+// clif and asm print here and exit.
+fn gen_module(
+    backend: &mut dyn Backend,
+    program: &hir::Program,
+    exports: &HashSet<String>,
+    export_main: bool,
+    emit: Option<Emit>,
+    reporter: &mut Reporter,
+    timings: &mut Timings,
+) -> io::Result<(FuncId, Vec<String>)> {
+    let module = backend.module();
     // Main function
     let mut main_sig = module.make_signature();
     main_sig.returns.push(AbiParam::new(types::I32));
-    let main_id = module.declare_function("main", Linkage::Export, &main_sig).unwrap();
+    let main_id = if export_main {
+        module.declare_function("main", Linkage::Export, &main_sig).unwrap()
+    } else {
+        module.declare_function("__nula_main", Linkage::Local, &main_sig).unwrap()
+    };
 
     let mut ctx = CodegenContext::new();
     ctx.func.signature = main_sig;
+    if backend.debug_info() {
+        ctx.func.dfg.collect_debug_info();
+    }
 
     let mut builder_ctx = FunctionBuilderContext::new();
     let mut func_builder = FunctionBuilder::new(&mut ctx.func, &mut builder_ctx);
@@ -76,47 +982,388 @@ fn main() -> io::Result<()> {
     func_builder.switch_to_block(entry_block);
     func_builder.seal_block(entry_block);
 
-    let mut codegen = CodeGen::new(&mut module, &mut func_builder, printf);
+    let mut codegen = CodeGen::new(backend, &mut func_builder);
+    codegen.print_clif = emit == Some(Emit::Clif);
+    codegen.print_asm = emit == Some(Emit::Asm);
+    codegen.exports = exports.clone();
+    codegen.file_names = reporter.sources.iter().map(|source| source.name.clone()).collect();
 
-    for node in ast {
-        codegen.gen_ast(&node);
+    let codegen_start = Measure::start();
+    if let Err(err) = codegen.gen_program(program) {
+        reporter.report(&err)?;
+        reporter.finish();
+        process::exit(EXIT_INTERNAL_ERROR);
     }
+    reporter.finish();
+    let function_times = std::mem::take(&mut codegen.function_times);
 
     let zero = codegen.builder.ins().iconst(types::I32, 0);
     codegen.builder.ins().return_(&[zero]);
+    let asm_blocks = std::mem::take(&mut codegen.asm_blocks);
+    func_builder.finalize();
 
-    module.define_function(main_id, &mut ctx).unwrap();
-    module.finalize_definitions();
+    if emit == Some(Emit::Clif) {
+        println!("{}", ctx.func.display());
+        process::exit(0);
+    }
+    ctx.set_disasm(emit == Some(Emit::Asm));
+    if let Err(err) = backend.emit_function(main_id, &mut ctx, &program.main.locals) {
+        reporter.report(&err)?;
+        process::exit(EXIT_INTERNAL_ERROR);
+    }
+    timings.add(codegen_start.finish("codegen"));
+    timings.functions = function_times;
+    if emit == Some(Emit::Asm) {
+        println!("main:\n{}", ctx.compiled_code().and_then(|code| code.vcode.as_deref()).unwrap_or_default());
+        process::exit(0);
+    }
+    Ok((main_id, asm_blocks))
+}
 
-    // Write object file
-    let obj_bytes = module.object.write().unwrap();
-    let project_dir = Path::new(&file).parent().unwrap_or(Path::new("."));
-    let nula_dir = project_dir.join("nula");
-    let bin_dir = nula_dir.join("bin");
-    fs::create_dir_all(&bin_dir)?;
-    let obj_path = bin_dir.join("nula_bin.o");
-    fs::write(&obj_path, obj_bytes)?;
+// The executable's main for --stack-size, running the program's on a thread
+// with a stack of `size` bytes
+fn gen_stack_main(backend: &mut dyn Backend, program_main: FuncId, size: u64) -> diagnostic::NulaResult<()> {
+    let module = backend.module();
+    let mut main_sig = module.make_signature();
+    main_sig.returns.push(AbiParam::new(types::I32));
+    let main_id = module.declare_function("main", Linkage::Export, &main_sig)?;
+    let mut run_sig = module.make_signature();
+    run_sig.params.extend([AbiParam::new(types::I64), AbiParam::new(types::I64)]);
+    run_sig.returns.push(AbiParam::new(types::I32));
+    let run_id = module.declare_function("nula_run_main", Linkage::Import, &run_sig)?;
 
-    // Link to executable
-    let linker = match platform.as_str() {
-        "linux" => "gcc",
-        "windows" => "link.exe",
-        "macos" => "clang",
-        _ => unreachable!(),
+    let mut ctx = CodegenContext::new();
+    ctx.func.signature = main_sig;
+    let mut builder_ctx = FunctionBuilderContext::new();
+    let mut builder = FunctionBuilder::new(&mut ctx.func, &mut builder_ctx);
+    let block = builder.create_block();
+    builder.switch_to_block(block);
+    builder.seal_block(block);
+    let program_ref = module.declare_func_in_func(program_main, builder.func);
+    let run_ref = module.declare_func_in_func(run_id, builder.func);
+    let program_addr = builder.ins().func_addr(types::I64, program_ref);
+    let size = builder.ins().iconst(types::I64, size as i64);
+    let call = builder.ins().call(run_ref, &[program_addr, size]);
+    let code = builder.inst_results(call)[0];
+    builder.ins().return_(&[code]);
+    builder.finalize();
+    backend.emit_function(main_id, &mut ctx, &[])
+}
+
+// Wall time and allocations of each phase of a compilation, for --timings
+// and --self-profile
+#[derive(Default)]
+struct Timings {
+    phases: Vec<Sample>,    // in the order they first ran; files add up
+    events: Vec<Sample>,    // every run of a phase, for a trace
+    functions: Vec<Sample>, // share of codegen spent on each function
+}
+
+impl Timings {
+    fn time<T>(&mut self, phase: &'static str, f: impl FnOnce() -> T) -> T {
+        let measure = Measure::start();
+        let result = f();
+        self.add(measure.finish(phase));
+        result
+    }
+
+    fn add(&mut self, sample: Sample) {
+        match self.phases.iter_mut().find(|phase| phase.name == sample.name) {
+            Some(total) => {
+                total.elapsed += sample.elapsed;
+                total.allocations += sample.allocations;
+                total.bytes += sample.bytes;
+            }
+            None => self.phases.push(sample.clone()),
+        }
+        self.events.push(sample);
+    }
+
+    // Once the compilation is done, with what the arguments asked for
+    fn finish(&self, args: &CheckArgs) -> io::Result<()> {
+        if args.timings {
+            self.print();
+        }
+        if let Some(path) = &args.self_profile {
+            let profile = if path.extension().is_some_and(|ext| ext == "json") {
+                profile::chrome_trace(&self.events, &self.functions)
+            } else {
+                profile::summary(&self.phases, &self.functions)
+            };
+            fs::write(path, profile).map_err(|err| at_path(path, err))?;
+        }
+        Ok(())
+    }
+
+    // To stderr, so it doesn't mix with --emit output or a SARIF log
+    fn print(&self) {
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+        for phase in &self.phases {
+            eprintln!("{:>16} {:>10.3} ms", phase.name, ms(phase.elapsed));
+            if phase.name == "codegen" {
+                for function in &self.functions {
+                    eprintln!("{:>16} {:>10.3} ms", format!("fn {}", function.name), ms(function.elapsed));
+                }
+            }
+        }
+        let total: Duration = self.phases.iter().map(|phase| phase.elapsed).sum();
+        eprintln!("{:>16} {:>10.3} ms", "total", ms(total));
+    }
+}
+
+// Removes what the failed link would have built on and exits
+fn link_failed(artifacts: &[&Path], message: String) -> ! {
+    eprintln!("{}", message);
+    for path in artifacts {
+        let _ = fs::remove_file(path);
+    }
+    process::exit(EXIT_LINK_ERROR);
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OptLevel {
+    /// No optimizations, for debugging the compiler's output
+    #[value(name = "0")]
+    O0,
+    /// The compiler's own passes only
+    #[value(name = "1")]
+    O1,
+    /// Also optimize in Cranelift
+    #[value(name = "2")]
+    O2,
+    /// Like 2, but keep code small
+    #[value(name = "s")]
+    Os,
+}
+
+impl OptLevel {
+    // Constant folding, compile-time evaluation of pure calls and dead function elimination
+    fn run_passes(self) -> bool {
+        self != OptLevel::O0
+    }
+
+    fn cranelift_opt_level(self) -> &'static str {
+        match self {
+            OptLevel::O0 | OptLevel::O1 => "none",
+            OptLevel::O2 => "speed",
+            OptLevel::Os => "speed_and_size",
+        }
+    }
+
+    // Checking the generated IR costs compile time, so optimized builds skip it
+    fn verify(self) -> bool {
+        matches!(self, OptLevel::O0 | OptLevel::O1)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum, Default)]
+enum MessageFormat {
+    #[default]
+    Human,
+    Json,
+    Sarif,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum, Default)]
+enum ColorChoice {
+    /// When stderr is a terminal, unless NO_COLOR or CLICOLOR=0 is set (or CLICOLOR_FORCE is)
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    fn enabled(self) -> bool {
+        // Unset and empty variables count as off, like NO_COLOR asks
+        let on = |var| env::var_os(var).is_some_and(|value| !value.is_empty() && value != "0");
+        let off = |var| env::var_os(var).is_some_and(|value| value == "0");
+        match self {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto if env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty()) => false,
+            ColorChoice::Auto if on("CLICOLOR_FORCE") => true,
+            ColorChoice::Auto if off("CLICOLOR") => false,
+            ColorChoice::Auto => io::stderr().is_terminal(),
+        }
+    }
+}
+
+// Prints diagnostics to stderr as they come, or collects them into a SARIF log
+struct Reporter<'a> {
+    format: MessageFormat,
+    color: bool,
+    sources: &'a [Source],
+    sarif_results: Vec<diagnostic::Diagnostic>,
+}
+
+impl Reporter<'_> {
+    fn report(&mut self, diagnostic: &diagnostic::Diagnostic) -> io::Result<()> {
+        // Diagnostics without a span come from the program as a whole; show them against the first file
+        let source = &self.sources[diagnostic.span.map_or(0, |span| span.file)];
+        match self.format {
+            MessageFormat::Human => diagnostic.eprint(&source.name, &source.code, self.color)?,
+            MessageFormat::Json => eprintln!("{}", diagnostic.to_json(&source.name)),
+            MessageFormat::Sarif => self.sarif_results.push(diagnostic.clone()),
+        }
+        Ok(())
+    }
+
+    // Called once no more diagnostics can be reported; writes the SARIF log to stdout
+    fn finish(&mut self) {
+        if self.format == MessageFormat::Sarif {
+            let files: Vec<&str> = self.sources.iter().map(|source| source.name.as_str()).collect();
+            println!("{}", diagnostic::to_sarif(&self.sarif_results, &files));
+        }
+    }
+}
+
+// What to install when the platform's linker is missing. The runtime is C
+// compiled at link time, so a C toolchain is needed either way; nula jit
+// needs neither.
+fn toolchain_hint(platform: &str, static_link: bool) -> &'static str {
+    match platform {
+        "linux" if static_link => "Install musl-gcc (the musl-tools package on Debian and Ubuntu) or pass another with --linker.",
+        "linux" => "Install gcc (the build-essential package on Debian and Ubuntu) or pass another with --linker; nula jit runs programs without one.",
+        "windows" => "Install the Visual Studio Build Tools with the C++ workload, or LLVM for lld-link and clang-cl, or use --platform windows-gnu with MinGW-w64.",
+        "windows-gnu" => "Install MinGW-w64 or pass another linker with --linker.",
+        "macos" => "Install the Command Line Tools with `xcode-select --install`; nula jit runs programs without them.",
+        _ => "Pass one with --linker.",
+    }
+}
+
+// The MSVC linker with the environment (PATH, LIB) a Developer Command Prompt
+// sets up, found through vswhere and the registry like the cc crate finds it,
+// so builds work from a plain terminal. Else lld-link or link.exe from PATH.
+fn msvc_linker(arch: &str) -> (String, Command) {
+    if let Some(command) = cc::windows_registry::find(arch, "link.exe") {
+        return ("link.exe".to_string(), command);
+    }
+    let linker = if on_path("lld-link") { "lld-link" } else { "link.exe" };
+    (linker.to_string(), Command::new(linker))
+}
+
+// C compiler flags for the runtime
+fn runtime_flags(platform: &str, arch: &str, release: bool, debug: bool, lib: bool, sysroot: Option<&Path>) -> Vec<String> {
+    if platform == "windows" {
+        // /Gy lets /OPT:REF drop the functions nothing calls
+        return if release { vec!["/O1".to_string(), "/Gy".to_string()] } else { vec!["/O2".to_string()] };
+    }
+    let mut flags: Vec<String> = if release {
+        ["-Os", "-ffunction-sections", "-fdata-sections"].map(String::from).to_vec()
+    } else {
+        vec!["-O2".to_string()]
     };
-    let exe_path = bin_dir.join(if platform == "windows" { "nula_bin.exe" } else { "nula_bin" });
-    let status = Command::new(linker)
-        .arg(obj_path.to_str().unwrap())
-        .arg("-o")
-        .arg(exe_path.to_str().unwrap())
-        .arg(if platform == "linux" { "-lc" } else { "" })
-        .status()?;
+    flags.extend(arch_flags(platform, arch).into_iter().map(String::from));
+    if debug {
+        flags.push("-g".to_string());
+    }
+    if lib {
+        flags.push("-fPIC".to_string());
+    }
+    if let Some(sysroot) = sysroot {
+        flags.push(format!("--sysroot={}", sysroot.display()));
+    }
+    flags
+}
 
-    if !status.success() {
-        eprintln!("Linking failed");
-        process::exit(1);
+// Compiles the runtime with a gcc or clang driver. The object only appears
+// once it's complete, so an interrupted build can't leave a broken one to reuse.
+fn compile_runtime(compiler: &str, rt_path: &Path, rt_obj: &Path, flags: &[String]) -> io::Result<ExitStatus> {
+    let partial = rt_obj.with_extension("partial");
+    let status = Command::new(compiler).args(flags).arg("-c").arg(rt_path).arg("-o").arg(&partial).status()?;
+    if status.success() {
+        fs::rename(&partial, rt_obj)?;
     }
+    Ok(status)
+}
 
-    println!("Compiled to {:?}", exe_path);
-    Ok(())
+// Compiles the runtime with cl.exe, or clang-cl if Visual Studio isn't installed
+fn compile_runtime_msvc(rt_path: &Path, rt_obj: &Path, arch: &str, flags: &[String], artifacts: &[&Path]) -> io::Result<ExitStatus> {
+    let partial = rt_obj.with_extension("partial");
+    let (compiler, mut command) = match cc::windows_registry::find(arch, "cl.exe") {
+        Some(command) => ("cl.exe", command),
+        None => ("clang-cl", Command::new("clang-cl")),
+    };
+    let status = command.args(["/nologo", "/c"]).args(flags).arg(rt_path).arg(format!("/Fo{}", partial.display())).status();
+    match status {
+        Ok(status) if status.success() => {
+            fs::rename(&partial, rt_obj)?;
+            Ok(status)
+        }
+        Ok(_) => link_failed(artifacts, "Compiling the runtime failed".to_string()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => link_failed(
+            artifacts,
+            format!("Couldn't find cl.exe or clang-cl to compile the runtime. {}", toolchain_hint("windows", false)),
+        ),
+        Err(err) => link_failed(artifacts, format!("Couldn't run {}: {}", compiler, err)),
+    }
+}
+
+// Whether `program` is in one of the PATH directories
+fn on_path(program: &str) -> bool {
+    let file = format!("{}{}", program, env::consts::EXE_SUFFIX);
+    env::var_os("PATH").is_some_and(|paths| env::split_paths(&paths).any(|dir| dir.join(&file).is_file()))
+}
+
+// Linker driver flags for --release (unused sections removed) and --strip
+// (no symbol table or debug info)
+fn size_flags(platform: &str, release: bool, strip: bool) -> Vec<&'static str> {
+    let mut flags = Vec::new();
+    if release {
+        flags.push(match platform {
+            "windows" => "/OPT:REF,ICF",
+            "macos" => "-Wl,-dead_strip",
+            _ => "-Wl,--gc-sections",
+        });
+    }
+    if strip {
+        flags.push(match platform {
+            "windows" => "/DEBUG:NONE",
+            "macos" => "-Wl,-S,-x",
+            _ => "-s",
+        });
+    }
+    flags
+}
+
+// Linker flags for --lib: a shared library exporting the @export-ed functions
+fn lib_flags(platform: &str, exports: &HashSet<String>) -> Vec<String> {
+    match platform {
+        "windows" => {
+            let mut flags = vec!["/DLL".to_string()];
+            let mut names: Vec<&String> = exports.iter().collect();
+            names.sort();
+            flags.extend(names.into_iter().map(|name| format!("/EXPORT:{}", name)));
+            flags
+        }
+        "macos" => vec!["-dynamiclib".to_string()],
+        // The runtime is compiled by the same driver, so it needs -fPIC too
+        _ => vec!["-shared".to_string(), "-fPIC".to_string()],
+    }
+}
+
+// Linker driver flags that pick the processor, where one driver handles several
+fn arch_flags(platform: &str, arch: &str) -> Vec<&'static str> {
+    match (platform, arch) {
+        ("macos", "aarch64") => vec!["-arch", "arm64"],
+        ("macos", _) => vec!["-arch", "x86_64"],
+        _ => Vec::new(),
+    }
+}
+
+// Wraps each asm("...") block in a function the generated code calls
+fn asm_source(blocks: &[String], platform: &str) -> String {
+    // Mach-O symbols carry a leading underscore
+    let prefix = if platform == "macos" { "_" } else { "" };
+    let mut out = String::from("    .text\n");
+    for (i, block) in blocks.iter().enumerate() {
+        let symbol = format!("{}__nula_asm_{}", prefix, i);
+        out.push_str(&format!("    .globl {}\n{}:\n", symbol, symbol));
+        for line in block.lines() {
+            out.push_str(&format!("    {}\n", line.trim()));
+        }
+        out.push_str("    ret\n");
+    }
+    out
 }
@@ -0,0 +1,118 @@
+// src/manifest.rs - nula.toml project manifests
+//
+// A project directory with a nula.toml builds with a bare `nula build`:
+//
+//     [package]
+//     name = "hello"              # names the executable
+//     entry = "main.nula"         # the default
+//
+//     [build]
+//     platforms = ["linux", "windows"]
+//     output = "hello-cli"        # instead of the package name
+//     link = ["m"]                # like --link
+//     linker-flags = ["-static"]  # passed to the linker as they are
+//     dir = "build"               # build output, instead of nula/bin next to the entry
+//     stack-size = "64M"          # like --stack-size
+//
+//     [target.windows-gnu]        # a platform, or a --target triple
+//     linker = "x86_64-w64-mingw32-gcc"  # like --linker
+//     sysroot = "/usr/x86_64-w64-mingw32"  # like --sysroot
+//
+//     [dependencies]
+//     utils = { path = "../utils" }  # another project, compiled in with its own dependencies
+
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+pub const FILE_NAME: &str = "nula.toml";
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    pub package: Package,
+    #[serde(default)]
+    pub build: Build,
+    #[serde(default)]
+    pub target: BTreeMap<String, Target>,
+    #[serde(default)]
+    pub dependencies: BTreeMap<String, Dependency>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Package {
+    pub name: String,
+    #[serde(default = "default_entry")]
+    pub entry: String,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Build {
+    #[serde(default)]
+    pub platforms: Vec<String>,
+    pub output: Option<String>,
+    #[serde(default)]
+    pub link: Vec<String>,
+    #[serde(default)]
+    pub linker_flags: Vec<String>,
+    pub dir: Option<String>,
+    pub stack_size: Option<String>,
+}
+
+// How to link for one platform or triple, usually another system's
+#[derive(Deserialize, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Target {
+    pub linker: Option<String>,
+    pub sysroot: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Dependency {
+    pub path: String, // directory with its own nula.toml, relative to this one
+}
+
+fn default_entry() -> String {
+    "main.nula".to_string()
+}
+
+// Manifest for `nula new`
+pub fn new_project(name: &str) -> String {
+    format!("[package]\nname = {:?}\nentry = \"src/main.nula\"\n", name)
+}
+
+pub fn load(dir: &Path) -> Result<Manifest, String> {
+    let path = dir.join(FILE_NAME);
+    let text = fs::read_to_string(&path).map_err(|err| format!("{}: {}", path.display(), err))?;
+    toml::from_str(&text).map_err(|err| format!("{}: {}", path.display(), err))
+}
+
+impl Manifest {
+    // Source files of the project in `dir`: its entry first, then those of its
+    // dependencies, each project only once
+    pub fn source_files(&self, dir: &Path) -> Result<Vec<PathBuf>, String> {
+        let mut files = Vec::new();
+        let mut seen = HashSet::new();
+        self.collect_files(dir, &mut files, &mut seen)?;
+        Ok(files)
+    }
+
+    fn collect_files(&self, dir: &Path, files: &mut Vec<PathBuf>, seen: &mut HashSet<PathBuf>) -> Result<(), String> {
+        let canonical = dir.canonicalize().map_err(|err| format!("{}: {}", dir.display(), err))?;
+        if !seen.insert(canonical) {
+            return Ok(());
+        }
+        files.push(dir.join(&self.package.entry));
+        for (name, dependency) in &self.dependencies {
+            let dep_dir = dir.join(&dependency.path);
+            let manifest = load(&dep_dir).map_err(|err| format!("dependency `{}`: {}", name, err))?;
+            manifest.collect_files(&dep_dir, files, seen)?;
+        }
+        Ok(())
+    }
+}
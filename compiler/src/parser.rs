@@ -1,337 +1,603 @@
 // src/parser.rs - Parser implementation
 
-use crate::ast::Ast;
-
-#[derive(Debug, Clone)]
-enum Token {
-    Ident(String),
-    Number(f64),
-    StringLit(String),
-    Operator(String),
-    Keyword(String),
-    Symbol(String),
-    Eof,
-}
+use crate::ast::{Ast, Node, Span};
+use crate::diagnostic::{Diagnostic, NulaResult};
+use crate::lexer::{Lexer, Token};
+
+// Binary operators from loosest to tightest binding
+const INFIX_OPERATORS: [(&str, u8); 16] = [
+    ("||", 1),
+    ("&&", 2),
+    ("==", 3),
+    ("!=", 3),
+    ("<", 4),
+    (">", 4),
+    ("<=", 4),
+    (">=", 4),
+    ("in", 5),
+    ("<<", 6),
+    (">>", 6),
+    ("+", 7),
+    ("-", 7),
+    ("*", 8),
+    ("/", 8),
+    ("^", 10),
+];
+const RIGHT_ASSOCIATIVE: [&str; 1] = ["^"];
+const UNARY_POWER: u8 = 9;
+
+// Keywords that start a statement, where parsing resumes after an error
+const STATEMENT_KEYWORDS: [&str; 8] = ["var", "fn", "async", "if", "while", "for", "write", "return"];
 
-pub struct Parser {
-    tokens: Vec<Token>,
-    pos: usize,
+pub struct Parser<'a> {
+    lexer: Lexer<'a>, // tokens are lexed as the parser gets to them
+    token: (Token<'a>, Span),         // the next one
+    ahead: Option<(Token<'a>, Span)>, // and the one after it, once looked at
+    consumed: usize,                  // tokens consumed so far
+    prev: Span, // span of the last consumed token
+    errors: Vec<Diagnostic>,
+    nesting: usize, // open ( and [ around the current token, inside which newlines don't end anything
 }
 
-impl Parser {
-    pub fn new(code: &str) -> Self {
-        // Tokenization logic (expanded)
-        let mut tokens = Vec::new();
-        let mut chars = code.chars().peekable();
-        while chars.peek().is_some() {
-            let ch = *chars.peek().unwrap();
-            match ch {
-                ' ' | '\t' | '\n' | '\r' => { chars.next(); continue; }
-                'a'..='z' | 'A'..='Z' | '_' => {
-                    let mut id = String::new();
-                    while let Some(&c) = chars.peek() {
-                        if c.is_alphanumeric() || c == '_' {
-                            id.push(c);
-                            chars.next();
-                        } else {
-                            break;
-                        }
-                    }
-                    if ["if", "else", "while", "for", "fn", "var", "write"].contains(&id.as_str()) {
-                        tokens.push(Token::Keyword(id));
-                    } else {
-                        tokens.push(Token::Ident(id));
-                    }
-                }
-                '0'..='9' | '.' => {
-                    let mut num_str = String::new();
-                    let mut has_dot = false;
-                    while let Some(&c) = chars.peek() {
-                        if c.is_digit(10) {
-                            num_str.push(c);
-                            chars.next();
-                        } else if c == '.' && !has_dot {
-                            num_str.push(c);
-                            has_dot = true;
-                            chars.next();
-                        } else {
-                            break;
-                        }
-                    }
-                    tokens.push(Token::Number(num_str.parse().unwrap_or(0.0)));
-                }
-                '"' => {
-                    chars.next();
-                    let mut s = String::new();
-                    while let Some(&c) = chars.peek() {
-                        if c == '"' {
-                            chars.next();
-                            break;
-                        }
-                        s.push(c);
-                        chars.next();
-                    }
-                    tokens.push(Token::StringLit(s));
-                }
-                '+' | '-' | '*' | '/' | '^' | '=' | '<' | '>' | '!' | '&' | '|' | '[' | ']' | '(' | ')' | '{' | '}' | ':' | ';' | ',' => {
-                    let op = chars.next().unwrap().to_string();
-                    tokens.push(if "+-*/^=<>!&|".contains(&op) { Token::Operator(op) } else { Token::Symbol(op) });
-                }
-                '@' => {
-                    // Single line comment
-                    chars.next();
-                    while let Some(&c) = chars.peek() {
-                        if c == '\n' { break; }
-                        chars.next();
-                    }
-                }
-                '!' => {
-                    // Multi line comment
-                    chars.next();
-                    let mut depth = 1;
-                    while depth > 0 && chars.peek().is_some() {
-                        let c = chars.next().unwrap();
-                        if c == '!' { depth -= 1; }
-                    }
-                }
-                _ => { chars.next(); } // Ignore unknown
-            }
-        }
-        tokens.push(Token::Eof);
-        Parser { tokens, pos: 0 }
+impl<'a> Parser<'a> {
+    // `file` is the index spans point into, see Span
+    pub fn new(code: &'a str, file: usize) -> Self {
+        let mut lexer = Lexer::new(code, file);
+        let token = lexer.next().expect("the lexer ends with Eof");
+        Parser { lexer, token, ahead: None, consumed: 0, prev: Span::default(), errors: Vec::new(), nesting: 0 }
     }
 
-    pub fn parse(&mut self) -> Vec<Ast> {
+    // Parses the whole program, or returns every syntax error found in it
+    pub fn parse(&mut self) -> Result<Vec<Node>, Vec<Diagnostic>> {
         let mut stmts = Vec::new();
-        while self.pos < self.tokens.len() - 1 {
-            stmts.push(self.parse_stmt());
+        loop {
+            stmts.extend(self.parse_block());
+            if matches!(self.peek(), Token::Eof) {
+                break;
+            }
+            // parse_block stopped at a } with no block open
+            let err = self.error("Unexpected symbol }".to_string());
+            self.report(err);
+            self.next();
+        }
+        let mut errors = std::mem::take(&mut self.lexer.errors);
+        errors.append(&mut self.errors);
+        if errors.is_empty() {
+            Ok(stmts)
+        } else {
+            Err(errors)
         }
-        stmts
     }
 
-    fn parse_stmt(&mut self) -> Ast {
-        match &self.peek() {
-            Token::Keyword(k) if k == "var" => self.parse_var_decl(),
-            Token::Keyword(k) if k == "fn" => self.parse_func_def(),
-            Token::Keyword(k) if k == "if" => self.parse_if(),
-            Token::Keyword(k) if k == "while" => self.parse_while(),
-            Token::Keyword(k) if k == "for" => self.parse_for(),
-            Token::Keyword(k) if k == "write" => self.parse_write(),
+    fn parse_stmt(&mut self) -> NulaResult<Node> {
+        match self.peek() {
+            Token::Keyword("var") => self.parse_var_decl(),
+            Token::Keyword("fn") => self.parse_func_def(),
+            Token::Keyword("async") => self.parse_async_func_def(),
+            Token::Keyword("if") => self.parse_if(),
+            Token::Keyword("while") => self.parse_while(),
+            Token::Keyword("for") => self.parse_for(),
+            Token::Keyword("write") => self.parse_write(),
+            Token::Keyword("return") => self.parse_return(),
+            Token::Directive("link") => self.parse_link(),
+            Token::Directive("export") => self.parse_export(),
             Token::Ident(_) => self.parse_assign_or_call(),
             _ => self.parse_expr(),
         }
     }
 
-    fn parse_var_decl(&mut self) -> Ast {
+    fn parse_var_decl(&mut self) -> NulaResult<Node> {
+        let start = self.span();
         self.next(); // var
-        let name = if let Token::Ident(n) = self.next() { n } else { panic!("Expected ident"); };
-        if let Token::Operator(op) = self.peek() {
-            if op == "=" {
-                self.next();
-                let value = self.parse_expr();
-                Ast::VarDecl(name, Box::new(value))
-            } else {
-                panic!("Expected =");
-            }
-        } else {
-            panic!("Expected =");
-        }
+        let name = self.expect_ident("variable name")?;
+        self.expect_operator("=")?;
+        let value = self.parse_expr()?;
+        Ok(self.node(start, Ast::VarDecl(name, Box::new(value))))
     }
 
-    fn parse_assign_or_call(&mut self) -> Ast {
-        let name = if let Token::Ident(n) = self.next() { n } else { unreachable!() };
-        match self.peek() {
-            Token::Symbol(s) if s == "(" => Ast::FuncCall(name, self.parse_args()),
-            Token::Operator(op) if op == "=" => {
-                self.next();
-                Ast::Assign(name, Box::new(self.parse_expr()))
-            }
-            Token::Symbol(s) if s == "[" => {
-                self.next();
-                let index = self.parse_expr();
-                self.expect_symbol("]");
-                Ast::Index(name, Box::new(index))
-            }
-            _ => Ast::Var(name),
+    fn parse_assign_or_call(&mut self) -> NulaResult<Node> {
+        let start = self.span();
+        let assigns = matches!(self.peek_second(), Token::Operator("=" | "+="));
+        if !assigns {
+            return self.parse_expr();
         }
+        let name = self.expect_ident("variable name")?;
+        let ast = if self.next_operator()? == "=" {
+            Ast::Assign(name, Box::new(self.parse_expr()?))
+        } else {
+            // x += e is x = x + e
+            let target = Node { ast: Ast::Var(name.clone()), span: start };
+            Ast::Assign(name, Box::new(bin_op("+".to_string(), target, self.parse_expr()?)))
+        };
+        Ok(self.node(start, ast))
     }
 
-    fn parse_func_def(&mut self) -> Ast {
+    fn parse_func_def(&mut self) -> NulaResult<Node> {
+        let start = self.span();
         self.next(); // fn
-        let name = if let Token::Ident(n) = self.next() { n } else { panic!("Expected func name"); };
-        self.expect_symbol("(");
-        let mut params = Vec::new();
-        while !matches!(&self.peek(), Token::Symbol(s) if s == ")") {
-            if let Token::Ident(p) = self.next() { params.push(p); }
-            if matches!(&self.peek(), Token::Symbol(s) if s == ",") { self.next(); }
-        }
-        self.expect_symbol(")");
-        self.expect_symbol("{");
+        let name = self.expect_ident("function name")?;
+        self.expect_symbol("(")?;
+        let params = self.parse_list(")", |p| p.expect_ident("parameter name"))?;
+        self.expect_symbol("{")?;
         let body = self.parse_block();
-        self.expect_symbol("}");
-        Ast::FuncDef(name, params, body)
+        self.expect_symbol("}")?;
+        Ok(self.node(start, Ast::FuncDef(name, params, body)))
     }
 
-    fn parse_if(&mut self) -> Ast {
+    fn parse_async_func_def(&mut self) -> NulaResult<Node> {
+        let start = self.span();
+        self.next(); // async
+        if !matches!(self.peek(), Token::Keyword("fn")) {
+            return Err(self.error(format!("Expected fn after async, got {:?}", self.peek())));
+        }
+        match self.parse_func_def()?.ast {
+            Ast::FuncDef(name, params, body) => Ok(self.node(start, Ast::AsyncFuncDef(name, params, body))),
+            _ => unreachable!(),
+        }
+    }
+
+    fn parse_if(&mut self) -> NulaResult<Node> {
+        let start = self.span();
         self.next(); // if
-        let cond = self.parse_expr();
-        self.expect_symbol("{");
+        let cond = self.parse_expr()?;
+        self.expect_symbol("{")?;
         let then = self.parse_block();
-        self.expect_symbol("}");
-        let els = if matches!(&self.peek(), Token::Keyword(k) if k == "else") {
+        self.expect_symbol("}")?;
+        let els = if matches!(self.peek(), Token::Keyword("else")) {
             self.next();
-            self.expect_symbol("{");
+            self.expect_symbol("{")?;
             let e = self.parse_block();
-            self.expect_symbol("}");
+            self.expect_symbol("}")?;
             Some(e)
         } else {
             None
         };
-        Ast::If(Box::new(cond), then, els)
+        Ok(self.node(start, Ast::If(Box::new(cond), then, els)))
     }
 
-    fn parse_while(&mut self) -> Ast {
+    fn parse_while(&mut self) -> NulaResult<Node> {
+        let start = self.span();
         self.next(); // while
-        let cond = self.parse_expr();
-        self.expect_symbol("{");
+        let cond = self.parse_expr()?;
+        self.expect_symbol("{")?;
         let body = self.parse_block();
-        self.expect_symbol("}");
-        Ast::While(Box::new(cond), body)
+        self.expect_symbol("}")?;
+        Ok(self.node(start, Ast::While(Box::new(cond), body)))
     }
 
-    fn parse_for(&mut self) -> Ast {
+    fn parse_for(&mut self) -> NulaResult<Node> {
+        let start = self.span();
         self.next(); // for
-        let var = if let Token::Ident(v) = self.next() { v } else { panic!("Expected var"); };
-        self.expect_keyword("in");
-        let start = self.parse_expr();
-        self.expect_operator("..");
-        let end = self.parse_expr();
-        self.expect_symbol("{");
+        let var = self.expect_ident("loop variable")?;
+        if matches!(self.peek(), Token::Symbol(",")) {
+            // for i, x in arr
+            self.next();
+            let elem = self.expect_ident("loop variable")?;
+            self.expect_keyword("in")?;
+            let array = self.parse_expr()?;
+            self.expect_symbol("{")?;
+            let body = self.parse_block();
+            self.expect_symbol("}")?;
+            return Ok(self.node(start, Ast::ForEach(Some(var), elem, Box::new(array), body)));
+        }
+        self.expect_keyword("in")?;
+        let from = self.parse_expr()?;
+        if !matches!(self.peek(), Token::Operator("..")) {
+            // for x in arr
+            self.expect_symbol("{")?;
+            let body = self.parse_block();
+            self.expect_symbol("}")?;
+            return Ok(self.node(start, Ast::ForEach(None, var, Box::new(from), body)));
+        }
+        self.expect_operator("..")?;
+        let to = self.parse_expr()?;
+        self.expect_symbol("{")?;
         let body = self.parse_block();
-        self.expect_symbol("}");
-        Ast::For(var, Box::new(start), Box::new(end), body)
+        self.expect_symbol("}")?;
+        Ok(self.node(start, Ast::For(var, Box::new(from), Box::new(to), body)))
     }
 
-    fn parse_write(&mut self) -> Ast {
+    fn parse_write(&mut self) -> NulaResult<Node> {
+        let start = self.span();
         self.next(); // write
-        Ast::FuncCall("write".to_string(), vec![self.parse_expr()])
+        let arg = self.parse_expr()?;
+        Ok(self.node(start, Ast::FuncCall("write".to_string(), vec![arg])))
+    }
+
+    fn parse_return(&mut self) -> NulaResult<Node> {
+        let start = self.span();
+        self.next(); // return
+        // The value has to start on the same line: `return` alone on a line returns 0
+        let value = if self.at_statement_end() {
+            self.node(start, Ast::Literal(0.0))
+        } else {
+            self.parse_expr()?
+        };
+        Ok(self.node(start, Ast::Return(Box::new(value))))
+    }
+
+    fn parse_link(&mut self) -> NulaResult<Node> {
+        let start = self.span();
+        self.next(); // @link
+        match self.peek() {
+            Token::StringLit(lib) => {
+                self.next();
+                Ok(self.node(start, Ast::Link(lib.to_string())))
+            }
+            tok => Err(self.error(format!("Expected library name after @link, got {:?}", tok))),
+        }
+    }
+
+    fn parse_export(&mut self) -> NulaResult<Node> {
+        let start = self.span();
+        self.next(); // @export
+        let name = self.expect_ident("function name after @export")?;
+        Ok(self.node(start, Ast::Export(name)))
     }
 
-    fn parse_block(&mut self) -> Vec<Ast> {
+    // Statements up to the closing } or the end of the file. A statement that
+    // fails to parse is recorded and skipped so the errors after it are found too.
+    fn parse_block(&mut self) -> Vec<Node> {
         let mut block = Vec::new();
-        while !matches!(&self.peek(), Token::Symbol(s) if s == "}") && !matches!(&self.peek(), Token::Eof) {
-            block.push(self.parse_stmt());
+        while !matches!(self.peek(), Token::Symbol("}")) && !matches!(self.peek(), Token::Eof) {
+            let start = self.consumed;
+            match self.parse_stmt().and_then(|stmt| self.end_statement().map(|()| stmt)) {
+                Ok(stmt) => block.push(stmt),
+                Err(err) => {
+                    self.report(err);
+                    self.synchronize();
+                    if self.consumed == start {
+                        self.next();
+                    }
+                }
+            }
         }
         block
     }
 
-    fn parse_expr(&mut self) -> Ast {
-        self.parse_add()
+    // A statement ends at a `;`, the end of its line, or the } closing its block
+    fn end_statement(&mut self) -> NulaResult<()> {
+        if self.at_statement_end() {
+            while matches!(self.peek(), Token::Symbol(";")) {
+                self.next();
+            }
+            Ok(())
+        } else {
+            Err(self.error(format!("Expected ; or a new line after the statement, got {:?}", self.peek())))
+        }
+    }
+
+    fn at_statement_end(&self) -> bool {
+        matches!(self.peek(), Token::Symbol(";" | "}"))
+            || matches!(self.peek(), Token::Eof)
+            || self.at_line_start()
     }
 
-    fn parse_add(&mut self) -> Ast {
-        let mut left = self.parse_mul();
-        while matches!(&self.peek(), Token::Operator(op) if ["+", "-"].contains(&op.as_str())) {
-            let op = self.next_operator();
-            let right = self.parse_mul();
-            left = Ast::BinOp(op, Box::new(left), Box::new(right));
+    // Whether the next token starts a new line outside of any ( or [
+    fn at_line_start(&self) -> bool {
+        self.nesting == 0 && self.span().line > self.prev.line
+    }
+
+    fn nested<T>(&mut self, parse: impl FnOnce(&mut Self) -> NulaResult<T>) -> NulaResult<T> {
+        self.nesting += 1;
+        let result = parse(self);
+        self.nesting -= 1;
+        result
+    }
+
+    // Skips the rest of a broken statement: stops after a ; or before the next
+    // line, statement keyword or the } closing the current block, passing over
+    // nested blocks
+    fn synchronize(&mut self) {
+        let mut depth = 0;
+        loop {
+            if depth == 0 && self.at_line_start() {
+                return;
+            }
+            match self.peek() {
+                Token::Eof => return,
+                Token::Symbol(";") if depth == 0 => {
+                    self.next();
+                    return;
+                }
+                Token::Symbol("{") => depth += 1,
+                Token::Symbol("}") => {
+                    if depth == 0 {
+                        return;
+                    }
+                    depth -= 1;
+                }
+                Token::Keyword(k) if depth == 0 && STATEMENT_KEYWORDS.contains(&k) => return,
+                Token::Directive(_) if depth == 0 => return,
+                _ => {}
+            }
+            self.next();
         }
-        left
     }
 
-    fn parse_mul(&mut self) -> Ast {
-        let mut left = self.parse_pow();
-        while matches!(&self.peek(), Token::Operator(op) if ["*", "/"].contains(&op.as_str())) {
-            let op = self.next_operator();
-            let right = self.parse_pow();
-            left = Ast::BinOp(op, Box::new(left), Box::new(right));
+    fn report(&mut self, err: Diagnostic) {
+        // An unclosed block fails the same way at every level it is nested in
+        let repeated = self.errors.last().is_some_and(|last| last.message == err.message && last.span == err.span);
+        if !repeated {
+            self.errors.push(err);
         }
-        left
     }
 
-    fn parse_pow(&mut self) -> Ast {
-        let mut left = self.parse_primary();
-        while matches!(&self.peek(), Token::Operator(op) if op == "^") {
+    fn parse_expr(&mut self) -> NulaResult<Node> {
+        self.parse_binary(0)
+    }
+
+    // Precedence climbing: parses operators binding at least as tightly as
+    // min_power, so `a + b * c` groups as `a + (b * c)`
+    fn parse_binary(&mut self, min_power: u8) -> NulaResult<Node> {
+        let mut left = self.parse_unary()?;
+        while let Some((op, power)) = self.infix_operator() {
+            if power < min_power {
+                break;
+            }
+            if self.at_line_start() {
+                // `a\n- b` could be one expression or two statements
+                return Err(self.error(format!(
+                    "Operator {} at the start of a line; put it at the end of the previous line or wrap the expression in ( )",
+                    op
+                )));
+            }
             self.next();
-            let right = self.parse_primary();
-            left = Ast::BinOp("^".to_string(), Box::new(left), Box::new(right));
+            let right_power = if RIGHT_ASSOCIATIVE.contains(&op) { power } else { power + 1 };
+            let right = self.parse_binary(right_power)?;
+            left = bin_op(op.to_string(), left, right);
         }
-        left
+        Ok(left)
     }
 
-    fn parse_primary(&mut self) -> Ast {
-        match self.peek().clone() {
-            Token::Number(n) => { self.next(); Ast::Literal(n) }
-            Token::StringLit(s) => { self.next(); Ast::StrLit(s) }
-            Token::Ident(id) => self.parse_assign_or_call(),
-            Token::Symbol(s) if s == "(" => {
+    // Prefix - and ! bind tighter than any infix operator but ^, so -x^2 is -(x^2)
+    fn parse_unary(&mut self) -> NulaResult<Node> {
+        let start = self.span();
+        match self.peek() {
+            Token::Operator(op @ ("-" | "!")) => {
                 self.next();
-                let expr = self.parse_expr();
-                self.expect_symbol(")");
-                expr
+                let operand = self.parse_binary(UNARY_POWER)?;
+                // Lowered to the binary forms codegen already knows: 0 - x and x == 0
+                let zero = Node { ast: Ast::Literal(0.0), span: start };
+                let ast = if op == "-" {
+                    Ast::BinOp(op.to_string(), Box::new(zero), Box::new(operand))
+                } else {
+                    Ast::BinOp("==".to_string(), Box::new(operand), Box::new(zero))
+                };
+                Ok(self.node(start, ast))
             }
-            Token::Symbol(s) if s == "[" => self.parse_array(),
-            _ => panic!("Unexpected token: {:?}", self.peek()),
+            _ => self.parse_postfix(),
         }
     }
 
-    fn parse_array(&mut self) -> Ast {
-        self.next(); // [
-        let mut elements = Vec::new();
-        while !matches!(&self.peek(), Token::Symbol(s) if s == "]") {
-            elements.push(self.parse_expr());
-            if matches!(&self.peek(), Token::Symbol(s) if s == ",") { self.next(); }
+    // Calls, indexing and method calls after a primary expression, in any
+    // combination: m[i][j], make()(x), xs.sum().half()
+    fn parse_postfix(&mut self) -> NulaResult<Node> {
+        let start = self.span();
+        let mut expr = self.parse_primary()?;
+        // A ( or [ on the next line starts a new statement rather than a call or index
+        while !self.at_line_start() {
+            let ast = match self.peek() {
+                Token::Symbol("(") => {
+                    let args = self.parse_args()?;
+                    match expr.ast {
+                        Ast::Var(name) => Ast::FuncCall(name, args),
+                        callee => Ast::Call(Box::new(Node { ast: callee, span: expr.span }), args),
+                    }
+                }
+                Token::Symbol("[") => {
+                    self.next();
+                    let index = self.nested(|p| p.parse_expr())?;
+                    self.expect_symbol("]")?;
+                    Ast::Index(Box::new(expr), Box::new(index))
+                }
+                Token::Operator(".") => {
+                    // x.f(a) calls f(x, a)
+                    self.next();
+                    let name = self.expect_ident("method name")?;
+                    if !matches!(self.peek(), Token::Symbol("(")) {
+                        return Err(self.error(format!("Expected ( after .{}: values have no fields, only methods", name)));
+                    }
+                    let mut args = vec![expr];
+                    args.extend(self.parse_args()?);
+                    Ast::FuncCall(name, args)
+                }
+                _ => break,
+            };
+            expr = self.node(start, ast);
         }
-        self.expect_symbol("]");
-        Ast::Array(elements)
+        Ok(expr)
     }
 
-    fn parse_args(&mut self) -> Vec<Ast> {
-        self.expect_symbol("(");
-        let mut args = Vec::new();
-        while !matches!(&self.peek(), Token::Symbol(s) if s == ")") {
-            args.push(self.parse_expr());
-            if matches!(&self.peek(), Token::Symbol(s) if s == ",") { self.next(); }
+    // The infix operator at the current token and its binding power, if any
+    fn infix_operator(&self) -> Option<(&'static str, u8)> {
+        let op = match self.peek() {
+            Token::Operator(op) => op,
+            Token::Keyword("in") => "in",
+            _ => return None,
+        };
+        INFIX_OPERATORS.iter().find(|(o, _)| *o == op).copied()
+    }
+
+    fn parse_primary(&mut self) -> NulaResult<Node> {
+        let start = self.span();
+        match self.peek() {
+            Token::Number(n) => { self.next(); Ok(self.node(start, Ast::Literal(n))) }
+            Token::StringLit(s) => { self.next(); Ok(self.node(start, Ast::StrLit(s.to_string()))) }
+            Token::Ident(name) => {
+                self.next();
+                Ok(self.node(start, Ast::Var(name.to_string())))
+            }
+            Token::Symbol("(") => {
+                self.next();
+                let expr = self.nested(|p| p.parse_expr())?;
+                self.expect_symbol(")")?;
+                Ok(expr)
+            }
+            Token::Symbol("[") => self.parse_array(),
+            Token::Keyword("await") => {
+                self.next();
+                let task = self.parse_postfix()?;
+                Ok(self.node(start, Ast::Await(Box::new(task))))
+            }
+            tok => Err(self.error(format!("Unexpected token: {:?}", tok))),
         }
-        self.expect_symbol(")");
-        args
     }
 
-    fn next(&mut self) -> Token {
-        let tok = self.tokens[self.pos].clone();
-        self.pos += 1;
+    fn parse_array(&mut self) -> NulaResult<Node> {
+        let start = self.span();
+        self.next(); // [
+        let elements = self.parse_list("]", |p| p.nested(|p| p.parse_expr()))?;
+        Ok(self.node(start, Ast::Array(elements)))
+    }
+
+    fn parse_args(&mut self) -> NulaResult<Vec<Node>> {
+        self.expect_symbol("(")?;
+        self.parse_list(")", |p| p.nested(|p| p.parse_expr()))
+    }
+
+    // Comma-separated items up to and including `close`; a trailing comma is allowed
+    fn parse_list<T>(&mut self, close: &str, mut item: impl FnMut(&mut Self) -> NulaResult<T>) -> NulaResult<Vec<T>> {
+        let mut items = Vec::new();
+        while !matches!(self.peek(), Token::Symbol(s) if s == close) {
+            items.push(item(self)?);
+            if matches!(self.peek(), Token::Symbol(",")) {
+                self.next();
+            } else {
+                break;
+            }
+        }
+        self.expect_symbol(close)?;
+        Ok(items)
+    }
+
+    fn next(&mut self) -> Token<'a> {
+        let (tok, span) = self.token;
+        self.prev = span;
+        // Stay on Eof so a truncated program reports an error instead of running off the end
+        if !matches!(tok, Token::Eof) {
+            self.token = self.ahead.take().unwrap_or_else(|| self.lexer.next().expect("the lexer ends with Eof"));
+            self.consumed += 1;
+        }
         tok
     }
 
-    fn peek(&self) -> Token {
-        self.tokens[self.pos].clone()
+    fn peek(&self) -> Token<'a> {
+        self.token.0
     }
 
-    fn expect_symbol(&mut self, sym: &str) {
-        if matches!(&self.peek(), Token::Symbol(s) if s == sym) {
+    // The token after the next one
+    fn peek_second(&mut self) -> Token<'a> {
+        if matches!(self.token.0, Token::Eof) {
+            return Token::Eof;
+        }
+        self.ahead.get_or_insert_with(|| self.lexer.next().expect("the lexer ends with Eof")).0
+    }
+
+    // Span of the next token
+    fn span(&self) -> Span {
+        self.token.1
+    }
+
+    // Node covering everything from start up to the last consumed token
+    fn node(&self, start: Span, ast: Ast) -> Node {
+        Node { ast, span: start.to(self.prev) }
+    }
+
+    fn error(&self, message: String) -> Diagnostic {
+        Diagnostic::error(message).with_span(self.span())
+    }
+
+    fn expect_symbol(&mut self, sym: &str) -> NulaResult<()> {
+        if matches!(self.peek(), Token::Symbol(s) if s == sym) {
             self.next();
+            Ok(())
         } else {
-            panic!("Expected symbol {}, got {:?}", sym, self.peek());
+            Err(self.error(format!("Expected symbol {}, got {:?}", sym, self.peek())))
         }
     }
 
-    fn expect_operator(&mut self, op: &str) {
-        if matches!(&self.peek(), Token::Operator(o) if o == op) {
+    fn expect_operator(&mut self, op: &str) -> NulaResult<()> {
+        if matches!(self.peek(), Token::Operator(o) if o == op) {
             self.next();
+            Ok(())
         } else {
-            panic!("Expected operator {}, got {:?}", op, self.peek());
+            Err(self.error(format!("Expected operator {}, got {:?}", op, self.peek())))
         }
     }
 
-    fn expect_keyword(&mut self, kw: &str) {
-        if matches!(&self.peek(), Token::Keyword(k) if k == kw) {
+    fn expect_keyword(&mut self, kw: &str) -> NulaResult<()> {
+        if matches!(self.peek(), Token::Keyword(k) if k == kw) {
             self.next();
+            Ok(())
         } else {
-            panic!("Expected keyword {}, got {:?}", kw, self.peek());
+            Err(self.error(format!("Expected keyword {}, got {:?}", kw, self.peek())))
         }
     }
 
-    fn next_operator(&mut self) -> String {
-        if let Token::Operator(op) = self.next() { op } else { panic!("Expected operator"); }
+    fn expect_ident(&mut self, what: &str) -> NulaResult<String> {
+        match self.peek() {
+            Token::Ident(name) => {
+                self.next();
+                Ok(name.to_string())
+            }
+            tok => Err(self.error(format!("Expected {}, got {:?}", what, tok))),
+        }
+    }
+
+    fn next_operator(&mut self) -> NulaResult<&'a str> {
+        match self.peek() {
+            Token::Operator(op) => {
+                self.next();
+                Ok(op)
+            }
+            tok => Err(self.error(format!("Expected operator, got {:?}", tok))),
+        }
+    }
+}
+
+fn bin_op(op: String, left: Node, right: Node) -> Node {
+    let span = left.span.to(right.span);
+    Node { ast: Ast::BinOp(op, Box::new(left), Box::new(right)), span }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast;
+
+    // The tree as --emit ast shows it, without the positions
+    fn tree(code: &str) -> String {
+        let nodes = Parser::new(code, 0).parse().expect("no syntax errors");
+        ast::dump(&nodes).lines().map(|line| format!("{}\n", line.rsplit_once("  ").map_or(line, |(node, _)| node))).collect()
+    }
+
+    // Lines of the syntax errors
+    fn error_lines(code: &str) -> Vec<usize> {
+        let errors = Parser::new(code, 0).parse().expect_err("syntax errors");
+        errors.iter().map(|err| err.span.expect("errors point somewhere").line).collect()
+    }
+
+    #[test]
+    fn precedence_and_associativity() {
+        assert_eq!(tree("x = 1 + 2 * 3 ^ 2 ^ 2"), "Assign x\n  BinOp +\n    Literal 1\n    BinOp *\n      Literal 2\n      BinOp ^\n        Literal 3\n        BinOp ^\n          Literal 2\n          Literal 2\n");
+    }
+
+    #[test]
+    fn postfix_chains() {
+        assert_eq!(tree("m[1][0]"), "Index\n  Index\n    Var m\n    Literal 1\n  Literal 0\n");
+        assert_eq!(tree("get()(2)"), "Call\n  FuncCall get\n  Literal 2\n");
+    }
+
+    #[test]
+    fn recovers_at_the_next_statement() {
+        assert_eq!(error_lines("var = 1\nvar b = 2\nwrite (\nvar c = 3\n"), [1, 4]);
+        assert_eq!(error_lines("fn f( {\n}\nwrite 1\nif {\n}\n"), [1, 4]);
+    }
+
+    #[test]
+    fn lexer_errors_come_with_syntax_errors() {
+        assert_eq!(error_lines("var a = \"open"), [1]);
+        assert_eq!(error_lines("var a = 1 $\nvar = 2\n"), [1, 2]);
     }
 }
@@ -0,0 +1,140 @@
+// src/profile.rs - Measuring the compiler itself, for --timings and --self-profile
+//
+// Every allocation the compiler makes goes through Counting, which keeps a
+// running count and total size: two relaxed atomic adds, cheap enough to
+// leave on. A Sample is a stretch of work, how long it took and what it
+// allocated; a profile is a list of them, written as a summary or as a
+// Chrome trace for chrome://tracing or Perfetto.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+pub struct Counting;
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+static ALLOCATED: AtomicU64 = AtomicU64::new(0); // bytes, never taken back on free
+
+fn count(size: usize) {
+    ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+    ALLOCATED.fetch_add(size as u64, Ordering::Relaxed);
+}
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count(layout.size());
+        System.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        count(layout.size());
+        System.alloc_zeroed(layout)
+    }
+
+    // Counted as a new allocation of the new size, as it may well be one
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        count(new_size);
+        System.realloc(ptr, layout, new_size)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[derive(Clone)]
+pub struct Sample {
+    pub name: String,
+    pub start: Instant,
+    pub elapsed: Duration,
+    pub allocations: u64,
+    pub bytes: u64,
+}
+
+// Started where the work starts, finished into a Sample where it ends
+pub struct Measure {
+    start: Instant,
+    allocations: u64,
+    bytes: u64,
+}
+
+impl Measure {
+    pub fn start() -> Self {
+        Measure {
+            start: Instant::now(),
+            allocations: ALLOCATIONS.load(Ordering::Relaxed),
+            bytes: ALLOCATED.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn finish(self, name: impl Into<String>) -> Sample {
+        Sample {
+            name: name.into(),
+            start: self.start,
+            elapsed: self.start.elapsed(),
+            allocations: ALLOCATIONS.load(Ordering::Relaxed) - self.allocations,
+            bytes: ALLOCATED.load(Ordering::Relaxed) - self.bytes,
+        }
+    }
+}
+
+// Phases and their total, then the functions generated: their total and
+// each of them, slowest first
+pub fn summary(phases: &[Sample], functions: &[Sample]) -> String {
+    let mut out = format!("{:<32} {:>12} {:>12} {:>14}\n", "", "ms", "allocations", "bytes");
+    let mut row = |sample: &Sample, name: &str| {
+        out.push_str(&format!(
+            "{:<32} {:>12.3} {:>12} {:>14}\n",
+            name,
+            sample.elapsed.as_secs_f64() * 1000.0,
+            sample.allocations,
+            sample.bytes
+        ))
+    };
+    for phase in phases {
+        row(phase, &phase.name);
+    }
+    row(&total_of(phases), "total");
+    if !functions.is_empty() {
+        row(&total_of(functions), "all functions");
+        let mut slowest: Vec<&Sample> = functions.iter().collect();
+        slowest.sort_by_key(|function| std::cmp::Reverse(function.elapsed));
+        for function in slowest {
+            row(function, &format!("fn {}", function.name));
+        }
+    }
+    out
+}
+
+fn total_of(samples: &[Sample]) -> Sample {
+    Sample {
+        name: String::new(),
+        start: Instant::now(),
+        elapsed: samples.iter().map(|sample| sample.elapsed).sum(),
+        allocations: samples.iter().map(|sample| sample.allocations).sum(),
+        bytes: samples.iter().map(|sample| sample.bytes).sum(),
+    }
+}
+
+// Chrome's trace event format: one complete ("X") event per sample, with
+// times in microseconds from the first. Functions nest under codegen, as
+// they ran inside it.
+pub fn chrome_trace(events: &[Sample], functions: &[Sample]) -> String {
+    let Some(epoch) = events.iter().chain(functions).map(|sample| sample.start).min() else {
+        return "{\"traceEvents\":[]}\n".to_string();
+    };
+    let event = |sample: &Sample, category: &str| {
+        format!(
+            "{{\"name\":{},\"cat\":\"{}\",\"ph\":\"X\",\"ts\":{:.3},\"dur\":{:.3},\"pid\":1,\"tid\":1,\"args\":{{\"allocations\":{},\"bytes\":{}}}}}",
+            crate::diagnostic::json_string(&sample.name),
+            category,
+            (sample.start - epoch).as_secs_f64() * 1e6,
+            sample.elapsed.as_secs_f64() * 1e6,
+            sample.allocations,
+            sample.bytes
+        )
+    };
+    let mut trace: Vec<String> = events.iter().map(|sample| event(sample, "phase")).collect();
+    trace.extend(functions.iter().map(|sample| event(sample, "function")));
+    format!("{{\"traceEvents\":[\n{}\n]}}\n", trace.join(",\n"))
+}
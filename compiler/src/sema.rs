@@ -0,0 +1,653 @@
+// src/sema.rs - Semantic checks run between parsing and codegen
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::ast::{Ast, Node, Span};
+use crate::diagnostic::{Diagnostic, WarningConfig};
+
+// What an argument has to be, as far as sema can tell from the expression.
+// Handles (threads, channels, ...) travel as numbers, and bytes only come
+// from calls.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Type {
+    Number,
+    String,
+    Bytes,
+    Array,
+    Any,
+}
+
+impl Type {
+    fn of(node: &Node) -> Type {
+        match &node.ast {
+            // `+` concatenates strings; every other operator gives a number
+            Ast::BinOp(op, left, right) if op == "+" => match (Type::of(left), Type::of(right)) {
+                (Type::Number, Type::Number) => Type::Number,
+                (Type::String, _) | (_, Type::String) => Type::String,
+                _ => Type::Any,
+            },
+            Ast::Literal(_) | Ast::BinOp(..) => Type::Number,
+            Ast::StrLit(_) => Type::String,
+            Ast::Array(_) => Type::Array,
+            _ => Type::Any,
+        }
+    }
+
+    fn accepts(self, actual: Type) -> bool {
+        self == Type::Any || actual == Type::Any || self == actual
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Type::Number => "number",
+            Type::String => "string",
+            Type::Bytes => "bytes",
+            Type::Array => "array",
+            Type::Any => "value",
+        }
+    }
+
+    fn with_article(self) -> &'static str {
+        match self {
+            Type::Number => "a number",
+            Type::String => "a string",
+            Type::Bytes => "bytes",
+            Type::Array => "an array",
+            Type::Any => "a value",
+        }
+    }
+}
+
+type Signature = Vec<(String, Type)>;
+
+use Type::{Any, Array, Bytes, Number, String as Str};
+
+// Functions implemented directly by codegen (see CodeGen::gen_builtin)
+const BUILTINS: [(&str, &[(&str, Type)]); 39] = [
+    ("write", &[("value", Any)]),
+    ("spawn", &[("function", Any), ("arg", Number)]),
+    ("join", &[("thread", Number)]),
+    ("channel", &[]),
+    ("send", &[("channel", Number), ("value", Number)]),
+    ("recv", &[("channel", Number)]),
+    ("mutex", &[]),
+    ("lock", &[("mutex", Number)]),
+    ("unlock", &[("mutex", Number)]),
+    ("atomic", &[("value", Number)]),
+    ("atomic_add", &[("cell", Number), ("n", Number)]),
+    ("atomic_load", &[("cell", Number)]),
+    ("atomic_store", &[("cell", Number), ("value", Number)]),
+    ("run", &[("cmd", Str), ("args", Array)]),
+    ("now", &[]),
+    ("format_time", &[("time", Number), ("fmt", Str)]),
+    ("parse_time", &[("text", Str), ("fmt", Str)]),
+    ("sha256", &[("text", Str)]),
+    ("md5", &[("text", Str)]),
+    ("fnv", &[("text", Str)]),
+    ("base64_encode", &[("text", Str)]),
+    ("base64_decode", &[("text", Str)]),
+    ("hex_encode", &[("text", Str)]),
+    ("hex_decode", &[("text", Str)]),
+    ("bytes", &[("text", Str)]),
+    ("bytes_len", &[("bytes", Bytes)]),
+    ("byte_at", &[("bytes", Bytes), ("index", Number)]),
+    ("slice", &[("bytes", Bytes), ("start", Number), ("end", Number)]),
+    ("bytes_to_string", &[("bytes", Bytes)]),
+    ("read_file", &[("path", Str)]),
+    ("write_file", &[("path", Str), ("data", Any)]),
+    ("asm", &[("source", Str)]),
+    ("vec4_add", &[("a", Array), ("b", Array)]),
+    ("vec4_sub", &[("a", Array), ("b", Array)]),
+    ("vec4_mul", &[("a", Array), ("b", Array)]),
+    ("vec4_div", &[("a", Array), ("b", Array)]),
+    ("vec4_dot", &[("a", Array), ("b", Array)]),
+    ("assert", &[("condition", Number)]),
+    ("exit", &[("code", Number)]),
+];
+
+// Warning names accepted by -A and -W
+pub const LINTS: [&str; 6] = [
+    "unused_variables",
+    "unused_functions",
+    "shadowed_variables",
+    "unreachable_code",
+    "double_lock",
+    "unreleased_lock",
+];
+
+pub fn check(ast: &[Node], config: &WarningConfig) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    check_names(ast, &mut diagnostics);
+    check_reachable(ast, &mut diagnostics);
+    let held = check_locks(ast, BTreeSet::new(), &mut diagnostics);
+    for m in held {
+        diagnostics.push(Diagnostic::warning(
+            "unreleased_lock",
+            format!("`{}` is locked but not unlocked on every path before the program ends", m),
+        ));
+    }
+    config.apply(diagnostics)
+}
+
+// Reports variables and functions used without being defined, and ones that
+// are defined but never used (unless their name starts with `_`). Functions are
+// visible everywhere; a variable from where it is declared (or first assigned)
+// until the end of the enclosing block.
+fn check_names(ast: &[Node], diagnostics: &mut Vec<Diagnostic>) {
+    let mut functions: BTreeMap<String, Signature> = BUILTINS
+        .iter()
+        .map(|(name, params)| (name.to_string(), params.iter().map(|&(p, t)| (p.to_string(), t)).collect()))
+        .collect();
+    let mut definitions = Vec::new();
+    collect_functions(ast, &mut definitions);
+    let mut defined: BTreeMap<&str, Span> = BTreeMap::new();
+    for &node in &definitions {
+        if let Ast::FuncDef(name, params, _) | Ast::AsyncFuncDef(name, params, _) = &node.ast {
+            if let Some(first) = defined.get(name.as_str()) {
+                let message = format!("Function `{}` is already defined at line {}", name, first.line);
+                diagnostics.push(Diagnostic::error(message).with_span(node.span));
+                continue;
+            }
+            if functions.contains_key(name) {
+                let message = format!("`{}` is a builtin function and cannot be redefined", name);
+                diagnostics.push(Diagnostic::error(message).with_span(node.span));
+                continue;
+            }
+            defined.insert(name, node.span);
+            // Parameters take the types of what their calls give them (see
+            // lower.rs), which reports calls that don't agree
+            functions.insert(name.clone(), params.iter().map(|p| (p.clone(), Type::Any)).collect());
+        }
+    }
+    // C callers can't wait on a task
+    for node in ast {
+        if let Ast::Export(name) = &node.ast {
+            if definitions.iter().any(|def| matches!(&def.ast, Ast::AsyncFuncDef(async_name, _, _) if async_name == name)) {
+                diagnostics.push(Diagnostic::error(format!("async function `{}` cannot be exported", name)).with_span(node.span));
+            }
+        }
+    }
+    let mut resolver = Resolver { functions: &functions, scopes: vec![BTreeMap::new()], called: BTreeSet::new(), diagnostics };
+    resolver.block(ast);
+    resolver.pop_scope();
+    for node in definitions {
+        if let Ast::FuncDef(name, _, _) | Ast::AsyncFuncDef(name, _, _) = &node.ast {
+            if !resolver.called.contains(name) && !name.starts_with('_') {
+                resolver.diagnostics.push(Diagnostic::warning("unused_functions", format!("`{}` is never called", name)).with_span(node.span));
+            }
+        }
+    }
+}
+
+// Every fn is callable from anywhere, including ones defined inside a block or
+// another function, as lower.rs makes them
+fn collect_functions<'a>(stmts: &'a [Node], out: &mut Vec<&'a Node>) {
+    for node in stmts {
+        match &node.ast {
+            Ast::FuncDef(_, _, body) | Ast::AsyncFuncDef(_, _, body) => {
+                out.push(node);
+                collect_functions(body, out);
+            }
+            Ast::If(_, then_body, else_body) => {
+                collect_functions(then_body, out);
+                collect_functions(else_body.as_deref().unwrap_or_default(), out);
+            }
+            Ast::While(_, body) | Ast::For(_, _, _, body) | Ast::ForEach(_, _, _, body) => collect_functions(body, out),
+            _ => {}
+        }
+    }
+}
+
+struct Binding {
+    span: Span, // where the variable is first assigned
+    read: bool,
+}
+
+struct Resolver<'a> {
+    functions: &'a BTreeMap<String, Signature>,
+    scopes: Vec<BTreeMap<String, Binding>>, // innermost block last
+    called: BTreeSet<String>, // functions that are called or used as a value
+    diagnostics: &'a mut Vec<Diagnostic>,
+}
+
+impl Resolver<'_> {
+    fn block(&mut self, stmts: &[Node]) {
+        for stmt in stmts {
+            self.node(stmt);
+        }
+    }
+
+    fn node(&mut self, node: &Node) {
+        match &node.ast {
+            Ast::VarDecl(name, value) => {
+                self.node(value);
+                if let Some(earlier) = self.lookup(name) {
+                    let message = format!("`{}` shadows a variable of the same name from line {}", name, earlier.span.line);
+                    self.diagnostics.push(Diagnostic::warning("shadowed_variables", message).with_span(node.span));
+                }
+                self.declare(name, node.span);
+            }
+            Ast::Assign(name, value) => {
+                self.node(value);
+                if self.lookup(name).is_none() {
+                    self.declare(name, node.span);
+                }
+            }
+            Ast::Var(name) => {
+                if let Some(binding) = self.lookup(name) {
+                    binding.read = true;
+                } else if self.functions.contains_key(name) {
+                    // A function name used as a value passes its address
+                    self.called.insert(name.clone());
+                } else {
+                    let variables = self.scopes.iter().flat_map(|scope| scope.keys());
+                    let suggestion = suggest(name, variables.chain(self.functions.keys()));
+                    self.diagnostics.push(undefined("variable", name, suggestion, node.span));
+                }
+            }
+            Ast::Index(array, index) => {
+                self.node(array);
+                self.node(index);
+            }
+            Ast::Call(callee, args) => {
+                self.node(callee);
+                self.block(args);
+            }
+            Ast::FuncCall(name, args) => {
+                self.called.insert(name.clone());
+                match self.functions.get(name) {
+                    Some(signature) => check_call(name, signature, args, node.span, self.diagnostics),
+                    None => {
+                        let suggestion = suggest(name, self.functions.keys());
+                        self.diagnostics.push(undefined("function", name, suggestion, node.span));
+                    }
+                }
+                self.block(args);
+            }
+            Ast::If(cond, then_body, else_body) => {
+                self.node(cond);
+                self.scoped_block(then_body);
+                if let Some(eb) = else_body {
+                    self.scoped_block(eb);
+                }
+            }
+            Ast::While(cond, body) => {
+                self.node(cond);
+                self.scoped_block(body);
+            }
+            Ast::For(var, from, to, body) => {
+                // The loop variable lives in the body's scope
+                self.node(from);
+                self.node(to);
+                self.scopes.push(BTreeMap::new());
+                self.declare(var, node.span);
+                self.block(body);
+                self.pop_scope();
+            }
+            Ast::ForEach(index, elem, array, body) => {
+                self.node(array);
+                self.scopes.push(BTreeMap::new());
+                if let Some(index) = index {
+                    self.declare(index, node.span);
+                }
+                self.declare(elem, node.span);
+                self.block(body);
+                self.pop_scope();
+            }
+            Ast::FuncDef(name, params, body) | Ast::AsyncFuncDef(name, params, body) => {
+                // Function bodies only see their own parameters and locals
+                let mut bindings = BTreeMap::new();
+                for param in params {
+                    if bindings.insert(param.clone(), Binding { span: node.span, read: true }).is_some() {
+                        let message = format!("Parameter `{}` of `{}` is declared more than once", param, name);
+                        self.diagnostics.push(Diagnostic::error(message).with_span(node.span));
+                    }
+                }
+                let outer = std::mem::replace(&mut self.scopes, vec![bindings]);
+                self.block(body);
+                self.pop_scope();
+                self.scopes = outer;
+            }
+            Ast::Await(expr) | Ast::Return(expr) => self.node(expr),
+            Ast::BinOp(_, left, right) => {
+                self.node(left);
+                self.node(right);
+            }
+            Ast::Array(elements) => self.block(elements),
+            Ast::Export(name) => {
+                self.called.insert(name.clone());
+                if BUILTINS.iter().any(|(builtin, _)| builtin == name) {
+                    self.diagnostics.push(Diagnostic::error(format!("`{}` is a builtin function and cannot be exported", name)).with_span(node.span));
+                } else if !self.functions.contains_key(name) {
+                    let suggestion = suggest(name, self.functions.keys());
+                    self.diagnostics.push(undefined("function", name, suggestion, node.span));
+                }
+            }
+            Ast::Literal(_) | Ast::StrLit(_) | Ast::Link(_) => {}
+        }
+    }
+
+    fn scoped_block(&mut self, stmts: &[Node]) {
+        self.scopes.push(BTreeMap::new());
+        self.block(stmts);
+        self.pop_scope();
+    }
+
+    fn lookup(&mut self, name: &str) -> Option<&mut Binding> {
+        self.scopes.iter_mut().rev().find_map(|scope| scope.get_mut(name))
+    }
+
+    // Binds `name` in the innermost scope, replacing a binding from the same block
+    fn declare(&mut self, name: &str, span: Span) {
+        let scope = self.scopes.last_mut().expect("resolver always has a scope");
+        if let Some(replaced) = scope.insert(name.to_string(), Binding { span, read: false }) {
+            self.report_unused(name, &replaced);
+        }
+    }
+
+    // Ends the innermost scope, reporting the variables it declared but never read
+    fn pop_scope(&mut self) {
+        let scope = self.scopes.pop().expect("resolver always has a scope");
+        for (name, binding) in &scope {
+            self.report_unused(name, binding);
+        }
+    }
+
+    fn report_unused(&mut self, name: &str, binding: &Binding) {
+        if !binding.read && !name.starts_with('_') {
+            let warning = Diagnostic::warning("unused_variables", format!("`{}` is assigned but never read", name));
+            self.diagnostics.push(warning.with_span(binding.span));
+        }
+    }
+}
+
+// Checks the number of arguments and, where both sides are known, their types
+fn check_call(name: &str, signature: &Signature, args: &[Node], span: Span, diagnostics: &mut Vec<Diagnostic>) {
+    let declared = || {
+        let params: Vec<String> = signature
+            .iter()
+            .map(|(p, t)| if *t == Type::Any { p.clone() } else { format!("{}: {}", p, t.name()) })
+            .collect();
+        format!("fn {}({})", name, params.join(", "))
+    };
+    if args.len() != signature.len() {
+        let expected = match signature.len() {
+            0 => "no arguments".to_string(),
+            1 => "1 argument".to_string(),
+            n => format!("{} arguments", n),
+        };
+        let message = format!("`{}` expects {}, got {} (declared as `{}`)", name, expected, args.len(), declared());
+        diagnostics.push(Diagnostic::error(message).with_span(span));
+        return;
+    }
+    for (arg, (param, expected)) in args.iter().zip(signature) {
+        let actual = Type::of(arg);
+        if !expected.accepts(actual) {
+            let message = format!(
+                "`{}` of `{}` must be {}, got {} (declared as `{}`)",
+                param,
+                name,
+                expected.with_article(),
+                actual.with_article(),
+                declared()
+            );
+            diagnostics.push(Diagnostic::error(message).with_span(arg.span));
+        }
+    }
+}
+
+fn undefined(kind: &str, name: &str, suggestion: Option<&String>, span: Span) -> Diagnostic {
+    let message = match suggestion {
+        Some(s) => format!("Undefined {} `{}`, did you mean `{}`?", kind, name, s),
+        None => format!("Undefined {} `{}`", kind, name),
+    };
+    Diagnostic::error(message).with_span(span)
+}
+
+// Closest candidate by edit distance, if it is close enough to be a likely typo
+fn suggest<'a>(name: &str, candidates: impl Iterator<Item = &'a String>) -> Option<&'a String> {
+    // Never suggest by rewriting the whole name, which any one-letter name would allow
+    let len = name.chars().count();
+    let max_distance = (len / 3).max(1).min(len - 1);
+    candidates
+        .map(|c| (edit_distance(name, c), c))
+        .filter(|&(d, _)| d <= max_distance)
+        .min_by_key(|&(d, _)| d)
+        .map(|(_, c)| c)
+}
+
+// Levenshtein distance over chars
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let substitute = diagonal + usize::from(ca != cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substitute.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
+
+// Functions the top-level statements (or with `tests`, the test_ functions)
+// can end up running: called directly, from another reachable function, or
+// taken as a value. Only these need code.
+pub fn reachable_functions(ast: &[Node], tests: bool) -> BTreeSet<String> {
+    let mut roots = BTreeSet::new();
+    let mut calls = BTreeMap::new();
+    for node in ast {
+        collect_calls(node, &mut roots, &mut calls);
+    }
+    if tests {
+        roots = calls.keys().filter(|name| name.starts_with("test_")).cloned().collect();
+    }
+    let mut reachable = BTreeSet::new();
+    let mut pending: Vec<String> = roots.into_iter().collect();
+    while let Some(name) = pending.pop() {
+        if let Some(callees) = calls.get(&name) {
+            if reachable.insert(name) {
+                pending.extend(callees.iter().cloned());
+            }
+        }
+    }
+    reachable
+}
+
+// Adds the names `node` refers to outside of function bodies to `refs`, and
+// the names each function defined in it refers to to `calls`. Builtins and
+// variables end up in there too, but only function names are looked up.
+fn collect_calls(node: &Node, refs: &mut BTreeSet<String>, calls: &mut BTreeMap<String, BTreeSet<String>>) {
+    match &node.ast {
+        Ast::FuncDef(name, _, body) | Ast::AsyncFuncDef(name, _, body) => {
+            let mut callees = BTreeSet::new();
+            for stmt in body {
+                collect_calls(stmt, &mut callees, calls);
+            }
+            calls.insert(name.clone(), callees);
+        }
+        Ast::FuncCall(name, args) => {
+            refs.insert(name.clone());
+            args.iter().for_each(|arg| collect_calls(arg, refs, calls));
+        }
+        Ast::Var(name) => {
+            refs.insert(name.clone());
+        }
+        Ast::VarDecl(_, value) | Ast::Assign(_, value) | Ast::Return(value) | Ast::Await(value) => collect_calls(value, refs, calls),
+        Ast::If(cond, then_body, else_body) => {
+            collect_calls(cond, refs, calls);
+            then_body.iter().chain(else_body.iter().flatten()).for_each(|stmt| collect_calls(stmt, refs, calls));
+        }
+        Ast::While(cond, body) => {
+            collect_calls(cond, refs, calls);
+            body.iter().for_each(|stmt| collect_calls(stmt, refs, calls));
+        }
+        Ast::For(_, from, to, body) => {
+            collect_calls(from, refs, calls);
+            collect_calls(to, refs, calls);
+            body.iter().for_each(|stmt| collect_calls(stmt, refs, calls));
+        }
+        Ast::ForEach(_, _, array, body) => {
+            collect_calls(array, refs, calls);
+            body.iter().for_each(|stmt| collect_calls(stmt, refs, calls));
+        }
+        Ast::Call(callee, args) => {
+            collect_calls(callee, refs, calls);
+            args.iter().for_each(|arg| collect_calls(arg, refs, calls));
+        }
+        Ast::BinOp(_, left, right) | Ast::Index(left, right) => {
+            collect_calls(left, refs, calls);
+            collect_calls(right, refs, calls);
+        }
+        Ast::Array(elements) => elements.iter().for_each(|elem| collect_calls(elem, refs, calls)),
+        // Exported functions are called from outside the program
+        Ast::Export(name) => {
+            refs.insert(name.clone());
+        }
+        Ast::Literal(_) | Ast::StrLit(_) | Ast::Link(_) => {}
+    }
+}
+
+// Warns about statements that follow a return on every path. Returns whether
+// `stmts` always ends in a return.
+fn check_reachable(stmts: &[Node], diagnostics: &mut Vec<Diagnostic>) -> bool {
+    for (i, stmt) in stmts.iter().enumerate() {
+        let returns = match &stmt.ast {
+            Ast::Return(_) => true,
+            Ast::If(_, then_body, else_body) => {
+                let then_returns = check_reachable(then_body, diagnostics);
+                let else_returns = else_body.as_ref().is_some_and(|eb| check_reachable(eb, diagnostics));
+                then_returns && else_returns
+            }
+            Ast::While(_, body) | Ast::For(_, _, _, body) | Ast::ForEach(_, _, _, body) => {
+                // The body may run zero times, so the loop never returns on every path
+                check_reachable(body, diagnostics);
+                false
+            }
+            Ast::FuncDef(_, _, body) | Ast::AsyncFuncDef(_, _, body) => {
+                check_reachable(body, diagnostics);
+                false
+            }
+            _ => false,
+        };
+        if returns {
+            let rest = &stmts[i + 1..];
+            if let (Some(first), Some(last)) = (rest.first(), rest.last()) {
+                diagnostics.push(Diagnostic::warning("unreachable_code", "Unreachable code").with_span(first.span.to(last.span)));
+            }
+            return true;
+        }
+    }
+    false
+}
+
+// Best-effort lock tracking: returns the set of mutex variables that may still
+// be held after running `stmts`, starting with `held` locked.
+fn check_locks(stmts: &[Node], mut held: BTreeSet<String>, warnings: &mut Vec<Diagnostic>) -> BTreeSet<String> {
+    for stmt in stmts {
+        match &stmt.ast {
+            Ast::FuncCall(name, args) if name == "lock" || name == "unlock" => {
+                let m = match args.first().map(|arg| &arg.ast) {
+                    Some(Ast::Var(m)) => m.clone(),
+                    _ => continue,
+                };
+                if name == "lock" {
+                    if !held.insert(m.clone()) {
+                        let warning = Diagnostic::warning("double_lock", format!("`{}` may be locked twice without an unlock in between", m));
+                        warnings.push(warning.with_span(stmt.span));
+                    }
+                } else {
+                    held.remove(&m);
+                }
+            }
+            Ast::If(_, then_body, else_body) => {
+                let mut after = check_locks(then_body, held.clone(), warnings);
+                let else_held = match else_body {
+                    Some(eb) => check_locks(eb, held.clone(), warnings),
+                    None => held.clone(),
+                };
+                after.extend(else_held);
+                held = after;
+            }
+            Ast::While(_, body) | Ast::For(_, _, _, body) | Ast::ForEach(_, _, _, body) => {
+                let after = check_locks(body, held.clone(), warnings);
+                for m in after.difference(&held) {
+                    let message = format!("`{}` is locked inside a loop but not unlocked before the next iteration", m);
+                    warnings.push(Diagnostic::warning("unreleased_lock", message).with_span(stmt.span));
+                }
+                held.extend(after);
+            }
+            Ast::FuncDef(name, _, body) | Ast::AsyncFuncDef(name, _, body) => {
+                for m in check_locks(body, BTreeSet::new(), warnings) {
+                    let warning = Diagnostic::warning("unreleased_lock", format!("`{}` may still be locked when function `{}` returns", m, name));
+                    warnings.push(warning.with_span(stmt.span));
+                }
+            }
+            _ => {}
+        }
+    }
+    held
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+
+    fn check_code(code: &str) -> Vec<String> {
+        let ast = Parser::new(code, 0).parse().expect("no syntax errors");
+        check(&ast, &WarningConfig::default()).iter().map(|d| d.to_string().lines().next().unwrap().to_string()).collect()
+    }
+
+    fn errors(code: &str) -> Vec<String> {
+        check_code(code).into_iter().filter(|line| line.starts_with("error")).collect()
+    }
+
+    #[test]
+    fn undefined_names_suggest_a_close_one() {
+        assert_eq!(errors("var count = 1\nwrite cont"), ["error: Undefined variable `cont`, did you mean `count`?"]);
+        assert_eq!(errors("fn greet() {\n}\ngret()"), ["error: Undefined function `gret`, did you mean `greet`?"]);
+        assert_eq!(check_code("write x"), ["error: Undefined variable `x`"]);
+    }
+
+    #[test]
+    fn calls_are_checked_against_the_signature() {
+        assert_eq!(check_code("fn f(a, b) {\n  return a + b\n}\nf(1)"), ["error: `f` expects 2 arguments, got 1 (declared as `fn f(a, b)`)"]);
+        assert_eq!(
+            check_code("sha256(1)"),
+            ["error: `text` of `sha256` must be a string, got a number (declared as `fn sha256(text: string)`)"]
+        );
+        assert!(check_code("var s = \"a\"\nwrite sha256(s + \"x\")").is_empty());
+        assert_eq!(
+            check_code("sha256(1 + 2)"),
+            ["error: `text` of `sha256` must be a string, got a number (declared as `fn sha256(text: string)`)"]
+        );
+        // Parameter types come from the calls, see lower.rs
+        assert!(check_code("fn greet(name) {\n  write name\n}\ngreet(\"bob\")\ngreet(1)").is_empty());
+    }
+
+    #[test]
+    fn functions_defined_anywhere_can_be_called() {
+        assert!(check_code("fn outer() {\n  fn inner() {\n    return 1\n  }\n  return inner()\n}\nwrite outer()").is_empty());
+        assert!(check_code("write later()\nif 1 {\n  fn later() {\n    return 2\n  }\n}").is_empty());
+    }
+
+    #[test]
+    fn duplicates_and_builtins_cant_be_defined() {
+        assert_eq!(check_code("fn f() {\n}\nfn f() {\n}\nf()"), ["error: Function `f` is already defined at line 1"]);
+        assert_eq!(errors("fn sha256(x) {\n}"), ["error: `sha256` is a builtin function and cannot be redefined"]);
+    }
+
+    #[test]
+    fn warnings() {
+        assert_eq!(check_code("var x = 1\nvar _y = 2"), ["warning: `x` is assigned but never read"]);
+        assert_eq!(check_code("fn f() {\n}"), ["warning: `f` is never called"]);
+        assert_eq!(check_code("fn f() {\n  return 1\n  write 2\n}\nf()"), ["warning: Unreachable code"]);
+        assert_eq!(
+            check_code("var m = mutex()\nlock(m)\nlock(m)\nunlock(m)"),
+            ["warning: `m` may be locked twice without an unlock in between"]
+        );
+    }
+}
@@ -0,0 +1,99 @@
+// src/source.rs - Reading the program's files
+//
+// Generated files can run to hundreds of megabytes. Rather than copying one
+// into a String, a big file is mapped into memory and lexed where it lies:
+// its pages belong to the page cache and can be dropped and read back as
+// needed, so memory use stays flat however big it is. Small files and stdin
+// are read as usual, and so is every file when it may change during the
+// build (see Mapping).
+
+use std::fs::{self, File};
+use std::io;
+use std::ops::Deref;
+
+// Files smaller than this aren't worth a mapping
+const MAP_THRESHOLD: u64 = 1 << 20;
+
+pub enum Text {
+    Owned(String),
+    #[cfg(unix)]
+    Mapped(Mapping),
+}
+
+impl Text {
+    // Big files are mapped if `map`
+    pub fn read(path: &str, map: bool) -> io::Result<Text> {
+        let file = File::open(path)?;
+        #[cfg(unix)]
+        if map && file.metadata()?.len() >= MAP_THRESHOLD {
+            return Mapping::new(&file).map(Text::Mapped);
+        }
+        drop(file);
+        fs::read_to_string(path).map(Text::Owned)
+    }
+
+    pub fn stdin() -> io::Result<Text> {
+        io::read_to_string(io::stdin()).map(Text::Owned)
+    }
+}
+
+impl Deref for Text {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        match self {
+            Text::Owned(text) => text,
+            #[cfg(unix)]
+            Text::Mapped(mapping) => mapping.text(),
+        }
+    }
+}
+
+// A whole file mapped read-only, checked to be UTF-8 when mapped. The mapping
+// is private, but that only keeps our writes from the file: the pages still
+// show the file as it is now. So the file mustn't change while it's mapped.
+// Rewritten, it could hand the lexer text that isn't UTF-8 any more, and
+// truncated, reading past its new end raises SIGBUS and kills the compiler.
+// That is a fair ask of a file being compiled, but not under --watch, whose
+// builds run while the files are being edited, so those read them instead.
+#[cfg(unix)]
+pub struct Mapping {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+#[cfg(unix)]
+impl Mapping {
+    fn new(file: &File) -> io::Result<Mapping> {
+        use std::os::unix::io::AsRawFd;
+
+        let len = usize::try_from(file.metadata()?.len()).map_err(|_| io::Error::from(io::ErrorKind::OutOfMemory))?;
+        let ptr = unsafe { libc::mmap(std::ptr::null_mut(), len, libc::PROT_READ, libc::MAP_PRIVATE, file.as_raw_fd(), 0) };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        let mapping = Mapping { ptr, len };
+        // The lexer goes through it front to back
+        unsafe { libc::madvise(ptr, len, libc::MADV_SEQUENTIAL) };
+        if std::str::from_utf8(mapping.bytes()).is_err() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "stream did not contain valid UTF-8"));
+        }
+        Ok(mapping)
+    }
+
+    fn bytes(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+
+    fn text(&self) -> &str {
+        // Checked when mapped, and the file doesn't change (see above)
+        unsafe { std::str::from_utf8_unchecked(self.bytes()) }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr, self.len) };
+    }
+}
@@ -0,0 +1,73 @@
+// tests/runtime.rs - Programs run through the JIT: what they print, and how they fail
+
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+fn jit(source: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_nula-compiler"))
+        .args(["jit", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("compiler starts");
+    child.stdin.take().unwrap().write_all(source.as_bytes()).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+// What a program that should run to the end prints
+fn stdout(source: &str) -> String {
+    let output = jit(source);
+    assert!(output.status.success(), "{:?}: {}", output.status, String::from_utf8_lossy(&output.stderr));
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn atomics_take_numbers_outside_their_range() {
+    assert_eq!(stdout("var a = atomic(0)\natomic_store(a, 0 - 2^1000)\nwrite atomic_load(a) < 0\natomic_store(a, 2)\nwrite atomic_add(a, 3)\n"), "1\n5\n");
+}
+
+#[test]
+fn shifts_take_numbers_outside_their_range() {
+    // Folded when constant, so once through a variable too
+    assert_eq!(stdout("var x = 2^1000\nwrite x << 1\nwrite 2^1000 << 1\nwrite 5 >> 1\n"), "-2\n-2\n2\n");
+}
+
+#[test]
+fn calls_give_back_strings_and_arrays() {
+    let source = "fn greet(name) {\n    return \"hi \" + name\n}\nfn pair(n) {\n    return [[n], [n + 1]]\n}\nwrite greet(\"bob\")\nvar p = pair(1)\nwrite p[1][0]\n";
+    assert_eq!(stdout(source), "hi bob\n2\n");
+}
+
+#[test]
+fn strings_compare_and_index_by_their_bytes() {
+    // Through a variable, so the comparison isn't folded
+    assert_eq!(stdout("var x = \"a\"\nwrite x + \"b\" == \"ab\"\nwrite x != \"a\"\nwrite x[0]\n"), "1\n0\n97\n");
+}
+
+#[test]
+fn numbers_print_in_full() {
+    assert_eq!(stdout("write 1234567\nwrite 0.1\nwrite 1 / 3\nwrite fnv(\"a\")\n"), "1234567\n0.1\n0.3333333333333333\n3826002220\n");
+}
+
+#[cfg(unix)]
+#[test]
+fn run_gives_back_output_and_status() {
+    assert_eq!(stdout("var r = run(\"echo\", [\"hi\"])\nwrite r[0]\nwrite r[2]\n"), "hi\n\n0\n");
+}
+
+#[test]
+fn tasks_can_be_awaited_again() {
+    assert_eq!(stdout("async fn twice(x) {\n    return x * 2\n}\nvar t = twice(21)\nwrite await t\nwrite await t\n"), "42\n42\n");
+}
+
+#[test]
+fn bytes_index_slice_and_go_through_files() {
+    let path = std::env::temp_dir().join(format!("nula-bytes-{}", std::process::id()));
+    let source = format!(
+        "var b = bytes(\"hello\")\nwrite b[1]\nwrite slice(b, 1, 3)\nwrite_file(\"{0}\", b)\nwrite read_file(\"{0}\") == b\n",
+        path.display()
+    );
+    assert_eq!(stdout(&source), "101\nel\n1\n");
+    std::fs::remove_file(path).unwrap();
+}
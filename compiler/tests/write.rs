@@ -0,0 +1,49 @@
+// tests/write.rs - What `write` prints, through the JIT and through an executable
+
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+
+// printf would read these as conversions, %n writing through a pointer it
+// takes from the stack
+const HOSTILE: &str = "write \"%s%n%s\"\nvar s = \"100%\" + \" %n%x\"\nwrite s\n";
+const PRINTED: &str = "%s%n%s\n100% %n%x\n";
+
+fn compiler(args: &[&str], dir: &PathBuf, source: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_nula-compiler"))
+        .args(args)
+        .current_dir(dir)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("compiler starts");
+    child.stdin.take().unwrap().write_all(source.as_bytes()).unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    output
+}
+
+// A directory of its own for each test, as builds leave files next to them
+fn scratch(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("nula-test-{}-{}", name, std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn jit_prints_percent_signs_as_text() {
+    let dir = scratch("jit");
+    let output = compiler(&["jit", "-"], &dir, HOSTILE);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), PRINTED);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn executables_print_percent_signs_as_text() {
+    let dir = scratch("build");
+    compiler(&["build", "-", "-o", "hostile"], &dir, HOSTILE);
+    let output = Command::new(dir.join("hostile")).output().unwrap();
+    assert_eq!(String::from_utf8_lossy(&output.stdout), PRINTED);
+    std::fs::remove_dir_all(dir).unwrap();
+}